@@ -1,9 +1,11 @@
 use criterion::{criterion_group, criterion_main, Criterion};
 use kvs::{
-    KvStore, KvsClient, KvsEngine, KvsServer, SharedQueueThreadPool, SledKvsEngine, ThreadPool,
+    KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer, RayonThreadPool,
+    SharedQueueThreadPool, SledKvsEngine, ThreadPool,
 };
 use rand::{rngs::SmallRng, Rng, SeedableRng};
 use std::net::SocketAddr;
+use std::path::Path;
 use tempfile::TempDir;
 
 // TODO: The spec requires to write and read 100 times but this takes several minutes with the sled engine.
@@ -17,7 +19,7 @@ fn random_ascii_string(rng: &mut impl Rng) -> String {
         // TODO: Track down deserialization error when re-opening existing store when allowing all
         // printable ascii characters.  Escape bug?  serde_json bug?
         // s.push(rng.gen_range(32, 127) as u8 as char);
-        s.push(rng.gen_range('a' as u8, 'z' as u8) as u8 as char);
+        s.push(rng.gen_range(b'a', b'z') as char);
     }
     s
 }
@@ -31,7 +33,7 @@ fn key_val_pairs(n: usize) -> Vec<(String, String)> {
     pairs
 }
 
-fn engine_write(engine: &mut impl KvsEngine, pairs: &Vec<(String, String)>) {
+fn engine_write(engine: &mut impl KvsEngine, pairs: &[(String, String)]) {
     for (k, v) in pairs {
         engine.set(k.to_string(), v.to_string()).unwrap();
     }
@@ -55,7 +57,7 @@ fn sled_write(c: &mut Criterion) {
     generic_write::<SledKvsEngine>(c, "sled_write")
 }
 
-fn engine_read(engine: &impl KvsEngine, pairs: &Vec<(String, String)>) {
+fn engine_read(engine: &impl KvsEngine, pairs: &[(String, String)]) {
     let mut rng = SmallRng::seed_from_u64(0x0DDB1A5E5BAD5EEDu64);
     for _ in 0..READ_COUNT {
         let i = rng.gen_range(0, pairs.len());
@@ -67,49 +69,147 @@ fn engine_read(engine: &impl KvsEngine, pairs: &Vec<(String, String)>) {
 }
 
 fn generic_read<T>(c: &mut Criterion, name: &str)
+where
+    T: KvsEngine + 'static + Sized,
+{
+    generic_read_with(c, name, |path| T::open(path));
+}
+
+/// Measures reads from a store opened with `open` once written, opening excluded.
+fn generic_read_with<T>(c: &mut Criterion, name: &str, open: fn(&Path) -> kvs::Result<T>)
 where
     T: KvsEngine + 'static + Sized,
 {
     let tmpdir = TempDir::new().unwrap();
     let pairs = key_val_pairs(WRITE_COUNT);
     {
-        let mut engine = T::open(&tmpdir).unwrap();
+        let mut engine = open(tmpdir.path()).unwrap();
         engine_write(&mut engine, &pairs);
     }
-    c.bench_function(name, move |b| {
-        b.iter(|| {
-            let engine = T::open(&tmpdir).unwrap();
-            engine_read(&engine, &pairs);
-        })
-    });
+    let engine = open(tmpdir.path()).unwrap();
+    c.bench_function(name, move |b| b.iter(|| engine_read(&engine, &pairs)));
 }
 
 fn kvs_read(c: &mut Criterion) {
     generic_read::<KvStore>(c, "kvs_read");
 }
 
+fn kvs_mmap_read(c: &mut Criterion) {
+    generic_read_with(c, "kvs_mmap_read", |path| {
+        let options = KvStoreOptions {
+            mmap: true,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with(path, options)
+    });
+}
+
 fn sled_read(c: &mut Criterion) {
     generic_read::<SledKvsEngine>(c, "sled_read");
 }
 
+const SERVER_THREADS: u32 = 4;
+const CLIENTS: usize = 8;
+const CLIENT_WRITES: usize = 20;
+
+/// Measures concurrent clients writing to a server backed by a store with `shards` shards.
+fn generic_server_write<P: ThreadPool + Send + Sync + 'static>(
+    c: &mut Criterion,
+    name: &str,
+    shards: usize,
+    port: u16,
+) {
+    let tmpdir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        shards,
+        ..KvStoreOptions::default()
+    };
+    let engine = KvStore::open_with(&tmpdir, options).unwrap();
+    let pool = P::new(SERVER_THREADS).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    c.bench_function(name, move |b| {
+        b.iter(|| {
+            let clients: Vec<_> = (0..CLIENTS)
+                .map(|client_id| {
+                    std::thread::spawn(move || {
+                        let mut client = KvsClient::new(addr).unwrap();
+                        for i in 0..CLIENT_WRITES {
+                            client
+                                .set(&format!("key{}-{}", client_id, i), "value")
+                                .unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for client in clients {
+                client.join().unwrap();
+            }
+        })
+    });
+    KvsClient::new(addr).unwrap().shutdown().unwrap();
+    server_thread.join().unwrap().unwrap();
+    drop(tmpdir);
+}
+
 fn server_kvs_shared_write(c: &mut Criterion) {
+    generic_server_write::<SharedQueueThreadPool>(c, "server_kvs_shared_write", 1, 4010);
+}
+
+fn server_kvs_sharded_shared_write(c: &mut Criterion) {
+    let shards = SERVER_THREADS as usize;
+    generic_server_write::<SharedQueueThreadPool>(
+        c,
+        "server_kvs_sharded_shared_write",
+        shards,
+        4011,
+    );
+}
+
+fn server_kvs_rayon_write(c: &mut Criterion) {
+    generic_server_write::<RayonThreadPool>(c, "server_kvs_rayon_write", 1, 4012);
+}
+
+fn server_kvs_sharded_rayon_write(c: &mut Criterion) {
+    let shards = SERVER_THREADS as usize;
+    generic_server_write::<RayonThreadPool>(c, "server_kvs_sharded_rayon_write", shards, 4013);
+}
+
+/// Measures a single client loading keys with a pipeline.
+fn server_kvs_pipelined_write(c: &mut Criterion) {
     let tmpdir = TempDir::new().unwrap();
     let engine = KvStore::open(&tmpdir).unwrap();
-    let pool = SharedQueueThreadPool::new(1).unwrap();
-    let addr = "127.0.0.1:4000".parse::<SocketAddr>().unwrap();
+    let pool = SharedQueueThreadPool::new(SERVER_THREADS).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 4014));
     let mut server = KvsServer::new(engine, pool, addr).unwrap();
-    let mut client = KvsClient::new(addr).unwrap();
     let server_thread = std::thread::spawn(move || server.run());
-    client.shutdown().unwrap();
-    assert!(server_thread.join().is_ok());
+    let mut client = KvsClient::new(addr).unwrap();
+    c.bench_function("server_kvs_pipelined_write", move |b| {
+        b.iter(|| {
+            let mut pipeline = client.pipeline().unwrap();
+            for i in 0..CLIENTS * CLIENT_WRITES {
+                pipeline.set(&format!("key{}", i), "value").unwrap();
+            }
+            assert!(pipeline.finish().unwrap().iter().all(|res| res.is_ok()));
+        })
+    });
+    KvsClient::new(addr).unwrap().shutdown().unwrap();
+    server_thread.join().unwrap().unwrap();
+    drop(tmpdir);
 }
 
 criterion_group!(
     benches,
     kvs_write,
     kvs_read,
+    kvs_mmap_read,
     sled_write,
     sled_read,
-    server_kvs_shared_write
+    server_kvs_shared_write,
+    server_kvs_sharded_shared_write,
+    server_kvs_rayon_write,
+    server_kvs_sharded_rayon_write,
+    server_kvs_pipelined_write
 );
 criterion_main!(benches);
@@ -0,0 +1,383 @@
+use crate::client::{check_server, ENCODING};
+use crate::{wire, KvError, Result, Stats, DEFAULT_NAMESPACE};
+use log::{debug, error};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// Size of chunks `AsyncKvsClient::set_reader()` sends.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Asynchronous counterpart of `KvsClient`.  Must be used from within a tokio runtime.
+///
+/// Requests share a connection that is reopened if the server closed it.  Requests issued
+/// concurrently, from clones of the same client included, are pipelined on it.  Streamed values
+/// get a connection of their own since they can not be interleaved with replies to other
+/// requests.
+#[derive(Clone)]
+pub struct AsyncKvsClient {
+    /// Namespace targeted by key-value operations.
+    ns: String,
+
+    shared: Arc<Shared>,
+}
+
+/// State shared by clones of a client.
+struct Shared {
+    addr: SocketAddr,
+
+    /// Connection to the server, if open.
+    conn: Mutex<Option<Arc<Connection>>>,
+
+    /// Identifier of the next request.
+    next_id: AtomicU64,
+
+    /// Secret presented to the server on connecting, if any.
+    token: std::sync::Mutex<Option<wire::Secret>>,
+}
+
+/// Connection requests are pipelined on.
+///
+/// A task writes requests so that callers giving up on a request do not leave it half sent.
+/// Another task reads replies and hands each of them over to the caller waiting for it.  Tasks
+/// do not keep the connection alive: once the client and its clones dropped it, the writing task
+/// closes it and the reading task ends when the server closes it in turn.
+struct Connection {
+    /// Requests to send.
+    requests: mpsc::UnboundedSender<Vec<u8>>,
+
+    waiting: Arc<Waiting>,
+
+    /// What the server told about itself when the connection was opened.
+    info: wire::ServerInfo,
+}
+
+/// Callers waiting for replies indexed by request identifier, `None` once the connection failed.
+type Waiting = std::sync::Mutex<Option<HashMap<u64, oneshot::Sender<Vec<u8>>>>>;
+
+impl AsyncKvsClient {
+    /// Creates a new client for server at `addr`.  Connects on first request.
+    pub fn new(addr: SocketAddr) -> AsyncKvsClient {
+        AsyncKvsClient {
+            ns: DEFAULT_NAMESPACE.to_owned(),
+            shared: Arc::new(Shared {
+                addr,
+                conn: Mutex::new(None),
+                next_id: AtomicU64::new(0),
+                token: std::sync::Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Targets key-value operations of this client to namespace `name`.  Clones are not affected.
+    pub fn set_namespace(&mut self, name: &str) {
+        self.ns = name.to_owned();
+    }
+
+    /// Presents `token` to servers requiring one before serving requests.
+    ///
+    /// The token applies to subsequent requests of this client and its clones.  Whether it
+    /// grants key-value or admin requests depends on which of its tokens the server was started
+    /// with.
+    pub async fn set_token(&self, token: &str) -> Result<()> {
+        let mut conn = self.shared.conn.lock().await;
+        *self.shared.token.lock()? = Some(wire::Secret(token.to_owned()));
+        conn.take();
+        Ok(())
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.send_recv(wire::Op::Get(self.ns.clone(), key.to_owned()))
+            .await
+    }
+
+    /// Sets value of `key` to `val`.
+    ///
+    /// Values making the request larger than the server accepts are streamed instead.
+    pub async fn set(&self, key: &str, val: &str) -> Result<()> {
+        let max = self.connection().await?.info.max_request_size;
+        // Identifiers all take the same room in the binary encoding.
+        let len = ENCODING.set_request_len(0, &self.ns, key, val)?;
+        if len > max.min(u64::from(wire::MAX_FRAME_LEN)) {
+            return self.set_reader(key, &mut val.as_bytes()).await;
+        }
+        let op = wire::Op::Set(self.ns.clone(), key.to_owned(), val.to_owned());
+        self.send_recv(op).await.map(|_: Option<String>| ())
+    }
+
+    pub async fn rm(&self, key: &str) -> Result<()> {
+        let op = wire::Op::Rm(self.ns.clone(), key.to_owned());
+        self.send_recv(op).await.map(|_: Option<String>| ())
+    }
+
+    /// Removes all keys of namespace `name`.
+    pub async fn drop_namespace(&self, name: &str) -> Result<()> {
+        let op = wire::Op::DropNamespace(name.to_owned());
+        self.send_recv(op).await.map(|_: Option<String>| ())
+    }
+
+    /// Returns size and garbage figures of the store the server delegates to.
+    pub async fn stats(&self) -> Result<Stats> {
+        self.send_recv(wire::Op::Stats).await
+    }
+
+    /// Sets value of `key` to what `value` yields, without holding it all in memory.
+    pub async fn set_reader(
+        &self,
+        key: &str,
+        value: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<()> {
+        let (mut rd, mut wr, id) = self.stream_connection().await?;
+        let op = wire::Op::SetStream(self.ns.clone(), key.to_owned());
+        send_request(&mut wr, id, op).await?;
+        let mut piece = vec![0; CHUNK_SIZE];
+        loop {
+            let len = value.read(&mut piece).await?;
+            if len == 0 {
+                break;
+            }
+            let mut buf = Vec::new();
+            std::io::Write::write_all(&mut wire::ChunkWriter::new(&mut buf), &piece[..len])?;
+            wr.write_all(&buf).await?;
+        }
+        wr.write_all(&wire::ChunkWriter::new(Vec::new()).finish()?)
+            .await?;
+        recv_reply(&mut rd, id).await.map(|_: Option<String>| ())
+    }
+
+    /// Writes value of `key` to `wr` and returns whether `key` exists.
+    pub async fn get_writer(
+        &self,
+        key: &str,
+        value: &mut (dyn AsyncWrite + Unpin + Send),
+    ) -> Result<bool> {
+        let (mut rd, mut wr, id) = self.stream_connection().await?;
+        let op = wire::Op::GetStream(self.ns.clone(), key.to_owned());
+        send_request(&mut wr, id, op).await?;
+        loop {
+            let len = wire::read_chunk_len(&mut rd).await?;
+            if len == 0 {
+                break;
+            }
+            let copied = tokio::io::copy(&mut (&mut rd).take(len), value).await?;
+            if copied < len {
+                return Err(KvError::Io(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        value.flush().await?;
+        recv_reply(&mut rd, id).await
+    }
+
+    /// Requests server to stop.
+    ///
+    /// When this function returns, the server has stopped all processing.
+    pub async fn shutdown(&self) -> Result<()> {
+        let res = self
+            .send_recv(wire::Op::Shutdown)
+            .await
+            .map(|_: Option<String>| ());
+        // The server closes all connections when stopping.
+        self.shared.conn.lock().await.take();
+        res
+    }
+
+    /// Returns what the server told about itself, connecting to it if needed.
+    pub async fn server_info(&self) -> Result<wire::ServerInfo> {
+        Ok(self.connection().await?.info.clone())
+    }
+
+    /// Sends request for `op` on the shared connection and waits for reply.
+    async fn send_recv<T: DeserializeOwned + Debug>(&self, op: wire::Op) -> Result<T> {
+        let conn = self.connection().await?;
+        let req = wire::Request {
+            id: self.next_id(),
+            op,
+        };
+        debug!("C: sending {:?}", req);
+        let mut buf = Vec::new();
+        ENCODING.write_request(&mut buf, &req)?;
+
+        let (tx, rx) = oneshot::channel();
+        match conn.waiting.lock()?.as_mut() {
+            Some(waiting) => waiting.insert(req.id, tx),
+            None => return Err(connection_lost()),
+        };
+        if conn.requests.send(buf).is_err() {
+            return Err(connection_lost());
+        }
+        let msg = rx.await.map_err(|_| connection_lost())?;
+        let reply = ENCODING.decode_reply::<T>(&msg)?;
+        debug!("C: received: {:?}", reply);
+        reply.result.map_err(KvError::from)
+    }
+
+    fn next_id(&self) -> u64 {
+        self.shared.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the shared connection, opening a new one if the previous one failed.
+    async fn connection(&self) -> Result<Arc<Connection>> {
+        let mut conn = self.shared.conn.lock().await;
+        if let Some(ref open) = *conn {
+            if open.waiting.lock()?.is_some() {
+                return Ok(open.clone());
+            }
+        }
+
+        *conn = None;
+        let (mut rd, mut wr) = self.connect().await?;
+        let info = self.hello(&mut rd, &mut wr).await?;
+        let (requests, pending) = mpsc::unbounded_channel();
+        let waiting = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        tokio::spawn(write_requests(waiting.clone(), wr, pending));
+        tokio::spawn(read_replies(waiting.clone(), rd));
+        let open = Arc::new(Connection {
+            requests,
+            waiting,
+            info,
+        });
+        *conn = Some(open.clone());
+        Ok(open)
+    }
+
+    /// Opens a connection for a single streaming request and returns it along with the
+    /// identifier of the request.
+    async fn stream_connection(&self) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf, u64)> {
+        let (mut rd, mut wr) = self.connect().await?;
+        self.hello(&mut rd, &mut wr).await?;
+        Ok((rd, wr, self.next_id()))
+    }
+
+    async fn connect(&self) -> Result<(BufReader<OwnedReadHalf>, OwnedWriteHalf)> {
+        let stream = TcpStream::connect(self.shared.addr).await?;
+        stream.set_nodelay(true)?;
+        let (rd, wr) = stream.into_split();
+        Ok((BufReader::new(rd), wr))
+    }
+
+    /// Checks that the server at the other end of a new connection speaks our protocol version
+    /// and presents the token, if any.
+    async fn hello(
+        &self,
+        rd: &mut BufReader<OwnedReadHalf>,
+        wr: &mut OwnedWriteHalf,
+    ) -> Result<wire::ServerInfo> {
+        let id = self.next_id();
+        let hello = async {
+            send_request(wr, id, wire::Op::Hello(wire::PROTOCOL_VERSION)).await?;
+            recv_reply(rd, id).await
+        };
+        let info = check_server(hello.await)?;
+        let token = self.shared.token.lock()?.clone();
+        if let Some(token) = token {
+            let id = self.next_id();
+            send_request(wr, id, wire::Op::Auth(token)).await?;
+            recv_reply(rd, id).await.map(|_: Option<String>| ())?;
+        }
+        Ok(info)
+    }
+}
+
+/// Sends requests handed over to the connection until it is dropped or fails.
+async fn write_requests(
+    waiting: Arc<Waiting>,
+    mut wr: OwnedWriteHalf,
+    mut requests: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
+    // Ends once the client and its clones dropped the connection, dropping `wr` then telling
+    // the server that no more requests follow.
+    while let Some(req) = requests.recv().await {
+        if let Err(err) = wr.write_all(&req).await {
+            error!("error while sending request: {}", err);
+            fail(&waiting);
+            return;
+        }
+    }
+}
+
+/// Hands replies over to callers waiting for them until the connection is closed or fails.
+async fn read_replies(waiting: Arc<Waiting>, mut rd: BufReader<OwnedReadHalf>) {
+    loop {
+        let msg = match ENCODING
+            .read_message_async(&mut rd, wire::MAX_FRAME_LEN as usize)
+            .await
+        {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                debug!("C: connection closed");
+                break;
+            }
+            Err(err) => {
+                error!("error while receiving reply: {}", err);
+                break;
+            }
+        };
+        let waiter = ENCODING.message_id(&msg).ok().and_then(|id| {
+            waiting
+                .lock()
+                .ok()?
+                .as_mut()
+                .and_then(|waiting| waiting.remove(&id))
+        });
+        match waiter {
+            // The caller may have given up on the request.
+            Some(waiter) => drop(waiter.send(msg)),
+            None => {
+                error!("reply to unexpected request");
+                break;
+            }
+        }
+    }
+    fail(&waiting);
+}
+
+/// Makes callers waiting for replies fail and later requests open a new connection.
+fn fail(waiting: &Waiting) {
+    if let Ok(mut waiting) = waiting.lock() {
+        waiting.take();
+    }
+}
+
+async fn send_request(wr: &mut OwnedWriteHalf, id: u64, op: wire::Op) -> Result<()> {
+    let req = wire::Request { id, op };
+    debug!("C: sending {:?}", req);
+    let mut buf = Vec::new();
+    ENCODING.write_request(&mut buf, &req)?;
+    wr.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Receives reply to request `id` on a connection not shared with other requests.
+async fn recv_reply<T: DeserializeOwned + Debug>(
+    rd: &mut BufReader<OwnedReadHalf>,
+    id: u64,
+) -> Result<T> {
+    let msg = ENCODING
+        .read_message_async(rd, wire::MAX_FRAME_LEN as usize)
+        .await?
+        .ok_or_else(|| KvError::Io(ErrorKind::UnexpectedEof.into()))?;
+    let reply = ENCODING.decode_reply::<T>(&msg)?;
+    debug!("C: received: {:?}", reply);
+    if reply.id != id {
+        return Err(KvError::Io(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("reply to unexpected request {}", reply.id),
+        )));
+    }
+    reply.result.map_err(KvError::from)
+}
+
+fn connection_lost() -> KvError {
+    KvError::Io(std::io::Error::new(
+        ErrorKind::ConnectionAborted,
+        "connection to server lost",
+    ))
+}
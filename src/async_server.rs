@@ -0,0 +1,532 @@
+use crate::server::{
+    bad_request, handle_request, oversized_request, KvsServerOptions, Outcome, ShutdownHandle,
+    ACCEPT_BACKOFF, MAX_IN_FLIGHT, PENDING_WAIT,
+};
+use crate::{thread_pool::*, wire, KvError, KvsEngine, Result};
+use log::{debug, error, warn};
+use serde::Serialize;
+use std::fmt::Debug;
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::SpooledTempFile;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify, Semaphore};
+use tokio::task::JoinSet;
+
+/// TCP/IP server handling requests from KvsClient instances with an event loop.
+///
+/// A single thread multiplexes all connections, which suits many mostly idle clients.  Engine
+/// calls still run on the thread pool.  Streamed values are spooled to temporary files so that
+/// they need not fit in memory and slow clients do not hold threads of the pool.
+pub struct AsyncKvsServer<E: KvsEngine, P: ThreadPool> {
+    listener: std::net::TcpListener,
+    shared: Arc<Shared<E, P>>,
+
+    /// Notified by shutdown handles.
+    stop: Arc<Notify>,
+}
+
+/// State shared by connection tasks.
+struct Shared<E, P> {
+    engine: E,
+    pool: P,
+    info: wire::ServerInfo,
+    options: KvsServerOptions,
+}
+
+/// Writing half of a connection, shared by tasks replying to its requests.  `None` once writing
+/// failed.
+type Writer = Arc<Mutex<Option<OwnedWriteHalf>>>;
+
+/// Size above which streamed values are spooled to a temporary file rather than memory.
+const SPOOL_MEMORY: usize = 1024 * 1024;
+
+/// Number of received pieces of a streamed value waiting to be spooled.
+const SPOOL_QUEUE: usize = 16;
+
+/// Size of chunks streamed values are sent as.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Connection a shutdown request came from, its encoding and the request identifier.
+type Requester = (Writer, wire::Encoding, u64);
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> AsyncKvsServer<E, P> {
+    /// Creates a new server listening for requests on `addr` and delegating requests to `engine`.
+    pub fn new(engine: E, pool: P, addr: SocketAddr) -> Result<AsyncKvsServer<E, P>> {
+        AsyncKvsServer::new_with(engine, pool, addr, KvsServerOptions::default())
+    }
+
+    /// Creates a new server with non-default options.
+    pub fn new_with(
+        engine: E,
+        pool: P,
+        addr: SocketAddr,
+        options: KvsServerOptions,
+    ) -> Result<AsyncKvsServer<E, P>> {
+        Ok(AsyncKvsServer {
+            listener: std::net::TcpListener::bind(addr)?,
+            shared: Arc::new(Shared {
+                engine,
+                pool,
+                info: options.server_info(),
+                options,
+            }),
+            stop: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Returns a handle stopping this server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::event_loop(self.stop.clone())
+    }
+
+    /// Serves requests until shutdown requested or a fatal error occurs.
+    pub fn run(&mut self) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(self.serve())
+    }
+
+    async fn serve(&self) -> Result<()> {
+        let listener = self.listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let listener = TcpListener::from_std(listener)?;
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (shutdown_tx, mut shutdown_rx) = mpsc::channel(1);
+        let mut connections = JoinSet::new();
+
+        let requester: Option<Requester> = loop {
+            tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(_) if connections.len() >= self.shared.options.max_connections => {
+                        warn!("too many connections, closing new one");
+                    }
+                    Ok((stream, _)) => {
+                        let shared = self.shared.clone();
+                        let stop = stop_rx.clone();
+                        let shutdown = shutdown_tx.clone();
+                        connections.spawn(async move {
+                            match serve_connection(shared, stream, stop).await {
+                                Ok(None) => debug!("S: connection closed"),
+                                Ok(Some(requester)) => {
+                                    // Fails if another connection requested shutdown first.
+                                    let _ = shutdown.send(requester).await;
+                                }
+                                Err(err) => error!("error while handling request: {}", err),
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        error!("error while accepting connection: {}", err);
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    }
+                },
+                Some(requester) = shutdown_rx.recv() => break Some(requester),
+                _ = self.stop.notified() => break None,
+                Some(res) = connections.join_next(), if !connections.is_empty() => {
+                    if res.is_err() {
+                        error!("connection task panicked");
+                    }
+                }
+            }
+        };
+
+        // Connections complete once requests already sent are handled.
+        drop(shutdown_rx);
+        let _ = stop_tx.send(true);
+        let drain = async {
+            while let Some(res) = connections.join_next().await {
+                if res.is_err() {
+                    error!("connection task panicked");
+                }
+            }
+        };
+        if wire::with_timeout(self.shared.options.drain_timeout, drain)
+            .await
+            .is_err()
+        {
+            warn!("gave up waiting for requests being handled");
+        }
+        let res = self.shared.engine.flush();
+
+        if let Some((wr, encoding, id)) = requester {
+            let reply = wire::Reply::<Option<String>> {
+                id,
+                result: match res {
+                    Ok(()) => Ok(None),
+                    Err(ref err) => Err(wire::ReplyError::Other(err.to_string())),
+                },
+            };
+            let timeout = self.shared.options.write_timeout;
+            if let Err(err) = send_reply(&wr, encoding, &reply, timeout).await {
+                error!("error while replying to shutdown request: {}", err);
+            }
+        }
+        debug!("S: exiting");
+        res
+    }
+}
+
+/// Serves requests received on `stream` until the client closes it or, once `stop` changed, has
+/// no request left to send.
+///
+/// Returns where the shutdown request came from if one was received on it.  All requests
+/// received before are handled when this function returns.
+async fn serve_connection<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    shared: Arc<Shared<E, P>>,
+    stream: TcpStream,
+    mut stop: watch::Receiver<bool>,
+) -> Result<Option<Requester>> {
+    stream.set_nodelay(true)?;
+    let (rd, wr) = stream.into_split();
+    let mut rd = BufReader::new(rd);
+    if !wait_request(&mut rd, shared.options.idle_timeout, &mut stop).await? {
+        return Ok(None);
+    }
+    let encoding = match wire::Encoding::detect_async(&mut rd).await? {
+        Some(encoding) => encoding,
+        None => return Ok(None),
+    };
+    debug!("S: {:?} connection", encoding);
+
+    let conn = Connection {
+        shared,
+        encoding,
+        wr: Arc::new(Mutex::new(Some(wr))),
+        in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+    };
+    let res = conn.serve(&mut rd, &mut stop).await;
+    // Requests received before the connection failed are still replied to.
+    drop(conn.in_flight.acquire_many(MAX_IN_FLIGHT as u32).await);
+    Ok(res?.map(|(id, encoding)| (conn.wr, encoding, id)))
+}
+
+/// Connection being served.
+struct Connection<E, P> {
+    shared: Arc<Shared<E, P>>,
+    encoding: wire::Encoding,
+    wr: Writer,
+
+    /// Permits for requests handled concurrently.
+    in_flight: Arc<Semaphore>,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Connection<E, P> {
+    /// Serves requests until the client closes the connection or, once `stop` changed, has no
+    /// request left to send.
+    ///
+    /// Returns the identifier and encoding of the shutdown request if one was received.
+    async fn serve(
+        &self,
+        rd: &mut BufReader<OwnedReadHalf>,
+        stop: &mut watch::Receiver<bool>,
+    ) -> Result<Option<(u64, wire::Encoding)>> {
+        let options = &self.shared.options;
+        let mut access = options.initial_access();
+        // Legacy clients send a single request and wait for the connection to be closed.
+        let mut legacy = false;
+        loop {
+            if legacy {
+                return Ok(None);
+            }
+            let msg = match self.read_message(rd, stop).await? {
+                Some(wire::Message::Whole(msg)) => msg,
+                Some(wire::Message::TooLarge(id)) => {
+                    // The next request can still be read since the message was skipped.
+                    match oversized_request(id, options.max_request_size) {
+                        Some(reply) => self.send_reply(self.encoding, &reply).await?,
+                        None => return Ok(None),
+                    }
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            let (req, encoding) = match self.encoding.decode_request(&msg) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    // The next request can still be read since the message was framed.
+                    if let Some(reply) = bad_request(self.encoding, &msg, err) {
+                        self.send_reply(self.encoding, &reply).await?;
+                    }
+                    continue;
+                }
+            };
+            legacy = encoding == wire::Encoding::LegacyJson;
+            debug!("S: handling {:?}", req);
+            let id = req.id;
+            if let Err(err) = access.check(&req.op) {
+                warn!("rejected {:?}: {:?}", req.op, err);
+                if let wire::Op::SetStream(..) = req.op {
+                    // Skip the value so that the next request can be read.
+                    wire::skip_chunks(rd, options.request_timeout).await?;
+                }
+                let reply = wire::Reply::<Option<String>> {
+                    id,
+                    result: Err(err),
+                };
+                self.send_reply(encoding, &reply).await?;
+                continue;
+            }
+            match req.op {
+                wire::Op::Shutdown => return Ok(Some((id, encoding))),
+                wire::Op::Auth(token) => {
+                    let (granted, result) = access.authenticate(options, &token);
+                    access = granted;
+                    self.send_reply(encoding, &wire::Reply { id, result })
+                        .await?;
+                }
+                wire::Op::Hello(version) => {
+                    if version != wire::PROTOCOL_VERSION {
+                        warn!("client speaks protocol version {}", version);
+                    }
+                    let reply = wire::Reply {
+                        id,
+                        result: Ok(&self.shared.info),
+                    };
+                    self.send_reply(encoding, &reply).await?;
+                }
+                // Streaming requests are handled before reading further requests, as by
+                // `KvsServer`.  Values are spooled so that the thread pool does not wait for
+                // clients, however slow they are.
+                wire::Op::SetStream(ns, key) => {
+                    let result = self
+                        .set_streamed(rd, ns, key)
+                        .await?
+                        .map(|_| None::<String>)
+                        .map_err(wire::ReplyError::from);
+                    self.send_reply(encoding, &wire::Reply { id, result })
+                        .await?;
+                }
+                wire::Op::GetStream(ns, key) => {
+                    let res = self.spawn_on_pool(move |engine| {
+                        let mut value = tempfile::spooled_tempfile(SPOOL_MEMORY);
+                        let found = engine
+                            .namespace(&ns)
+                            .and_then(|e| e.get_writer(key, &mut value))?;
+                        value.seek(SeekFrom::Start(0))?;
+                        Ok((found, value))
+                    });
+                    let res: Result<(bool, SpooledTempFile)> = handler_result(res).await?;
+                    // Replies to other requests must not be interleaved with the value.
+                    let mut wr = self.wr.lock().await;
+                    let write_timeout = options.write_timeout;
+                    let result = match res {
+                        Ok((found, mut value)) => {
+                            let mut piece = vec![0; CHUNK_SIZE];
+                            loop {
+                                let len = value.read(&mut piece)?;
+                                if len == 0 {
+                                    break;
+                                }
+                                let mut buf = Vec::new();
+                                wire::ChunkWriter::new(&mut buf).write_all(&piece[..len])?;
+                                write_locked(&mut wr, &buf, write_timeout).await?;
+                            }
+                            Ok(found)
+                        }
+                        Err(err) => Err(wire::ReplyError::from(err)),
+                    };
+                    let mut buf = wire::ChunkWriter::new(Vec::new()).finish()?;
+                    encoding.write_reply(&mut buf, &wire::Reply { id, result })?;
+                    write_locked(&mut wr, &buf, write_timeout).await?;
+                }
+                op => {
+                    let permit = self
+                        .in_flight
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("semaphore never closed");
+                    let outcome = self.spawn_on_pool(move |engine| handle_request(engine, op));
+                    let wr = self.wr.clone();
+                    let write_timeout = self.shared.options.write_timeout;
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        let outcome = outcome.await.unwrap_or_else(|_| {
+                            Outcome::Value(Err(wire::ReplyError::Other(
+                                "request handler panicked".to_owned(),
+                            )))
+                        });
+                        let res = match outcome.encode(encoding, id) {
+                            Ok(buf) => write_all(&wr, &buf, write_timeout).await,
+                            Err(err) => Err(err),
+                        };
+                        match res {
+                            Ok(()) => debug!("S: OK"),
+                            Err(err) => error!("error while replying: {}", err),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Receives a request without decoding it, skipping it if too large.  Returns `None` if the
+    /// connection was closed, stayed idle for too long between requests or if `stop` changed and
+    /// no request was sent before.
+    async fn read_message(
+        &self,
+        rd: &mut BufReader<OwnedReadHalf>,
+        stop: &mut watch::Receiver<bool>,
+    ) -> Result<Option<wire::Message>> {
+        let options = &self.shared.options;
+        if !wait_request(rd, options.idle_timeout, stop).await? {
+            return Ok(None);
+        }
+        let read = self
+            .encoding
+            .read_message_or_skip_async(rd, options.max_request_size);
+        wire::with_timeout(options.request_timeout, read).await?
+    }
+
+    /// Receives the value streamed on `rd` and sets `key` of namespace `ns` to it.  The outer
+    /// error tells that the connection failed and the inner one that the value was refused.
+    ///
+    /// Values are spooled by a blocking task so that the event loop does not wait for the disk,
+    /// and skipped instead as soon as the engine would refuse them.
+    async fn set_streamed(
+        &self,
+        rd: &mut BufReader<OwnedReadHalf>,
+        ns: String,
+        key: String,
+    ) -> Result<Result<()>> {
+        let timeout = self.shared.options.request_timeout;
+        let checked = self.spawn_on_pool({
+            let (ns, key) = (ns.clone(), key.clone());
+            move |engine| engine.namespace(&ns)?.check_set(&key)
+        });
+        let max_size = match handler_result(checked).await? {
+            Ok(max_size) => max_size,
+            Err(err) => {
+                wire::skip_chunks(rd, timeout).await?;
+                return Ok(Err(err));
+            }
+        };
+
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(SPOOL_QUEUE);
+        let spool = tokio::task::spawn_blocking(move || -> Result<SpooledTempFile> {
+            let mut value = tempfile::spooled_tempfile(SPOOL_MEMORY);
+            while let Some(piece) = rx.blocking_recv() {
+                value.write_all(&piece)?;
+            }
+            value.seek(SeekFrom::Start(0))?;
+            Ok(value)
+        });
+        let received = wire::copy_chunks(rd, Some(&tx), max_size.unwrap_or(u64::MAX), timeout);
+        let received = received.await?;
+        drop(tx);
+        if received.is_none() {
+            let max_size = max_size.expect("values of any size accepted without a maximum");
+            return Ok(Err(KvError::ValueTooLarge(max_size)));
+        }
+        let mut value = match spool.await {
+            Ok(Ok(value)) => value,
+            Ok(Err(err)) => return Ok(Err(err)),
+            Err(_) => return Err(KvError::Other("spooling task panicked".to_owned())),
+        };
+
+        let res = self.spawn_on_pool(move |engine| {
+            engine
+                .namespace(&ns)
+                .and_then(|e| e.set_reader(key, &mut value))
+        });
+        handler_result(res).await
+    }
+
+    async fn send_reply<T: Serialize + Debug>(
+        &self,
+        encoding: wire::Encoding,
+        r: &wire::Reply<T>,
+    ) -> Result<()> {
+        send_reply(&self.wr, encoding, r, self.shared.options.write_timeout).await
+    }
+
+    /// Runs `f` on the thread pool.  The returned receiver fails if `f` panicked.
+    fn spawn_on_pool<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&E) -> T + Send + 'static,
+    ) -> oneshot::Receiver<T> {
+        let (tx, rx) = oneshot::channel();
+        let engine = self.shared.engine.clone();
+        self.shared.pool.spawn(move || {
+            let _ = tx.send(f(&engine));
+        });
+        rx
+    }
+}
+
+/// Waits for a request to start arriving on `rd` for at most `idle_timeout`.  Returns whether
+/// one did, `false` meaning that the connection was closed, stayed idle for too long or that
+/// `stop` changed and no request was sent before.
+async fn wait_request(
+    rd: &mut BufReader<OwnedReadHalf>,
+    idle_timeout: Option<Duration>,
+    stop: &mut watch::Receiver<bool>,
+) -> Result<bool> {
+    let wait = loop {
+        if *stop.borrow() {
+            break wire::with_timeout(Some(PENDING_WAIT), rd.fill_buf()).await;
+        }
+        // Filling the buffer can be cancelled without losing data.
+        tokio::select! {
+            wait = wire::with_timeout(idle_timeout, rd.fill_buf()) => break wait,
+            res = stop.changed() => {
+                if res.is_err() {
+                    return Ok(false);
+                }
+            }
+        }
+    };
+    match wait {
+        Ok(buf) => Ok(!buf?.is_empty()),
+        Err(_) => {
+            debug!("S: closing idle connection");
+            Ok(false)
+        }
+    }
+}
+
+/// Waits for the result of a function run by `Connection::spawn_on_pool()`.
+async fn handler_result<T>(rx: oneshot::Receiver<T>) -> Result<T> {
+    rx.await
+        .map_err(|_| KvError::Other("request handler panicked".to_owned()))
+}
+
+async fn send_reply<T: Serialize + Debug>(
+    wr: &Writer,
+    encoding: wire::Encoding,
+    r: &wire::Reply<T>,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    debug!("S: replying {:?}", r);
+    let mut buf = Vec::new();
+    encoding.write_reply(&mut buf, r)?;
+    write_all(wr, &buf, timeout).await
+}
+
+/// Writes `buf` to the connection, closing it if that fails or takes longer than `timeout`.
+async fn write_all(wr: &Writer, buf: &[u8], timeout: Option<Duration>) -> Result<()> {
+    write_locked(&mut *wr.lock().await, buf, timeout).await
+}
+
+/// Version of `write_all()` for callers already holding the writing half.
+async fn write_locked(
+    wr: &mut Option<OwnedWriteHalf>,
+    buf: &[u8],
+    timeout: Option<Duration>,
+) -> Result<()> {
+    let stream = wr
+        .as_mut()
+        .ok_or_else(|| KvError::Io(ErrorKind::NotConnected.into()))?;
+    let res = wire::with_timeout(timeout, stream.write_all(buf)).await;
+    if !matches!(res, Ok(Ok(()))) {
+        // What is left of `buf` would garble later replies.
+        wr.take();
+    }
+    Ok(res??)
+}
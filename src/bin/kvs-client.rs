@@ -19,6 +19,24 @@ fn try_main() -> Result<()> {
                 .help("Sets IP address and port to connect to")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("namespace")
+                .long("namespace")
+                .value_name("NAME")
+                .set(ArgSettings::Global)
+                .help("Sets namespace targeted by key-value operations")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("TOKEN")
+                .env("KVS_TOKEN")
+                .hide_env_values(true)
+                .set(ArgSettings::Global)
+                .help("Authenticates with this token or admin token")
+                .takes_value(true),
+        )
         .subcommand(SubCommand::with_name("get").arg(Arg::with_name("key").required(true).index(1)))
         .subcommand(
             SubCommand::with_name("set")
@@ -26,6 +44,18 @@ fn try_main() -> Result<()> {
                 .arg(Arg::with_name("value").required(true).index(2)),
         )
         .subcommand(SubCommand::with_name("rm").arg(Arg::with_name("key").required(true).index(1)))
+        .subcommand(
+            SubCommand::with_name("drop-namespace")
+                .arg(Arg::with_name("name").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("stats").arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .help("Prints statistics as JSON"),
+            ),
+        )
+        .subcommand(SubCommand::with_name("shutdown").about("Stops the server"))
         .get_matches();
 
     let addr: SocketAddr = matches
@@ -34,6 +64,12 @@ fn try_main() -> Result<()> {
         .parse()?;
 
     let mut client = KvsClient::new(addr)?;
+    if let Some(ns) = matches.value_of("namespace") {
+        client.set_namespace(ns);
+    }
+    if let Some(token) = matches.value_of("token") {
+        client.set_token(token);
+    }
 
     match matches.subcommand() {
         ("get", Some(smatches)) => match client.get(smatches.value_of("key").unwrap()) {
@@ -52,6 +88,19 @@ fn try_main() -> Result<()> {
             smatches.value_of("value").unwrap(),
         ),
         ("rm", Some(smatches)) => client.rm(smatches.value_of("key").unwrap()),
+        ("drop-namespace", Some(smatches)) => {
+            client.drop_namespace(smatches.value_of("name").unwrap())
+        }
+        ("stats", Some(smatches)) => {
+            let stats = client.stats()?;
+            if smatches.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                println!("{}", stats);
+            }
+            Ok(())
+        }
+        ("shutdown", Some(_)) => client.shutdown(),
         _ => panic!("clap should have detected missing subcommand"),
     }
 }
@@ -1,10 +1,17 @@
-use clap::{App, Arg};
-use kvs::{self, thread_pool::*, EngineKind, KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
-use log::info;
+use clap::{App, Arg, ArgMatches};
+use kvs::{
+    self, thread_pool::*, EngineRegistry, KvError, KvsServer, KvsServerOptions, Result,
+    ShutdownHandle,
+};
+use log::{error, info};
 
+use std::convert::TryFrom;
 use std::error::Error;
 
+use std::fs;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
 
 fn try_main() -> Result<()> {
     let matches = App::new("kvs-server")
@@ -25,6 +32,98 @@ fn try_main() -> Result<()> {
                 .help("Sets key-value store backend")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("data_dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help("Sets directory holding the store (default: current directory)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("runtime")
+                .long("runtime")
+                .value_name("RUNTIME")
+                .help("Serves connections with a reader thread pool (sync) or an event loop (async)")
+                .possible_values(&["sync", "async"])
+                .default_value("sync"),
+        )
+        .arg(
+            Arg::with_name("max_key_len")
+                .long("max-key-len")
+                .value_name("BYTES")
+                .help("Refuses keys longer than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_value_size")
+                .long("max-value-size")
+                .value_name("BYTES")
+                .help("Refuses values larger than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_store_size")
+                .long("max-store-size")
+                .value_name("BYTES")
+                .help("Refuses writes once the store is this large")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("request_timeout")
+                .long("request-timeout")
+                .value_name("SECONDS")
+                .help("Closes connections taking longer than this to send a request")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("idle_timeout")
+                .long("idle-timeout")
+                .value_name("SECONDS")
+                .help("Closes connections idle for this long between requests")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_request_size")
+                .long("max-request-size")
+                .value_name("BYTES")
+                .help("Closes connections sending requests larger than this, streamed values excluded")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_connections")
+                .long("max-connections")
+                .value_name("COUNT")
+                .help("Closes new connections while this many are open")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reader_threads")
+                .long("reader-threads")
+                .value_name("COUNT")
+                .help("Reads requests from connections with this many threads (sync runtime)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drain_timeout")
+                .long("drain-timeout")
+                .value_name("SECONDS")
+                .help("Waits this long for requests being handled when shutting down")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("token_file")
+                .long("token-file")
+                .value_name("FILE")
+                .help("Serves only clients presenting the token stored in this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin_token_file")
+                .long("admin-token-file")
+                .value_name("FILE")
+                .help("Serves admin requests only to clients presenting the token stored in this file")
+                .takes_value(true),
+        )
         .get_matches();
 
     let addr: SocketAddr = matches
@@ -33,18 +132,129 @@ fn try_main() -> Result<()> {
         .parse()?;
 
     let engine_name = matches.value_of("engine");
+    let data_dir = Path::new(matches.value_of("data_dir").unwrap_or("."));
 
     info!("version: {}", env!("CARGO_PKG_VERSION"));
     info!("engine: {}", engine_name.unwrap_or("default"));
     info!("address: {}", addr);
+    info!("data directory: {}", data_dir.display());
+    let runtime = matches.value_of("runtime").unwrap_or("sync");
+    info!("runtime: {}", runtime);
 
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
 
-    let (engine_kind, dir) = kvs::prepare_engine_creation(engine_name)?;
-    match engine_kind {
-        EngineKind::Kvs => KvsServer::new(KvStore::open(dir)?, pool, addr)?.run(),
-        EngineKind::Sled => KvsServer::new(SledKvsEngine::open(dir)?, pool, addr)?.run(),
+    let registry = EngineRegistry::default();
+    let (mut manifest, dir) = kvs::prepare_engine_creation(&registry, data_dir, engine_name)?;
+    // Limits given on the command line replace those recorded in the manifest.
+    let mut limits = manifest.limits()?;
+    if let Some(len) = parse_size(&matches, "max_key_len")? {
+        limits.max_key_len = Some(len as usize);
+    }
+    if let Some(size) = parse_size(&matches, "max_value_size")? {
+        limits.max_value_size = Some(size);
+    }
+    if let Some(size) = parse_size(&matches, "max_store_size")? {
+        limits.max_store_size = Some(size);
+    }
+    info!("limits: {:?}", limits);
+    if limits != manifest.limits()? {
+        manifest.set_limits(&limits);
+        if registry.is_persistent(&manifest.engine)? {
+            manifest.save(data_dir)?;
+        }
+    }
+    let engine = registry.open(&manifest, &dir)?;
+    let mut options = KvsServerOptions {
+        engine_name: Some(manifest.engine.clone()),
+        ..KvsServerOptions::default()
+    };
+    if let Some(timeout) = parse_timeout(&matches, "request_timeout")? {
+        options.request_timeout = Some(timeout);
+    }
+    if let Some(timeout) = parse_timeout(&matches, "idle_timeout")? {
+        options.idle_timeout = Some(timeout);
+    }
+    if let Some(timeout) = parse_timeout(&matches, "drain_timeout")? {
+        options.drain_timeout = Some(timeout);
+    }
+    if let Some(size) = parse_size(&matches, "max_request_size")? {
+        options.max_request_size = size as usize;
+    }
+    if let Some(count) = parse_size(&matches, "max_connections")? {
+        options.max_connections = count as usize;
+    }
+    if let Some(count) = parse_size(&matches, "reader_threads")? {
+        options.reader_threads = u32::try_from(count)
+            .ok()
+            .filter(|&count| count > 0)
+            .ok_or_else(|| KvError::Other(format!("invalid thread count: {}", count)))?;
+    }
+    options.token = read_token(&matches, "token_file")?;
+    options.admin_token = read_token(&matches, "admin_token_file")?;
+    match runtime {
+        #[cfg(feature = "async")]
+        "async" => {
+            let mut server = kvs::AsyncKvsServer::new_with(engine, pool, addr, options)?;
+            stop_on_signal(server.shutdown_handle())?;
+            server.run()
+        }
+        #[cfg(not(feature = "async"))]
+        "async" => Err(KvError::Other(
+            "kvs-server built without the async feature".to_owned(),
+        )),
+        _ => {
+            let mut server = KvsServer::new_with(engine, pool, addr, options)?;
+            stop_on_signal(server.shutdown_handle())?;
+            server.run()
+        }
+    }
+}
+
+/// Returns token stored in file named by argument `name` if present.
+fn read_token(matches: &ArgMatches, name: &str) -> Result<Option<String>> {
+    let path = match matches.value_of(name) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let token = fs::read_to_string(path)?.trim().to_owned();
+    if token.is_empty() {
+        return Err(KvError::Other(format!("empty token file: {}", path)));
     }
+    Ok(Some(token))
+}
+
+/// Shuts the server down gracefully on SIGINT and SIGTERM.
+fn stop_on_signal(handle: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("shutting down");
+        if let Err(err) = handle.shutdown() {
+            error!("error while shutting down: {}", err);
+        }
+    })
+    .map_err(|err| KvError::Other(format!("failed to handle signals: {}", err)))
+}
+
+/// Returns value of size argument `name` if present.
+fn parse_size(matches: &ArgMatches, name: &str) -> Result<Option<u64>> {
+    matches
+        .value_of(name)
+        .map(|val| {
+            val.parse()
+                .map_err(|_| KvError::Other(format!("invalid size: {}", val)))
+        })
+        .transpose()
+}
+
+/// Returns value of timeout argument `name`, given in seconds, if present.
+fn parse_timeout(matches: &ArgMatches, name: &str) -> Result<Option<Duration>> {
+    matches
+        .value_of(name)
+        .map(|val| {
+            val.parse()
+                .map(Duration::from_secs)
+                .map_err(|_| KvError::Other(format!("invalid timeout: {}", val)))
+        })
+        .transpose()
 }
 
 fn main() {
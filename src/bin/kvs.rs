@@ -1,6 +1,7 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::{self, EngineKind, KvError, KvStore, KvsEngine, Result, SledKvsEngine};
+use clap::{App, AppSettings, Arg, ArgSettings, SubCommand};
+use kvs::{self, EngineRegistry, KvError, KvStore, KvsEngine, Result};
 use std::error::Error;
+use std::path::Path;
 
 fn try_main() -> Result<()> {
     let matches = App::new("kvs")
@@ -15,6 +16,22 @@ fn try_main() -> Result<()> {
                 .help("Sets key-value store backend")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("data_dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .set(ArgSettings::Global)
+                .help("Sets directory holding the store (default: current directory)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("namespace")
+                .long("namespace")
+                .value_name("NAME")
+                .set(ArgSettings::Global)
+                .help("Sets namespace targeted by key-value operations")
+                .takes_value(true),
+        )
         .subcommand(SubCommand::with_name("get").arg(Arg::with_name("key").required(true).index(1)))
         .subcommand(
             SubCommand::with_name("set")
@@ -22,16 +39,112 @@ fn try_main() -> Result<()> {
                 .arg(Arg::with_name("value").required(true).index(2)),
         )
         .subcommand(SubCommand::with_name("rm").arg(Arg::with_name("key").required(true).index(1)))
+        .subcommand(
+            SubCommand::with_name("drop-namespace")
+                .arg(Arg::with_name("name").required(true).index(1)),
+        )
+        .subcommand(
+            SubCommand::with_name("stats").arg(
+                Arg::with_name("json")
+                    .long("json")
+                    .help("Prints statistics as JSON"),
+            ),
+        )
+        .subcommand(
+            SubCommand::with_name("upgrade").about("Migrates store to the current on-disk format"),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Checks store integrity, exiting with an error if it is damaged")
+                .arg(
+                    Arg::with_name("repair_to")
+                        .long("repair-to")
+                        .value_name("FILE")
+                        .help(
+                            "Writes log holding all recoverable keys to FILE, or one log per shard \
+                             in directory FILE for sharded stores",
+                        )
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Copies the store to another engine and switches to it")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .value_name("ENGINE-NAME")
+                        .help("Checks that the store currently uses ENGINE-NAME")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("ENGINE-NAME")
+                        .help("Sets engine to migrate to")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    let (engine_kind, dir) = kvs::prepare_engine_creation(matches.value_of("engine"))?;
-    match engine_kind {
-        EngineKind::Kvs => handle_subcommand(matches, KvStore::open(dir)?),
-        EngineKind::Sled => handle_subcommand(matches, SledKvsEngine::open(dir)?),
+    let registry = EngineRegistry::default();
+    let data_dir = Path::new(matches.value_of("data_dir").unwrap_or("."));
+
+    // The source engine is selected by --from rather than --engine.
+    if let ("migrate", Some(smatches)) = matches.subcommand() {
+        let to = smatches.value_of("to").unwrap();
+        let from = smatches
+            .value_of("from")
+            .or_else(|| matches.value_of("engine"));
+        let count = kvs::migrate(&registry, data_dir, from, to)?;
+        println!("Migrated {} keys to {} engine", count, to);
+        return Ok(());
     }
+    // Offline commands check or fix an existing store and so must not create one.
+    let offline = matches!(matches.subcommand_name(), Some("upgrade") | Some("fsck"));
+    let (mut manifest, dir) = if offline {
+        kvs::find_existing_store(&registry, data_dir, matches.value_of("engine"))?
+    } else {
+        kvs::prepare_engine_creation(&registry, data_dir, matches.value_of("engine"))?
+    };
+
+    // Offline commands must run before opening the store as opening fails on older formats or
+    // damaged logs.
+    match (matches.subcommand(), manifest.engine.as_str()) {
+        (("upgrade", _), "kvs") | (("upgrade", _), "kvs-sharded") => {
+            let version = KvStore::upgrade(dir)?;
+            manifest.format_version = KvStore::FORMAT_VERSION;
+            manifest.save(data_dir)?;
+            println!("Upgraded store from format version {}", version);
+            return Ok(());
+        }
+        (("fsck", Some(smatches)), "kvs") | (("fsck", Some(smatches)), "kvs-sharded") => {
+            let report = KvStore::fsck(dir, smatches.value_of("repair_to").map(Path::new))?;
+            println!("{}", report);
+            return if report.is_damaged() {
+                Err(KvError::Other("store is damaged".to_owned()))
+            } else {
+                Ok(())
+            };
+        }
+        (("upgrade", _), _) | (("fsck", _), _) => {
+            return Err(KvError::Other(format!(
+                "{} engine does not support this command",
+                manifest.engine
+            )));
+        }
+        _ => (),
+    }
+
+    handle_subcommand(matches, registry.open(&manifest, &dir)?)
 }
 
 fn handle_subcommand(matches: clap::ArgMatches, engine: impl KvsEngine) -> Result<()> {
+    let engine = match matches.value_of("namespace") {
+        Some(ns) => engine.namespace(ns)?,
+        None => engine,
+    };
     match matches.subcommand() {
         ("get", Some(smatches)) => match engine.get(smatches.value_of("key").unwrap().to_owned()) {
             Ok(Some(val)) => {
@@ -49,6 +162,18 @@ fn handle_subcommand(matches: clap::ArgMatches, engine: impl KvsEngine) -> Resul
             smatches.value_of("value").unwrap().to_owned(),
         ),
         ("rm", Some(smatches)) => engine.remove(smatches.value_of("key").unwrap().to_owned()),
+        ("drop-namespace", Some(smatches)) => {
+            engine.drop_namespace(smatches.value_of("name").unwrap())
+        }
+        ("stats", Some(smatches)) => {
+            let stats = engine.stats()?;
+            if smatches.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&stats)?);
+            } else {
+                println!("{}", stats);
+            }
+            Ok(())
+        }
         _ => panic!("clap should have detected missing subcommand"),
     }
 }
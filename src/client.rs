@@ -1,51 +1,522 @@
-use crate::{wire, KvError, Result};
+use crate::{wire, KvError, Result, Stats, DEFAULT_NAMESPACE};
 use log::debug;
+use serde::de::DeserializeOwned;
 
-use std::io::prelude::*;
+use std::fmt::Debug;
+use std::io::{self, prelude::*, BufReader, BufWriter, ErrorKind};
 use std::net::SocketAddr;
 use std::net::TcpStream;
 
+/// Encoding of requests and replies.  The server also accepts JSON for compatibility.
+pub(crate) const ENCODING: wire::Encoding = wire::Encoding::Binary;
+
 /// TCP/IP client connecting to key-value store server.
+///
+/// All requests are sent on the same connection, which is reopened if the server closed it.
 pub struct KvsClient {
     addr: SocketAddr,
+
+    /// Namespace targeted by key-value operations.
+    ns: String,
+
+    /// Connection to the server, if open.
+    conn: Option<BufReader<TcpStream>>,
+
+    /// Identifier of the next request.
+    next_id: u64,
+
+    /// What the server told about itself when the connection was opened.
+    server: Option<wire::ServerInfo>,
+
+    /// Secret presented to the server on connecting, if any.
+    token: Option<wire::Secret>,
 }
 
 impl KvsClient {
     /// Creates a new client connected to server at `addr`.
     pub fn new(addr: SocketAddr) -> Result<KvsClient> {
-        Ok(KvsClient { addr })
+        Ok(KvsClient {
+            addr,
+            ns: DEFAULT_NAMESPACE.to_owned(),
+            conn: None,
+            next_id: 0,
+            server: None,
+            token: None,
+        })
+    }
+
+    /// Makes subsequent key-value operations target namespace `name`.
+    pub fn set_namespace(&mut self, name: &str) {
+        self.ns = name.to_owned();
+    }
+
+    /// Presents `token` to servers requiring one before serving requests.
+    ///
+    /// The token applies to subsequent requests.  Whether it grants key-value or admin requests
+    /// depends on which of its tokens the server was started with.
+    pub fn set_token(&mut self, token: &str) {
+        self.token = Some(wire::Secret(token.to_owned()));
+        self.conn = None;
     }
 
     pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        self.send_recv(wire::Request::Get(key.to_string()))
+        self.send_recv(wire::Op::Get(self.ns.clone(), key.to_string()))
     }
 
+    /// Sets value of `key` to `val`.
+    ///
+    /// Values making the request larger than the server accepts are streamed instead.
     pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
-        self.send_recv(wire::Request::Set(key.to_string(), val.to_string()))
-            .map(|_| ())
+        if self.exceeds_request_limit(key, val)? {
+            return self.set_reader(key, &mut val.as_bytes());
+        }
+        self.send_recv(wire::Op::Set(
+            self.ns.clone(),
+            key.to_string(),
+            val.to_string(),
+        ))
+        .map(|_: Option<String>| ())
     }
 
     pub fn rm(&mut self, key: &str) -> Result<()> {
-        self.send_recv(wire::Request::Rm(key.to_string()))
-            .map(|_| ())
+        self.send_recv(wire::Op::Rm(self.ns.clone(), key.to_string()))
+            .map(|_: Option<String>| ())
+    }
+
+    /// Removes all keys stored in namespace `name`.
+    pub fn drop_namespace(&mut self, name: &str) -> Result<()> {
+        self.send_recv(wire::Op::DropNamespace(name.to_string()))
+            .map(|_: Option<String>| ())
+    }
+
+    /// Returns size and garbage figures of the store served by the server.
+    pub fn stats(&mut self) -> Result<Stats> {
+        self.send_recv(wire::Op::Stats)
+    }
+
+    /// Sets value of `key` to all bytes read from `value`.
+    ///
+    /// The value is streamed to the server and so does not need to fit in memory.
+    pub fn set_reader(&mut self, key: &str, value: &mut dyn Read) -> Result<()> {
+        let op = wire::Op::SetStream(self.ns.clone(), key.to_string());
+        self.exchange(op, |conn, id| {
+            let mut chunks = wire::ChunkWriter::new(BufWriter::new(conn.get_ref()));
+            io::copy(value, &mut chunks)?;
+            chunks.finish()?;
+            recv_reply(conn, id).map(|_: Option<String>| ())
+        })
+    }
+
+    /// Writes value of `key` to `wr` and returns whether `key` exists.
+    ///
+    /// The value is streamed from the server and so does not need to fit in memory.  Part of the
+    /// value may have been written when an error is returned.
+    pub fn get_writer(&mut self, key: &str, wr: &mut dyn Write) -> Result<bool> {
+        let op = wire::Op::GetStream(self.ns.clone(), key.to_string());
+        self.exchange(op, |conn, id| {
+            io::copy(&mut wire::ChunkReader::new(&mut *conn), wr)?;
+            recv_reply(conn, id)
+        })
     }
 
     /// Requests server to stop.
     ///
     /// When this function returns, the server has stopped all processing.
     pub fn shutdown(&mut self) -> Result<()> {
-        self.send_recv(wire::Request::Shutdown).map(|_| ())
+        let res = self
+            .send_recv(wire::Op::Shutdown)
+            .map(|_: Option<String>| ());
+        // The server closes all connections when stopping.
+        self.conn = None;
+        res
+    }
+
+    /// Returns what the server told about itself, connecting to it if needed.
+    pub fn server_info(&mut self) -> Result<&wire::ServerInfo> {
+        self.connection()?;
+        Ok(self
+            .server
+            .as_ref()
+            .expect("handshake done when connecting"))
     }
 
-    /// Sends request `req` to server and waits for reply.
-    fn send_recv(&self, req: wire::Request) -> Result<Option<String>> {
+    /// Starts sending requests without waiting for replies in between.
+    ///
+    /// This saves a round trip per request and lets the server handle requests concurrently,
+    /// which speeds up bulk loads.
+    pub fn pipeline(&mut self) -> Result<Pipeline<'_>> {
+        self.connection()?;
+        Ok(Pipeline {
+            first_id: self.next_id,
+            client: self,
+            pending: Vec::new(),
+            results: Vec::new(),
+            in_flight: 0,
+        })
+    }
+
+    /// Sends request for `op` to server and waits for reply.
+    fn send_recv<T: DeserializeOwned + Debug>(&mut self, op: wire::Op) -> Result<T> {
+        self.exchange(op, |conn, id| recv_reply(conn, id))
+    }
+
+    /// Sends request for `op` to server and calls `f` with the connection to send the rest of the
+    /// request on and receive the reply from, along with the request identifier.
+    fn exchange<T>(
+        &mut self,
+        op: wire::Op,
+        f: impl FnOnce(&mut BufReader<TcpStream>, u64) -> Result<T>,
+    ) -> Result<T> {
+        let req = self.request(op);
         debug!("C: sending {:?}", req);
-        // A socket is a vehicle for a single request and so must be created per-request.
-        let mut stream = TcpStream::connect(self.addr)?;
-        let ser_req = serde_json::to_string(&req)?;
-        writeln!(stream, "{}", ser_req)?;
-        let reply = serde_json::from_reader::<_, wire::Reply>(&mut stream)?;
-        debug!("C: received: {:?}", reply);
-        reply.0.map_err(KvError::Server)
+        let conn = self.connection()?;
+        let res = send_request(conn.get_mut(), &req).and_then(|_| f(conn, req.id));
+        if let Err(KvError::Io(_)) | Err(KvError::Serde(_)) | Err(KvError::Bincode(_)) = res {
+            // The connection may be out of sync with the server.
+            self.conn = None;
+        }
+        res
+    }
+
+    /// Returns whether a request setting `key` to `val` is larger than the server accepts,
+    /// connecting to it if it was never told.
+    fn exceeds_request_limit(&mut self, key: &str, val: &str) -> Result<bool> {
+        if self.server.is_none() {
+            self.connection()?;
+        }
+        let max = match self.server {
+            Some(ref info) => info.max_request_size,
+            None => unreachable!("handshake done when connecting"),
+        };
+        let len = ENCODING.set_request_len(self.next_id, &self.ns, key, val)?;
+        Ok(len > max.min(u64::from(wire::MAX_FRAME_LEN)))
+    }
+
+    fn request(&mut self, op: wire::Op) -> wire::Request {
+        let id = self.next_id;
+        self.next_id += 1;
+        wire::Request { id, op }
+    }
+
+    /// Returns connection to the server, opening a new one if the server closed the previous one.
+    fn connection(&mut self) -> Result<&mut BufReader<TcpStream>> {
+        let open = match self.conn {
+            Some(ref conn) => is_open(conn.get_ref()),
+            None => false,
+        };
+        if !open {
+            self.conn = None;
+            let stream = TcpStream::connect(self.addr)?;
+            stream.set_nodelay(true)?;
+            let mut conn = BufReader::new(stream);
+            self.server = Some(self.hello(&mut conn)?);
+            if let Some(token) = self.token.clone() {
+                self.authenticate(&mut conn, token)?;
+            }
+            self.conn = Some(conn);
+        }
+        Ok(self.conn.as_mut().expect("connection just opened"))
+    }
+
+    /// Checks that the server at the other end of `conn` speaks our protocol version.
+    fn hello(&mut self, conn: &mut BufReader<TcpStream>) -> Result<wire::ServerInfo> {
+        let req = self.request(wire::Op::Hello(wire::PROTOCOL_VERSION));
+        debug!("C: sending {:?}", req);
+        check_server(send_request(conn.get_mut(), &req).and_then(|_| recv_reply(conn, req.id)))
+    }
+
+    fn authenticate(&mut self, conn: &mut BufReader<TcpStream>, token: wire::Secret) -> Result<()> {
+        let req = self.request(wire::Op::Auth(token));
+        debug!("C: sending {:?}", req);
+        send_request(conn.get_mut(), &req)?;
+        recv_reply(conn, req.id).map(|_: Option<String>| ())
+    }
+}
+
+/// Returns server description from outcome `res` of the handshake if the server is compatible.
+pub(crate) fn check_server(res: Result<wire::ServerInfo>) -> Result<wire::ServerInfo> {
+    let info = res.map_err(|err| match err {
+        // Servers predating the handshake do not know the request.  Those predating the binary
+        // encoding can not even decode it and close the connection instead of replying.
+        KvError::Server(msg) => KvError::IncompatibleServer(msg),
+        KvError::Io(ref io_err)
+            if matches!(
+                io_err.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::BrokenPipe
+                    | ErrorKind::InvalidData
+            ) =>
+        {
+            KvError::IncompatibleServer(format!("handshake failed: {}", io_err))
+        }
+        KvError::Serde(_) | KvError::Bincode(_) => {
+            KvError::IncompatibleServer(format!("malformed reply to handshake: {}", err))
+        }
+        err => err,
+    })?;
+    if info.protocol_version != wire::PROTOCOL_VERSION {
+        return Err(KvError::IncompatibleServer(format!(
+            "server speaks protocol version {} but client speaks version {}",
+            info.protocol_version,
+            wire::PROTOCOL_VERSION
+        )));
+    }
+    Ok(info)
+}
+
+/// Returns whether `stream` can carry a new request, that is the server has not closed it and
+/// has nothing pending on it.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut buf = [0; 1];
+    let idle = match stream.peek(&mut buf) {
+        Err(ref err) => err.kind() == ErrorKind::WouldBlock,
+        Ok(_) => false,
+    };
+    stream.set_nonblocking(false).is_ok() && idle
+}
+
+fn send_request(wr: &mut impl Write, req: &wire::Request) -> Result<()> {
+    // Sent in one write so that Nagle's algorithm does not delay the end of the request.
+    let mut ser = Vec::new();
+    serialize_request(&mut ser, req)?;
+    wr.write_all(&ser)?;
+    Ok(())
+}
+
+fn serialize_request(buf: &mut Vec<u8>, req: &wire::Request) -> Result<()> {
+    ENCODING.write_request(buf, req)
+}
+
+/// Receives reply to request `id`.
+fn recv_reply<T: DeserializeOwned + Debug>(rd: &mut impl BufRead, id: u64) -> Result<T> {
+    let reply = read_reply::<T>(rd)?;
+    if reply.id != id {
+        return Err(out_of_sync(reply.id));
+    }
+    reply.result.map_err(KvError::from)
+}
+
+fn read_reply<T: DeserializeOwned + Debug>(rd: &mut impl BufRead) -> Result<wire::Reply<T>> {
+    let reply = ENCODING.read_reply::<T>(rd)?;
+    debug!("C: received: {:?}", reply);
+    Ok(reply)
+}
+
+/// Returns error for reply to unexpected request `id`.
+///
+/// This is an I/O error so that the connection is reopened.
+fn out_of_sync(id: u64) -> KvError {
+    KvError::Io(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("reply to unexpected request {}", id),
+    ))
+}
+
+/// Maximum number of requests sent by a `Pipeline` but not replied to yet.
+const PIPELINE_WINDOW: usize = 32;
+
+/// Size of requests a `Pipeline` buffers before sending them.
+const PIPELINE_BUFFER: usize = 16 * 1024;
+
+/// Requests sent without waiting for replies in between, created by `KvsClient::pipeline()`.
+///
+/// The server may handle requests concurrently and in any order, so a pipeline should not hold
+/// several requests for the same key.  Replies are read while requests are sent so that neither
+/// side blocks on full buffers.
+/// Dropping a pipeline before calling `finish()` closes the connection.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+
+    /// Identifier of the first request.
+    first_id: u64,
+
+    /// Serialized requests not sent yet.
+    pending: Vec<u8>,
+
+    /// Results of requests in the order they were queued, `None` until replied to.
+    results: Vec<Option<Result<Option<String>>>>,
+
+    /// Number of requests queued but not replied to.
+    in_flight: usize,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: &str) -> Result<()> {
+        let op = wire::Op::Get(self.client.ns.clone(), key.to_string());
+        self.push(op)
+    }
+
+    /// Queues setting `key` to `val`.
+    ///
+    /// Values making the request larger than the server accepts are streamed once all requests
+    /// queued before are replied to.
+    pub fn set(&mut self, key: &str, val: &str) -> Result<()> {
+        if self.client.exceeds_request_limit(key, val)? {
+            return self.set_streamed(key, val);
+        }
+        let op = wire::Op::Set(self.client.ns.clone(), key.to_string(), val.to_string());
+        self.push(op)
+    }
+
+    pub fn rm(&mut self, key: &str) -> Result<()> {
+        let op = wire::Op::Rm(self.client.ns.clone(), key.to_string());
+        self.push(op)
+    }
+
+    /// Waits for all replies and returns results of requests in the order they were queued.
+    ///
+    /// Results hold the value for `get()` and nothing otherwise.  An error is returned instead
+    /// if the connection fails.
+    pub fn finish(mut self) -> Result<Vec<Result<Option<String>>>> {
+        self.flush()?;
+        while self.in_flight > 0 {
+            self.recv()?;
+        }
+        Ok(std::mem::take(&mut self.results)
+            .into_iter()
+            .map(|res| res.expect("all replies received"))
+            .collect())
+    }
+
+    fn push(&mut self, op: wire::Op) -> Result<()> {
+        if self.in_flight >= PIPELINE_WINDOW {
+            self.flush()?;
+            self.recv()?;
+        }
+        let req = self.client.request(op);
+        debug!("C: queuing {:?}", req);
+        serialize_request(&mut self.pending, &req)?;
+        self.results.push(None);
+        self.in_flight += 1;
+        if self.pending.len() >= PIPELINE_BUFFER {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Streams `val` to set `key`, recording the result as that of a queued request.
+    fn set_streamed(&mut self, key: &str, val: &str) -> Result<()> {
+        // The server reads nothing else from the connection while receiving the value, so
+        // replies it is still to send could fill buffers on both sides.
+        self.flush()?;
+        while self.in_flight > 0 {
+            self.recv()?;
+        }
+        let res = self.client.set_reader(key, &mut val.as_bytes());
+        if self.client.conn.is_none() {
+            // The connection failed, replies to earlier requests being lost with it.
+            return res;
+        }
+        // The request got the identifier following those queued before.
+        self.results.push(Some(res.map(|()| None)));
+        Ok(())
+    }
+
+    fn conn(&mut self) -> &mut BufReader<TcpStream> {
+        self.client
+            .conn
+            .as_mut()
+            .expect("connection open while pipeline alive")
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending);
+        self.conn().get_mut().write_all(&pending)?;
+        Ok(())
+    }
+
+    /// Waits for a reply.
+    fn recv(&mut self) -> Result<()> {
+        let reply = read_reply::<Option<String>>(self.conn())?;
+        let slot = reply
+            .id
+            .checked_sub(self.first_id)
+            .and_then(|i| self.results.get_mut(i as usize))
+            .filter(|slot| slot.is_none())
+            .ok_or_else(|| out_of_sync(reply.id))?;
+        *slot = Some(reply.result.map_err(KvError::from));
+        self.in_flight -= 1;
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline<'_> {
+    fn drop(&mut self) {
+        if self.in_flight > 0 {
+            // Replies left unread would be mistaken for replies to later requests.
+            self.client.conn = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers the handshake of a client as a server speaking `protocol_version` would.
+    fn fake_server(listener: TcpListener, protocol_version: u32) {
+        let (stream, _) = listener.accept().unwrap();
+        let mut rd = BufReader::new(stream.try_clone().unwrap());
+        let encoding = wire::Encoding::detect(&mut rd).unwrap().unwrap();
+        let req = encoding.read_request(&mut rd).unwrap().unwrap();
+        assert_eq!(req.op, wire::Op::Hello(wire::PROTOCOL_VERSION));
+        let reply = wire::Reply {
+            id: req.id,
+            result: Ok(wire::ServerInfo {
+                protocol_version,
+                server_version: "0.0.0".to_owned(),
+                engine: None,
+                features: Vec::new(),
+                max_request_size: 1024,
+            }),
+        };
+        let mut buf = Vec::new();
+        encoding.write_reply(&mut buf, &reply).unwrap();
+        (&stream).write_all(&buf).unwrap();
+    }
+
+    /// Closes the connection of a client on receiving its first request, as servers predating
+    /// the binary encoding do, or replies with garbage if `garbage` is set.
+    fn old_server(listener: TcpListener, garbage: bool) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).unwrap();
+        if garbage {
+            stream.write_all(b"garbage\n").unwrap();
+        }
+    }
+
+    #[test]
+    fn server_predating_handshake() {
+        for &garbage in &[false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = thread::spawn(move || old_server(listener, garbage));
+            let mut client = KvsClient::new(addr).unwrap();
+            assert!(matches!(
+                client.get("key"),
+                Err(KvError::IncompatibleServer(_))
+            ));
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn incompatible_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || fake_server(listener, wire::PROTOCOL_VERSION + 1));
+        let mut client = KvsClient::new(addr).unwrap();
+        assert!(matches!(
+            client.get("key"),
+            Err(KvError::IncompatibleServer(_))
+        ));
+        server.join().unwrap();
     }
 }
@@ -1,12 +1,251 @@
+use std::io::{Read, Write};
 use std::path::Path;
+use std::sync::Arc;
 
-use crate::error::Result;
+use crate::error::{KvError, Result};
+use crate::registry::{EngineRegistry, DEFAULT_ENGINE};
+use crate::stats::Stats;
+
+/// Name of the namespace targeted by engines returned by `KvsEngine::open()`.
+pub const DEFAULT_NAMESPACE: &str = "";
 
 // TODO: Most methods take String arguments because tests use str::to_owned().  There
 // must be a better way.
 pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// Version of the on-disk format written by this engine or 0 if the engine does not version
+    /// its data itself.
+    const FORMAT_VERSION: u32 = 0;
+
     fn open<P: AsRef<Path>>(path: P) -> Result<Self>;
     fn set(&self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Returns a handle on the same store whose operations target namespace `name`.
+    ///
+    /// Each namespace has its own keyspace.  Namespaces are created on first use.  Engines not
+    /// overriding this method only support the default namespace.
+    fn namespace(&self, name: &str) -> Result<Self> {
+        if name != DEFAULT_NAMESPACE {
+            return Err(KvError::Other(
+                "engine does not support namespaces".to_owned(),
+            ));
+        }
+        Ok(self.clone())
+    }
+
+    /// Removes all keys stored in namespace `name`.
+    ///
+    /// The default implementation removes the keys one by one.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        let ns = self.namespace(name)?;
+        for key in ns.keys()? {
+            ns.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns names of all namespaces that may hold keys, in no particular order.
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(vec![DEFAULT_NAMESPACE.to_owned()])
+    }
+
+    /// Returns all keys of the namespace targeted by this handle, in no particular order.
+    fn keys(&self) -> Result<Vec<String>> {
+        Err(KvError::Other(
+            "engine does not support listing keys".to_owned(),
+        ))
+    }
+
+    /// Returns size and garbage figures for the whole store, all namespaces included.
+    ///
+    /// The default implementation only counts keys and reports no on-disk data.
+    fn stats(&self) -> Result<Stats> {
+        let mut live_keys = 0;
+        for name in self.namespaces()? {
+            live_keys += self.namespace(&name)?.keys()?.len() as u64;
+        }
+        Ok(Stats {
+            live_keys,
+            total_bytes: 0,
+            dead_bytes: None,
+            dead_entries: None,
+            last_compaction: None,
+            compactions: None,
+        })
+    }
+
+    /// Makes all writes acknowledged so far durable.
+    ///
+    /// Engines making each write durable before acknowledging it need not override this method.
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Sets value of `key` to all bytes read from `value`.
+    ///
+    /// Engines overriding this method should not require the value to fit in memory nor to be
+    /// valid UTF-8.
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        let mut buf = String::new();
+        value.read_to_string(&mut buf)?;
+        self.set(key, buf)
+    }
+
+    /// Fails if setting `key` would be refused whatever the value, for instance because the key
+    /// is too long or the store is full, and otherwise returns the size of the largest value
+    /// `key` may be set to, if bounded.
+    ///
+    /// This lets callers receiving a value refuse it before it is received whole.  Engines
+    /// enforcing no limits need not override this method.
+    fn check_set(&self, _key: &str) -> Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Writes value of `key` to `wr` and returns whether `key` exists.
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        match self.get(key)? {
+            Some(val) => {
+                wr.write_all(val.as_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Object-safe counterpart of `KvsEngine` backing `BoxedKvsEngine`.
+trait DynKvsEngine: Send + Sync {
+    fn set(&self, key: String, value: String) -> Result<()>;
+    fn get(&self, key: String) -> Result<Option<String>>;
+    fn remove(&self, key: String) -> Result<()>;
+    fn namespace(&self, name: &str) -> Result<BoxedKvsEngine>;
+    fn drop_namespace(&self, name: &str) -> Result<()>;
+    fn namespaces(&self) -> Result<Vec<String>>;
+    fn keys(&self) -> Result<Vec<String>>;
+    fn stats(&self) -> Result<Stats>;
+    fn flush(&self) -> Result<()>;
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()>;
+    fn check_set(&self, key: &str) -> Result<Option<u64>>;
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool>;
+}
+
+impl<E: KvsEngine> DynKvsEngine for E {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn namespace(&self, name: &str) -> Result<BoxedKvsEngine> {
+        KvsEngine::namespace(self, name).map(BoxedKvsEngine::new)
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        KvsEngine::drop_namespace(self, name)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        KvsEngine::namespaces(self)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        KvsEngine::keys(self)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        KvsEngine::stats(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvsEngine::flush(self)
+    }
+
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        KvsEngine::set_reader(self, key, value)
+    }
+
+    fn check_set(&self, key: &str) -> Result<Option<u64>> {
+        KvsEngine::check_set(self, key)
+    }
+
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        KvsEngine::get_writer(self, key, wr)
+    }
+}
+
+/// Engine whose concrete type is chosen at runtime.
+///
+/// This allows selecting engines by name through an `EngineRegistry` while still handing a
+/// `KvsEngine` to generic code such as `KvsServer`.
+#[derive(Clone)]
+pub struct BoxedKvsEngine(Arc<dyn DynKvsEngine>);
+
+impl BoxedKvsEngine {
+    /// Wraps `engine`.
+    pub fn new<E: KvsEngine>(engine: E) -> BoxedKvsEngine {
+        BoxedKvsEngine(Arc::new(engine))
+    }
+}
+
+impl KvsEngine for BoxedKvsEngine {
+    /// Opens the default engine at `path`.
+    fn open<P: AsRef<Path>>(path: P) -> Result<BoxedKvsEngine> {
+        let registry = EngineRegistry::default();
+        registry.open(&registry.new_manifest(DEFAULT_ENGINE)?, path.as_ref())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+
+    fn namespace(&self, name: &str) -> Result<BoxedKvsEngine> {
+        self.0.namespace(name)
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.0.drop_namespace(name)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.0.namespaces()
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        self.0.keys()
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        self.0.stats()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()
+    }
+
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        self.0.set_reader(key, value)
+    }
+
+    fn check_set(&self, key: &str) -> Result<Option<u64>> {
+        self.0.check_set(key)
+    }
+
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        self.0.get_writer(key, wr)
+    }
 }
@@ -1,3 +1,4 @@
+use crate::store_be::FORMAT_VERSION;
 use std::convert::From;
 use std::fmt;
 use std::io;
@@ -8,11 +9,22 @@ use std::net;
 pub enum KvError {
     Io(io::Error),
     Serde(serde_json::Error),
+    Bincode(bincode::Error),
     Sled(sled::Error),
     KeyNotFound(String),
     BadEngine,
     Server(String),
     UnknownEngine,
+    UnsupportedFormat(u32),
+    CorruptLog(u64),
+    InvalidUtf8(String),
+    KeyTooLong(usize),
+    ValueTooLarge(u64),
+    QuotaExceeded(u64),
+    RequestTooLarge(u64),
+    IncompatibleServer(String),
+    Unauthenticated,
+    PermissionDenied,
     Other(String),
 }
 
@@ -22,6 +34,12 @@ impl From<serde_json::Error> for KvError {
     }
 }
 
+impl From<bincode::Error> for KvError {
+    fn from(err: bincode::Error) -> KvError {
+        KvError::Bincode(err)
+    }
+}
+
 impl From<sled::Error> for KvError {
     fn from(err: sled::Error) -> KvError {
         KvError::Sled(err)
@@ -57,11 +75,39 @@ impl fmt::Display for KvError {
         match *self {
             KvError::Io(_) => write!(f, "I/O error"),
             KvError::Serde(_) => write!(f, "Serialization error"),
+            KvError::Bincode(_) => write!(f, "Binary serialization error"),
             KvError::Sled(_) => write!(f, "Sled error"),
             KvError::KeyNotFound(ref key) => write!(f, "Key not found: {}", key),
             KvError::BadEngine => write!(f, "Selected engine does not support data stored on disk"),
             KvError::Server(ref msg) => write!(f, "Server error: {}", msg),
             KvError::UnknownEngine => write!(f, "Unknown engine"),
+            KvError::UnsupportedFormat(version) if version < FORMAT_VERSION => write!(
+                f,
+                "Unsupported on-disk format version {} (migrate it with `kvs upgrade`)",
+                version
+            ),
+            KvError::UnsupportedFormat(version) => write!(
+                f,
+                "Unsupported on-disk format version {} written by a newer kvs",
+                version
+            ),
+            KvError::CorruptLog(off) => write!(
+                f,
+                "Corrupt record at offset {} of log (check and repair the store with `kvs fsck`)",
+                off
+            ),
+            KvError::InvalidUtf8(ref key) => {
+                write!(f, "Value of key {} is not valid UTF-8", key)
+            }
+            KvError::KeyTooLong(max) => write!(f, "Key longer than {} bytes", max),
+            KvError::ValueTooLarge(max) => write!(f, "Value larger than {} bytes", max),
+            KvError::QuotaExceeded(max) => {
+                write!(f, "Store reached its maximum size of {} bytes", max)
+            }
+            KvError::RequestTooLarge(max) => write!(f, "Request larger than {} bytes", max),
+            KvError::IncompatibleServer(ref why) => write!(f, "Incompatible server: {}", why),
+            KvError::Unauthenticated => write!(f, "Server requires a valid token"),
+            KvError::PermissionDenied => write!(f, "Request requires the admin token"),
             KvError::Other(ref err) => write!(f, "{}", err),
         }
     }
@@ -72,11 +118,22 @@ impl std::error::Error for KvError {
         match *self {
             KvError::Io(ref err) => Some(err),
             KvError::Serde(ref err) => Some(err),
+            KvError::Bincode(ref err) => Some(err),
             KvError::Sled(ref err) => Some(err),
             KvError::KeyNotFound(_) => None,
             KvError::BadEngine => None,
             KvError::Server(_) => None,
             KvError::UnknownEngine => None,
+            KvError::UnsupportedFormat(_) => None,
+            KvError::CorruptLog(_) => None,
+            KvError::InvalidUtf8(_) => None,
+            KvError::KeyTooLong(_) => None,
+            KvError::ValueTooLarge(_) => None,
+            KvError::QuotaExceeded(_) => None,
+            KvError::RequestTooLarge(_) => None,
+            KvError::IncompatibleServer(_) => None,
+            KvError::Unauthenticated => None,
+            KvError::PermissionDenied => None,
             KvError::Other(_) => None,
         }
     }
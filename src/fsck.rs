@@ -0,0 +1,543 @@
+//! Offline integrity checker for `KvStore` logs.
+
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, prelude::*, BufReader, ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+use crate::error::*;
+use crate::store_be::{self, Header, LogWriter, Tag, FORMAT_VERSION};
+use crate::vfs::OsFs;
+use crate::KvStore;
+
+/// Outcome of checking a `KvStore` log.
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    /// Format version found in file header.
+    pub version: u32,
+
+    /// Number of well-formed records.
+    pub records: u64,
+
+    /// Number of keys that can be read after replaying well-formed records.
+    pub live_keys: u64,
+
+    /// Number of keys written at some point but removed since.
+    pub dead_keys: u64,
+
+    /// Number of well-formed records superseded by later ones.
+    pub dead_entries: u64,
+
+    /// Offsets of records whose value extends past the end of the log.
+    pub dangling_offsets: Vec<u64>,
+
+    /// Identifiers of blobs referenced by records but missing from the blob directory.
+    pub missing_blobs: Vec<u64>,
+
+    /// Namespaces and keys whose latest value is in a missing blob.  They are left out of the
+    /// repaired log rather than brought back to an older value.
+    pub lost_keys: Vec<(String, String)>,
+
+    /// Byte ranges `[start, end)` that could not be decoded.
+    pub corrupt_regions: Vec<(u64, u64)>,
+
+    /// Reports of each shard of a sharded store, whose counts are summed above.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shards: Vec<FsckReport>,
+}
+
+impl FsckReport {
+    /// Returns whether some records could not be recovered.
+    pub fn is_damaged(&self) -> bool {
+        !self.dangling_offsets.is_empty()
+            || !self.missing_blobs.is_empty()
+            || !self.corrupt_regions.is_empty()
+            || self.shards.iter().any(FsckReport::is_damaged)
+    }
+
+    /// Adds counts of `shard` to this report and records it.
+    fn add_shard(&mut self, shard: FsckReport) {
+        self.version = if self.shards.is_empty() {
+            shard.version
+        } else {
+            self.version.min(shard.version)
+        };
+        self.records += shard.records;
+        self.live_keys += shard.live_keys;
+        self.dead_keys += shard.dead_keys;
+        self.dead_entries += shard.dead_entries;
+        self.shards.push(shard);
+    }
+
+    /// Writes one line per problem found, each starting with `prefix`.
+    fn fmt_damage(&self, f: &mut fmt::Formatter<'_>, prefix: &str) -> fmt::Result {
+        for off in &self.dangling_offsets {
+            writeln!(f, "{}dangling record at offset {}", prefix, off)?;
+        }
+        for id in &self.missing_blobs {
+            writeln!(f, "{}missing blob {}", prefix, id)?;
+        }
+        for (ns, key) in &self.lost_keys {
+            writeln!(f, "{}lost key {:?} in namespace {:?}", prefix, key, ns)?;
+        }
+        for (start, end) in &self.corrupt_regions {
+            writeln!(f, "{}corrupt region at offsets {}..{}", prefix, start, end)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FsckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "format version: {}", self.version)?;
+        writeln!(f, "records: {}", self.records)?;
+        writeln!(f, "live keys: {}", self.live_keys)?;
+        writeln!(f, "dead keys: {}", self.dead_keys)?;
+        writeln!(f, "dead entries: {}", self.dead_entries)?;
+        self.fmt_damage(f, "")?;
+        for (i, shard) in self.shards.iter().enumerate() {
+            shard.fmt_damage(f, &format!("shard {}: ", i))?;
+        }
+        write!(
+            f,
+            "status: {}",
+            if self.is_damaged() {
+                "damaged"
+            } else {
+                "clean"
+            }
+        )
+    }
+}
+
+/// Location of the value of a live key.
+enum ValuePos {
+    /// Offset and size of value serialized in log, trailing newline included.
+    Log(u64, usize),
+
+    /// Identifier of blob holding value.
+    Blob(u64),
+
+    /// Value in a missing blob.
+    Lost,
+}
+
+impl KvStore {
+    /// Checks every record of the store at `path` without opening it.
+    ///
+    /// If `repair_to` is set, a log holding all live keys recovered from well-formed records is
+    /// written there.  For sharded stores, `repair_to` is a directory receiving the repaired log of
+    /// each shard under the name of the shard directory.  The store itself is never modified:
+    /// `repair_to` must lie outside of it.
+    pub fn fsck<P: AsRef<Path>>(path: P, repair_to: Option<&Path>) -> Result<FsckReport> {
+        let path = path.as_ref();
+        if let Some(dst) = repair_to {
+            if resolve(dst)?.starts_with(path.canonicalize()?) {
+                return Err(KvError::Other(format!(
+                    "repaired log {} must not be written inside the checked store",
+                    dst.display()
+                )));
+            }
+        }
+        let count = match store_be::read_shard_count(&OsFs, path)? {
+            Some(count) => count,
+            None => return fsck_log(path, repair_to),
+        };
+        if let Some(dir) = repair_to {
+            fs::create_dir_all(dir)?;
+        }
+        let mut report = FsckReport::default();
+        for i in 0..count {
+            let shard_path = store_be::shard_path(path, i);
+            let shard_repair_to = repair_to.map(|dir| dir.join(shard_path.file_name().unwrap()));
+            report.add_shard(fsck_log(&shard_path, shard_repair_to.as_deref())?);
+        }
+        Ok(report)
+    }
+}
+
+/// Checks the log of the unsharded store at `path`.
+fn fsck_log(path: &Path, repair_to: Option<&Path>) -> Result<FsckReport> {
+    let filename = path.join("kv.db");
+    let blob_dir = path.join("blobs");
+    let file = File::open(&filename)?;
+    let file_len = file.metadata()?.len();
+    let mut rd = BufReader::new(&file);
+    let mut report = FsckReport::default();
+
+    let mut corrupt_start = None;
+    report.version = match store_be::read_format_version(&mut rd) {
+        Ok(version) if (1..=FORMAT_VERSION).contains(&version) => version,
+        Ok(version) => return Err(KvError::UnsupportedFormat(version)),
+        Err(KvError::Serde(_)) => {
+            corrupt_start = Some(0);
+            FORMAT_VERSION
+        }
+        Err(err) => return Err(err),
+    };
+
+    let mut namespaces: HashMap<String, HashMap<String, ValuePos>> = HashMap::new();
+    let mut seen_keys = HashSet::new();
+    let mut line = Vec::new();
+    // Set while skipping what follows the header of a dangling record.
+    let mut in_dangling = false;
+    loop {
+        let rec_off = rd.stream_position()?;
+        line.clear();
+        if rd.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        let hdr = match parse_header(&line) {
+            Some(hdr) => hdr,
+            None => {
+                // Skip lines until the next well-formed header.
+                if !in_dangling {
+                    corrupt_start.get_or_insert(rec_off);
+                }
+                continue;
+            }
+        };
+        in_dangling = false;
+        if let Some(start) = corrupt_start.take() {
+            report.corrupt_regions.push((start, rec_off));
+        }
+
+        match hdr.tag {
+            Tag::Set => {
+                let value_off = rd.stream_position()?;
+                // Check the size before allocating as a corrupt one may be huge.  Records
+                // following a dangling one are still scanned so that they can be repaired.
+                let in_log = value_off
+                    .checked_add(hdr.value_size as u64)
+                    .is_some_and(|value_end| value_end <= file_len);
+                if !in_log {
+                    report.dangling_offsets.push(rec_off);
+                    in_dangling = true;
+                    continue;
+                }
+                let mut ser_val = vec![0; hdr.value_size];
+                rd.read_exact(&mut ser_val)?;
+                if decode_value(&ser_val).is_none() {
+                    report
+                        .corrupt_regions
+                        .push((rec_off, value_off + hdr.value_size as u64));
+                    continue;
+                }
+
+                report.records += 1;
+                let pos = ValuePos::Log(value_off, hdr.value_size);
+                insert(&mut namespaces, &mut seen_keys, &mut report, hdr, pos);
+            }
+            Tag::SetBlob(id) => {
+                report.records += 1;
+                let pos = if blob_dir.join(id.to_string()).is_file() {
+                    ValuePos::Blob(id)
+                } else {
+                    report.missing_blobs.push(id);
+                    ValuePos::Lost
+                };
+                insert(&mut namespaces, &mut seen_keys, &mut report, hdr, pos);
+            }
+            Tag::Rm => {
+                report.records += 1;
+                let removed = namespaces
+                    .get_mut(&*hdr.ns)
+                    .and_then(|index| index.remove(&*hdr.key));
+                if removed.is_some() {
+                    report.dead_entries += 1;
+                }
+            }
+            Tag::Drop => {
+                report.records += 1;
+                if let Some(index) = namespaces.remove(&*hdr.ns) {
+                    report.dead_entries += index.len() as u64;
+                }
+            }
+        }
+    }
+    if let Some(start) = corrupt_start {
+        report.corrupt_regions.push((start, file_len));
+    }
+
+    for (ns, index) in &namespaces {
+        for (key, pos) in index {
+            if let ValuePos::Lost = pos {
+                report.lost_keys.push((ns.clone(), key.clone()));
+            }
+        }
+    }
+    report.lost_keys.sort();
+    let lost = report.lost_keys.len() as u64;
+    report.live_keys = namespaces
+        .values()
+        .map(|index| index.len() as u64)
+        .sum::<u64>()
+        - lost;
+    report.dead_keys = seen_keys.len() as u64 - report.live_keys - lost;
+
+    if let Some(repaired) = repair_to {
+        write_repaired_log(&file, &namespaces, repaired)?;
+    }
+
+    Ok(report)
+}
+
+fn insert(
+    namespaces: &mut HashMap<String, HashMap<String, ValuePos>>,
+    seen_keys: &mut HashSet<(String, String)>,
+    report: &mut FsckReport,
+    hdr: Header,
+    pos: ValuePos,
+) {
+    seen_keys.insert((hdr.ns.to_string(), hdr.key.to_string()));
+    let index = namespaces.entry(hdr.ns.into_owned()).or_default();
+    if index.insert(hdr.key.into_owned(), pos).is_some() {
+        report.dead_entries += 1;
+    }
+}
+
+/// Decodes record header from `line`, returning `None` if it is not well-formed.
+fn parse_header(line: &[u8]) -> Option<Header<'_>> {
+    if !line.ends_with(b"\n") {
+        return None;
+    }
+    let hdr = serde_json::from_slice::<Header>(line).ok()?;
+    match hdr.tag {
+        Tag::Set if hdr.value_size == 0 => None,
+        Tag::Rm | Tag::Drop | Tag::SetBlob(_) if hdr.value_size != 0 => None,
+        _ => Some(hdr),
+    }
+}
+
+/// Decodes serialized value, trailing newline included, returning `None` if it is not
+/// well-formed.
+fn decode_value(ser_val: &[u8]) -> Option<String> {
+    match ser_val.split_last() {
+        Some((b'\n', ser)) => serde_json::from_slice(ser).ok(),
+        _ => None,
+    }
+}
+
+/// Writes a log holding the values of `namespaces` read from `src` to `dst`, atomically
+/// replacing any previous file.
+fn write_repaired_log(
+    src: &File,
+    namespaces: &HashMap<String, HashMap<String, ValuePos>>,
+    dst: &Path,
+) -> Result<()> {
+    let mut rd = BufReader::new(src);
+    let dst_dir = match dst.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let tmp_file = NamedTempFile::new_in(dst_dir)?;
+    let mut wr = LogWriter::new(tmp_file.as_file(), 0);
+    store_be::write_file_header(&mut wr)?;
+    for (ns, index) in namespaces {
+        for (key, pos) in index {
+            match *pos {
+                ValuePos::Log(off, size) => {
+                    rd.seek(SeekFrom::Start(off))?;
+                    let mut ser_val = vec![0; size];
+                    rd.read_exact(&mut ser_val)?;
+                    let val = decode_value(&ser_val).expect("value checked while scanning log");
+                    store_be::append_to_open_log(&mut wr, Tag::Set, ns, key, Some(&val))?;
+                }
+                // Blobs are shared with the checked store.
+                ValuePos::Blob(id) => {
+                    store_be::append_to_open_log(&mut wr, Tag::SetBlob(id), ns, key, None)?;
+                }
+                ValuePos::Lost => (),
+            }
+        }
+    }
+    wr.flush()?;
+    drop(wr);
+    tmp_file.as_file().sync_all()?;
+    tmp_file.persist(dst).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Returns the absolute form of `path` with symbolic links resolved, even if it does not exist
+/// yet.
+fn resolve(path: &Path) -> io::Result<PathBuf> {
+    match path.canonicalize() {
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let name = match path.file_name() {
+                Some(name) => name,
+                None => return Err(err),
+            };
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => resolve(dir)?,
+                _ => std::env::current_dir()?,
+            };
+            Ok(dir.join(name))
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStoreOptions, KvsEngine};
+    use std::fs::{self, OpenOptions};
+
+    #[test]
+    fn clean_store() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "v1".to_string())?;
+            kvs.set("k1".to_string(), "v2".to_string())?;
+            kvs.set("k2".to_string(), "v3".to_string())?;
+            kvs.remove("k2".to_string())?;
+        }
+        let report = KvStore::fsck(&tmpdir, None)?;
+        assert!(!report.is_damaged());
+        assert_eq!(report.records, 4);
+        assert_eq!(report.live_keys, 1);
+        assert_eq!(report.dead_keys, 1);
+        assert_eq!(report.dead_entries, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_store() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let filename = tmpdir.path().join("kv.db");
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "v1".to_string())?;
+            kvs.set("k2".to_string(), "v2".to_string())?;
+        }
+        let mut file = OpenOptions::new().append(true).open(&filename)?;
+        file.write_all(b"garbage\n{\"tag\":\"Set\",\"key\":\"k3\",\"value_size\":100}\n\"v\"\n")?;
+        drop(file);
+
+        let outdir = tempfile::tempdir()?;
+        let repaired = outdir.path().join("repaired");
+        let report = KvStore::fsck(&tmpdir, Some(&repaired))?;
+        assert!(report.is_damaged());
+        assert_eq!(report.live_keys, 2);
+        assert_eq!(report.corrupt_regions.len(), 1);
+        assert_eq!(report.dangling_offsets.len(), 1);
+
+        fs::rename(&repaired, &filename)?;
+        assert!(!KvStore::fsck(&tmpdir, None)?.is_damaged());
+        let kvs = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs.get("k1".to_string())?, Some("v1".to_string()));
+        assert_eq!(kvs.get("k2".to_string())?, Some("v2".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn repair_inside_store() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let filename = tmpdir.path().join("kv.db");
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "v1".to_string())?;
+        }
+        let log = fs::read(&filename)?;
+
+        for dst in &[filename.clone(), tmpdir.path().join("blobs/repaired")] {
+            assert!(matches!(
+                KvStore::fsck(&tmpdir, Some(dst)),
+                Err(KvError::Other(_))
+            ));
+        }
+        assert_eq!(fs::read(&filename)?, log);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_blob() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let large = "x".repeat(store_be::BLOB_THRESHOLD);
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "old".to_string())?;
+            kvs.set("k1".to_string(), large)?;
+            kvs.set("k2".to_string(), "v2".to_string())?;
+        }
+        for entry in fs::read_dir(tmpdir.path().join("blobs"))? {
+            fs::remove_file(entry?.path())?;
+        }
+
+        let outdir = tempfile::tempdir()?;
+        let repaired = outdir.path().join("repaired");
+        let report = KvStore::fsck(&tmpdir, Some(&repaired))?;
+        assert!(report.is_damaged());
+        assert_eq!(report.missing_blobs.len(), 1);
+        assert_eq!(report.lost_keys, vec![("".to_string(), "k1".to_string())]);
+        assert_eq!(report.live_keys, 1);
+        assert_eq!(report.dead_keys, 0);
+
+        // The stale value of the lost key is not brought back.
+        fs::rename(&repaired, tmpdir.path().join("kv.db"))?;
+        let kvs = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs.get("k1".to_string())?, None);
+        assert_eq!(kvs.get("k2".to_string())?, Some("v2".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn huge_value_size() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let filename = tmpdir.path().join("kv.db");
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "v1".to_string())?;
+        }
+        let mut file = OpenOptions::new().append(true).open(&filename)?;
+        file.write_all(b"{\"tag\":\"Set\",\"key\":\"x\",\"value_size\":18446744073709551000}\n")?;
+        file.write_all(b"\"v\"\n{\"tag\":\"Set\",\"key\":\"k2\",\"value_size\":5}\n\"v2\"\n")?;
+        drop(file);
+
+        let report = KvStore::fsck(&tmpdir, None)?;
+        assert_eq!(report.dangling_offsets.len(), 1);
+        assert!(report.corrupt_regions.is_empty());
+        assert_eq!(report.live_keys, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn sharded_store() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let options = KvStoreOptions {
+            shards: 2,
+            ..KvStoreOptions::default()
+        };
+        {
+            let kvs = KvStore::open_with(&tmpdir, options.clone())?;
+            for i in 0..10 {
+                kvs.set(format!("k{}", i), "v".to_string())?;
+            }
+        }
+        let report = KvStore::fsck(&tmpdir, None)?;
+        assert!(!report.is_damaged());
+        assert_eq!(report.shards.len(), 2);
+        assert_eq!(report.live_keys, 10);
+
+        let shard_log = store_be::shard_path(tmpdir.path(), 1).join("kv.db");
+        let mut file = OpenOptions::new().append(true).open(&shard_log)?;
+        file.write_all(b"garbage\n")?;
+        drop(file);
+        let outdir = tempfile::tempdir()?;
+        let repaired = outdir.path().join("repaired");
+        let report = KvStore::fsck(&tmpdir, Some(&repaired))?;
+        assert!(report.is_damaged());
+        assert_eq!(report.shards[1].corrupt_regions.len(), 1);
+        assert_eq!(report.live_keys, 10);
+
+        fs::rename(repaired.join("shard-1"), &shard_log)?;
+        assert!(!KvStore::fsck(&tmpdir, None)?.is_damaged());
+        assert_eq!(KvStore::open_with(&tmpdir, options)?.stats()?.live_keys, 10);
+        Ok(())
+    }
+}
@@ -1,89 +1,59 @@
-use std::fs;
-use std::path::{Path, PathBuf};
-
 mod wire;
+pub use wire::{ServerInfo, PROTOCOL_VERSION};
 
 mod error;
 pub use error::KvError;
 pub use error::Result;
 
+#[cfg(test)]
+mod sim_fs;
+mod vfs;
+
+mod limits;
+pub use limits::Limits;
+
 mod store_be;
-pub use store_be::KvStore;
+pub use store_be::{KvStore, KvStoreOptions};
+
+mod fsck;
+pub use fsck::FsckReport;
 
 mod sled_be;
-pub use sled_be::SledKvsEngine;
+pub use sled_be::{MergeOperator, SledKvsEngine, SledOptions, SledWatch, WatchEvent};
+
+mod mem_be;
+pub use mem_be::MemKvsEngine;
 
 mod engine;
-pub use engine::KvsEngine;
+pub use engine::{BoxedKvsEngine, KvsEngine, DEFAULT_NAMESPACE};
 
-mod client;
-pub use client::KvsClient;
-mod server;
-pub use server::KvsServer;
+mod registry;
+pub use registry::{EngineOpener, EngineRegistry, DEFAULT_ENGINE};
 
-pub mod thread_pool;
-pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+mod manifest;
+pub use manifest::{find_existing_store, prepare_engine_creation, Manifest};
 
-/// All supported backends.
-#[derive(PartialEq)]
-pub enum EngineKind {
-    Kvs,
-    Sled,
-}
+mod migrate;
+pub use migrate::migrate;
 
-/// Computes backend to use and directory where data should be stored.
-///
-/// This function implements the convoluted logic described in the spec.
-///
-/// TODO: This function is ugly because it pushes responsibility of engine creation to its callers.
-/// A better interface would be a function that creates the engine and passes it to a closure
-/// argument.  I tried doing that and miserably failed because the closure must be generic (take a
-/// impl KvsEngine).
-pub fn prepare_engine_creation(name_opt: Option<&str>) -> Result<(EngineKind, PathBuf)> {
-    // Look for existing database if any in current directory.
-    let mut on_disk_data: Option<EngineKind> = None;
-    for entry in fs::read_dir(".")? {
-        let found = match entry?.file_name().to_str() {
-            Some("pna-kvs") => Some(EngineKind::Kvs),
-            Some("pna-sled") => Some(EngineKind::Sled),
-            _ => None,
-        };
-        if found.is_some() {
-            on_disk_data = found;
-            break;
-        }
-    }
+mod stats;
+pub use stats::Stats;
 
-    // Compute engine to use.
-    let selected_kind = match name_opt {
-        Some(name) => {
-            let desired_kind = match name {
-                "kvs" => EngineKind::Kvs,
-                "sled" => EngineKind::Sled,
-                _ => return Err(KvError::BadEngine),
-            };
-            if let Some(kind) = on_disk_data {
-                if desired_kind != kind {
-                    return Err(KvError::BadEngine);
-                }
-            };
-            desired_kind
-        }
-        None => {
-            if let Some(kind) = on_disk_data {
-                kind
-            } else {
-                EngineKind::Kvs
-            }
-        }
-    };
+mod client;
+pub use client::{KvsClient, Pipeline};
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+mod server;
+pub use server::{KvsServer, KvsServerOptions, ShutdownHandle};
+#[cfg(feature = "async")]
+mod async_server;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
 
-    // Make sub-directory that will hold data in case it does not exist already.
-    let dir = Path::new(match selected_kind {
-        EngineKind::Kvs => "pna-kvs",
-        EngineKind::Sled => "pna-sled",
-    });
-    fs::create_dir_all(&dir)?;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-    Ok((selected_kind, dir.to_path_buf()))
-}
+pub mod thread_pool;
+pub use thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
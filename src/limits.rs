@@ -0,0 +1,47 @@
+use crate::error::*;
+
+/// Bounds on what clients can write to a store.  `None` means unlimited.
+///
+/// Reads and removals are never refused so that a full store can still be emptied.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// Maximum length of keys in bytes.
+    pub max_key_len: Option<usize>,
+
+    /// Maximum size of values in bytes.
+    pub max_value_size: Option<u64>,
+
+    /// Maximum size of on-disk data in bytes, as reported by `Stats::total_bytes`.
+    ///
+    /// Writes are refused once the store reaches this size so it can exceed it by one value.
+    pub max_store_size: Option<u64>,
+}
+
+impl Limits {
+    /// Returns whether no limit is set.
+    pub fn is_unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+
+    pub(crate) fn check_key(&self, key: &str) -> Result<()> {
+        match self.max_key_len {
+            Some(max) if key.len() > max => Err(KvError::KeyTooLong(max)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn check_value(&self, size: u64) -> Result<()> {
+        match self.max_value_size {
+            Some(max) if size > max => Err(KvError::ValueTooLarge(max)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails if a store holding `size` bytes must refuse writes.
+    pub(crate) fn check_store(&self, size: u64) -> Result<()> {
+        match self.max_store_size {
+            Some(max) if size >= max => Err(KvError::QuotaExceeded(max)),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use crate::error::*;
+use crate::limits::Limits;
+use crate::migrate::MIGRATION_MARKER;
+use crate::registry::{EngineRegistry, DEFAULT_ENGINE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tempfile::NamedTempFile;
+
+/// Name of the file describing the store held in a data directory.
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Description of the store held in a data directory.
+///
+/// The data of the engine itself lives in sub-directory `pna-ENGINE` of the data directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Name under which the engine owning the data is registered.
+    pub engine: String,
+
+    /// Version of the on-disk format of the engine data or 0 if unknown.
+    pub format_version: u32,
+
+    /// Engine-specific options the store was created with, such as `shards`, `mmap` and limits.
+    ///
+    /// Engines read them back when opening the store.
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Creates manifest for engine `engine` writing format `format_version`, without options.
+    pub fn new(engine: &str, format_version: u32) -> Manifest {
+        Manifest {
+            engine: engine.to_owned(),
+            format_version,
+            options: BTreeMap::new(),
+        }
+    }
+
+    /// Returns option `name` parsed as a `T` or `None` if it is not set.
+    pub fn option<T: FromStr>(&self, name: &str) -> Result<Option<T>> {
+        match self.options.get(name) {
+            Some(val) => val.parse().map(Some).map_err(|_| {
+                KvError::Other(format!("invalid manifest option {}: {:?}", name, val))
+            }),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns limits recorded in options.
+    pub fn limits(&self) -> Result<Limits> {
+        Ok(Limits {
+            max_key_len: self.option("max_key_len")?,
+            max_value_size: self.option("max_value_size")?,
+            max_store_size: self.option("max_store_size")?,
+        })
+    }
+
+    /// Records `limits` in options, replacing previous ones.
+    pub fn set_limits(&mut self, limits: &Limits) {
+        let limits = [
+            ("max_key_len", limits.max_key_len.map(|len| len as u64)),
+            ("max_value_size", limits.max_value_size),
+            ("max_store_size", limits.max_store_size),
+        ];
+        for (name, val) in limits.iter() {
+            match val {
+                Some(val) => self.options.insert(name.to_string(), val.to_string()),
+                None => self.options.remove(*name),
+            };
+        }
+    }
+
+    /// Reads manifest of data directory `data_dir` or returns `None` if there is none.
+    pub fn load(data_dir: &Path) -> Result<Option<Manifest>> {
+        match File::open(data_dir.join(MANIFEST_FILE)) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Writes manifest to data directory `data_dir`, atomically replacing the previous one.
+    pub fn save(&self, data_dir: &Path) -> Result<()> {
+        let tmp_file = NamedTempFile::new_in(data_dir)?;
+        {
+            let mut wr = BufWriter::new(tmp_file.as_file());
+            serde_json::to_writer_pretty(&mut wr, self)?;
+            writeln!(wr)?;
+            wr.flush()?;
+        }
+        tmp_file.as_file().sync_all()?;
+        tmp_file
+            .persist(data_dir.join(MANIFEST_FILE))
+            .map_err(|err| err.error)?;
+        Ok(())
+    }
+
+    /// Returns directory holding data of the engine inside data directory `data_dir`.
+    pub fn engine_dir(&self, data_dir: &Path) -> PathBuf {
+        data_dir.join(format!("pna-{}", self.engine))
+    }
+}
+
+/// Reads or creates manifest of data directory `data_dir` and returns it along with the directory
+/// where the engine should store its data.
+///
+/// When `name_opt` is `None`, the engine owning existing data if any or `DEFAULT_ENGINE` is
+/// selected.  Selecting another engine than the one owning existing data is an error.  Volatile
+/// engines leave the data directory untouched and get an empty path.
+pub fn prepare_engine_creation(
+    registry: &EngineRegistry,
+    data_dir: &Path,
+    name_opt: Option<&str>,
+) -> Result<(Manifest, PathBuf)> {
+    if let Some(name) = name_opt {
+        if !registry.is_persistent(name)? {
+            return Ok((registry.new_manifest(name)?, PathBuf::new()));
+        }
+    }
+
+    fs::create_dir_all(data_dir)?;
+    let manifest = match Manifest::load(data_dir)? {
+        Some(manifest) => manifest,
+        None => {
+            let manifest = match find_legacy_store(registry, data_dir)? {
+                Some(engine) => legacy_manifest(registry, data_dir, &engine)?,
+                None => registry.new_manifest(name_opt.unwrap_or(DEFAULT_ENGINE))?,
+            };
+            manifest.save(data_dir)?;
+            manifest
+        }
+    };
+
+    if let Some(name) = name_opt {
+        if name != manifest.engine {
+            return Err(KvError::BadEngine);
+        }
+    }
+    registry.is_persistent(&manifest.engine)?;
+
+    // Make sub-directory that will hold data in case it does not exist already.
+    let dir = manifest.engine_dir(data_dir);
+    fs::create_dir_all(&dir)?;
+
+    Ok((manifest, dir))
+}
+
+/// Reads manifest of the store held in data directory `data_dir` and returns it along with the
+/// directory holding data of its engine, without creating anything.
+///
+/// Fails if `data_dir` holds no store, or one owned by another engine than `name_opt` if set.
+pub fn find_existing_store(
+    registry: &EngineRegistry,
+    data_dir: &Path,
+    name_opt: Option<&str>,
+) -> Result<(Manifest, PathBuf)> {
+    let manifest = match Manifest::load(data_dir)? {
+        Some(manifest) => Some(manifest),
+        None if data_dir.is_dir() => match find_legacy_store(registry, data_dir)? {
+            Some(engine) => Some(legacy_manifest(registry, data_dir, &engine)?),
+            None => None,
+        },
+        None => None,
+    };
+    let (manifest, dir) = match manifest {
+        Some(manifest) if manifest.engine_dir(data_dir).is_dir() => {
+            let dir = manifest.engine_dir(data_dir);
+            (manifest, dir)
+        }
+        _ => {
+            return Err(KvError::Other(format!(
+                "no store found in {}",
+                data_dir.display()
+            )))
+        }
+    };
+
+    if let Some(name) = name_opt {
+        if name != manifest.engine {
+            return Err(KvError::BadEngine);
+        }
+    }
+    Ok((manifest, dir))
+}
+
+/// Returns manifest describing store of engine `engine` created in `data_dir` before manifests
+/// existed, recording the format version found on disk.
+fn legacy_manifest(registry: &EngineRegistry, data_dir: &Path, engine: &str) -> Result<Manifest> {
+    let mut manifest = Manifest::new(engine, 0);
+    manifest.format_version =
+        registry.detect_format_version(engine, &manifest.engine_dir(data_dir))?;
+    Ok(manifest)
+}
+
+/// Looks for data written before manifests existed, when the engine was detected from the
+/// sub-directory name.
+///
+/// Directories left by interrupted migrations are ignored.  Fails if several engines hold data
+/// as the store they belong to can not be told.
+fn find_legacy_store(registry: &EngineRegistry, data_dir: &Path) -> Result<Option<String>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let engine = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("pna-"))
+            .filter(|name| registry.is_persistent(name).unwrap_or(false))
+            .map(str::to_owned);
+        if let Some(engine) = engine {
+            if !entry.path().join(MIGRATION_MARKER).exists() {
+                found.push(engine);
+            }
+        }
+    }
+    if found.len() > 1 {
+        found.sort();
+        return Err(KvError::Other(format!(
+            "data of several engines found in {}: {}",
+            data_dir.display(),
+            found.join(", ")
+        )));
+    }
+    Ok(found.pop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, KvsEngine};
+    use tempfile::TempDir;
+
+    #[test]
+    fn create_and_reopen() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let data_dir = tmpdir.path().join("data");
+        let registry = EngineRegistry::default();
+
+        let (manifest, dir) = prepare_engine_creation(&registry, &data_dir, Some("sled"))?;
+        assert_eq!(manifest, Manifest::new("sled", 0));
+        assert_eq!(dir, data_dir.join("pna-sled"));
+        assert_eq!(Manifest::load(&data_dir)?, Some(manifest.clone()));
+
+        assert_eq!(
+            prepare_engine_creation(&registry, &data_dir, None)?,
+            (manifest, dir)
+        );
+        assert!(matches!(
+            prepare_engine_creation(&registry, &data_dir, Some("kvs")),
+            Err(KvError::BadEngine)
+        ));
+        let (manifest, dir) = prepare_engine_creation(&registry, &data_dir, Some("mem"))?;
+        assert_eq!(manifest.engine, "mem");
+        assert_eq!(dir, PathBuf::new());
+        Ok(())
+    }
+
+    #[test]
+    fn adopt_legacy_store() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        fs::create_dir(tmpdir.path().join("pna-kvs"))?;
+        let registry = EngineRegistry::default();
+
+        let (manifest, dir) = prepare_engine_creation(&registry, tmpdir.path(), None)?;
+        assert_eq!(manifest, Manifest::new("kvs", KvStore::FORMAT_VERSION));
+        assert_eq!(dir, tmpdir.path().join("pna-kvs"));
+        assert_eq!(Manifest::load(tmpdir.path())?, Some(manifest));
+        Ok(())
+    }
+
+    #[test]
+    fn ambiguous_legacy_store() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        fs::create_dir(tmpdir.path().join("pna-kvs"))?;
+        fs::create_dir(tmpdir.path().join("pna-sled"))?;
+        let registry = EngineRegistry::default();
+        assert!(matches!(
+            find_existing_store(&registry, tmpdir.path(), None),
+            Err(KvError::Other(_))
+        ));
+
+        // Leftovers of interrupted migrations are not stores.
+        fs::write(tmpdir.path().join("pna-sled").join(MIGRATION_MARKER), "")?;
+        let (manifest, _) = find_existing_store(&registry, tmpdir.path(), None)?;
+        assert_eq!(manifest.engine, "kvs");
+        Ok(())
+    }
+
+    #[test]
+    fn check_format_version() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let dir = tmpdir.path().join("pna-kvs");
+        fs::create_dir(&dir)?;
+        // Version 1 logs have no file header.
+        fs::write(
+            dir.join("kv.db"),
+            "{\"tag\":\"Set\",\"key\":\"k\",\"value_size\":4}\n\"v\"\n",
+        )?;
+        let registry = EngineRegistry::default();
+
+        let (mut manifest, dir) = find_existing_store(&registry, tmpdir.path(), None)?;
+        assert_eq!(manifest.format_version, 1);
+        assert!(matches!(
+            registry.open(&manifest, &dir),
+            Err(KvError::UnsupportedFormat(1))
+        ));
+
+        KvStore::upgrade(&dir)?;
+        manifest.format_version = KvStore::FORMAT_VERSION + 1;
+        assert!(matches!(
+            registry.open(&manifest, &dir),
+            Err(KvError::UnsupportedFormat(_))
+        ));
+        manifest.format_version = KvStore::FORMAT_VERSION;
+        registry.open(&manifest, &dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn find_existing() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let data_dir = tmpdir.path().join("data");
+        let registry = EngineRegistry::default();
+
+        assert!(matches!(
+            find_existing_store(&registry, &data_dir, None),
+            Err(KvError::Other(_))
+        ));
+        assert!(!data_dir.exists());
+        fs::create_dir(&data_dir)?;
+        assert!(find_existing_store(&registry, &data_dir, None).is_err());
+        assert_eq!(fs::read_dir(&data_dir)?.count(), 0);
+
+        let created = prepare_engine_creation(&registry, &data_dir, Some("sled"))?;
+        assert_eq!(find_existing_store(&registry, &data_dir, None)?, created);
+        assert!(matches!(
+            find_existing_store(&registry, &data_dir, Some("kvs")),
+            Err(KvError::BadEngine)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn record_options() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let registry = EngineRegistry::default();
+
+        let (mut manifest, _) = prepare_engine_creation(&registry, tmpdir.path(), Some("kvs"))?;
+        assert_eq!(manifest.option::<usize>("shards")?, Some(1));
+        assert_eq!(manifest.option::<bool>("mmap")?, Some(false));
+        assert!(manifest.limits()?.is_unlimited());
+
+        let limits = Limits {
+            max_key_len: Some(10),
+            max_store_size: Some(1000),
+            ..Limits::default()
+        };
+        manifest.set_limits(&limits);
+        manifest.save(tmpdir.path())?;
+        let manifest = Manifest::load(tmpdir.path())?.unwrap();
+        assert_eq!(manifest.limits()?, limits);
+        assert_eq!(manifest.option::<usize>("shards")?, Some(1));
+        Ok(())
+    }
+}
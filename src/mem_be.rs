@@ -0,0 +1,146 @@
+use crate::engine::{KvsEngine, DEFAULT_NAMESPACE};
+use crate::error::*;
+use crate::stats::Stats;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+type Index = HashMap<String, Vec<u8>>;
+
+/// Key-value store keeping all data in memory.
+///
+/// Nothing is persisted: each call to `open()` returns an empty store whatever the path.  This is
+/// meant for tests and ephemeral caches.
+#[derive(Clone)]
+pub struct MemKvsEngine {
+    namespaces: Arc<Mutex<HashMap<String, Index>>>,
+
+    /// Namespace targeted by operations on this handle.
+    ns: String,
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn open<P: AsRef<Path>>(_path: P) -> Result<MemKvsEngine> {
+        Ok(MemKvsEngine {
+            namespaces: Arc::new(Mutex::new(HashMap::new())),
+            ns: DEFAULT_NAMESPACE.to_owned(),
+        })
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key, value.into_bytes())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let namespaces = self.namespaces.lock()?;
+        match namespaces.get(&self.ns).and_then(|index| index.get(&key)) {
+            Some(val) => match String::from_utf8(val.clone()) {
+                Ok(val) => Ok(Some(val)),
+                Err(_) => Err(KvError::InvalidUtf8(key)),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut namespaces = self.namespaces.lock()?;
+        match namespaces
+            .get_mut(&self.ns)
+            .and_then(|index| index.remove(&key))
+        {
+            Some(_) => Ok(()),
+            None => Err(KvError::KeyNotFound(key)),
+        }
+    }
+
+    fn namespace(&self, name: &str) -> Result<MemKvsEngine> {
+        Ok(MemKvsEngine {
+            namespaces: self.namespaces.clone(),
+            ns: name.to_owned(),
+        })
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.namespaces.lock()?.remove(name);
+        Ok(())
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.namespaces.lock()?.keys().cloned().collect())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let namespaces = self.namespaces.lock()?;
+        Ok(namespaces
+            .get(&self.ns)
+            .map(|index| index.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let namespaces = self.namespaces.lock()?;
+        Ok(Stats {
+            live_keys: namespaces.values().map(|index| index.len() as u64).sum(),
+            total_bytes: 0,
+            dead_bytes: Some(0),
+            dead_entries: Some(0),
+            last_compaction: None,
+            compactions: None,
+        })
+    }
+
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        let mut buf = Vec::new();
+        value.read_to_end(&mut buf)?;
+        self.set_bytes(key, buf)
+    }
+
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        let val = {
+            let namespaces = self.namespaces.lock()?;
+            match namespaces.get(&self.ns).and_then(|index| index.get(&key)) {
+                Some(val) => val.clone(),
+                None => return Ok(false),
+            }
+        };
+        wr.write_all(&val)?;
+        Ok(true)
+    }
+}
+
+impl MemKvsEngine {
+    fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        self.namespaces
+            .lock()?
+            .entry(self.ns.clone())
+            .or_default()
+            .insert(key, value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_and_streaming() -> Result<()> {
+        let engine = MemKvsEngine::open("unused")?;
+        let ns = engine.namespace("ns")?;
+        engine.set("k".to_string(), "v1".to_string())?;
+        ns.set_reader("k".to_string(), &mut &[0xffu8, 0xfe][..])?;
+        assert_eq!(engine.get("k".to_string())?, Some("v1".to_string()));
+        let mut val = Vec::new();
+        assert!(ns.get_writer("k".to_string(), &mut val)?);
+        assert_eq!(val, [0xff, 0xfe]);
+        assert!(ns.get("k".to_string()).is_err());
+        assert_eq!(engine.stats()?.live_keys, 2);
+
+        engine.drop_namespace("ns")?;
+        assert_eq!(ns.get("k".to_string())?, None);
+        assert!(engine.remove("k".to_string()).is_ok());
+        assert!(engine.remove("k".to_string()).is_err());
+        Ok(())
+    }
+}
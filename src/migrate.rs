@@ -0,0 +1,181 @@
+use crate::engine::KvsEngine;
+use crate::error::*;
+use crate::manifest::find_existing_store;
+use crate::registry::EngineRegistry;
+use log::warn;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+/// Size of values copied in memory.  Larger ones go through a temporary file.
+const SPOOL_MEMORY: usize = 1024 * 1024;
+
+/// File marking an engine directory as being filled by a migration, removed once the manifest
+/// switched to it.
+pub(crate) const MIGRATION_MARKER: &str = ".migrating";
+
+/// Copies all data of the store held in `data_dir` to engine `to` and makes it the engine of the
+/// store.  Returns the number of keys copied.
+///
+/// Fails if `data_dir` holds no store.  The store must not be in use.  If `from` is set, it must
+/// name the current engine of the store.  Fails too if the directory of engine `to` exists and
+/// was not left by an interrupted migration.
+/// The manifest is switched to the new engine only once all data has been copied and key counts
+/// checked so an interrupted migration leaves the store untouched.
+pub fn migrate(
+    registry: &EngineRegistry,
+    data_dir: &Path,
+    from: Option<&str>,
+    to: &str,
+) -> Result<u64> {
+    let (src_manifest, src_dir) = find_existing_store(registry, data_dir, from)?;
+    if !registry.is_persistent(&src_manifest.engine)? || !registry.is_persistent(to)? {
+        return Err(KvError::Other(
+            "can only migrate between engines storing data on disk".to_owned(),
+        ));
+    }
+    if src_manifest.engine == to {
+        return Err(KvError::Other(format!("store already uses {} engine", to)));
+    }
+
+    let mut dst_manifest = registry.new_manifest(to)?;
+    let dst_dir = dst_manifest.engine_dir(data_dir);
+    let marker = dst_dir.join(MIGRATION_MARKER);
+    // Engines are dropped at the end of the block so that all data is on disk before switching.
+    let total = {
+        if marker.exists() {
+            // Leftover of an interrupted migration.
+            fs::remove_dir_all(&dst_dir)?;
+        } else if dst_dir.exists() {
+            return Err(KvError::Other(format!(
+                "{} already exists and was not left by an interrupted migration",
+                dst_dir.display()
+            )));
+        }
+        fs::create_dir_all(&dst_dir)?;
+        fs::File::create(&marker)?;
+        let src = registry.open(&src_manifest, &src_dir)?;
+        // Limits are only carried over once copied as engines account for sizes differently.
+        let dst = registry.open(&dst_manifest, &dst_dir)?;
+
+        let mut counts = HashMap::new();
+        let mut val = tempfile::spooled_tempfile(SPOOL_MEMORY);
+        for ns in src.namespaces()? {
+            let src_ns = src.namespace(&ns)?;
+            let dst_ns = dst.namespace(&ns)?;
+            let mut count = 0;
+            for key in src_ns.keys()? {
+                val.set_len(0)?;
+                val.seek(SeekFrom::Start(0))?;
+                if src_ns.get_writer(key.clone(), &mut val)? {
+                    val.seek(SeekFrom::Start(0))?;
+                    dst_ns.set_reader(key, &mut val)?;
+                    count += 1;
+                }
+            }
+            counts.insert(ns, count);
+        }
+
+        for (ns, count) in &counts {
+            let copied = dst.namespace(ns)?.keys()?.len() as u64;
+            if copied != *count {
+                return Err(KvError::Other(format!(
+                    "namespace {:?} holds {} keys after migration instead of {}",
+                    ns, copied, count
+                )));
+            }
+        }
+        let total = counts.values().sum();
+        if dst.stats()?.live_keys != total {
+            return Err(KvError::Other(
+                "key count mismatch after migration".to_owned(),
+            ));
+        }
+        total
+    };
+
+    dst_manifest.set_limits(&src_manifest.limits()?);
+    dst_manifest.save(data_dir)?;
+    if let Err(err) = fs::remove_file(&marker) {
+        warn!("failed to remove {}: {}", marker.display(), err);
+    }
+    if let Err(err) = fs::remove_dir_all(&src_dir) {
+        warn!("failed to remove {}: {}", src_dir.display(), err);
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::prepare_engine_creation;
+    use tempfile::TempDir;
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let registry = EngineRegistry::default();
+        {
+            let (manifest, dir) = prepare_engine_creation(&registry, tmpdir.path(), Some("kvs"))?;
+            let store = registry.open(&manifest, &dir)?;
+            store.set("key1".to_owned(), "value1".to_owned())?;
+            store.set("key2".to_owned(), "value2".to_owned())?;
+            store
+                .namespace("ns")?
+                .set_reader("key1".to_owned(), &mut &vec![0xffu8; SPOOL_MEMORY + 1][..])?;
+        }
+
+        assert_eq!(migrate(&registry, tmpdir.path(), Some("kvs"), "sled")?, 3);
+        assert!(!tmpdir.path().join("pna-kvs").exists());
+        assert!(migrate(&registry, tmpdir.path(), Some("kvs"), "sled").is_err());
+        assert_eq!(migrate(&registry, tmpdir.path(), None, "kvs")?, 3);
+
+        let (manifest, dir) = prepare_engine_creation(&registry, tmpdir.path(), None)?;
+        assert_eq!(manifest.engine, "kvs");
+        let store = registry.open(&manifest, &dir)?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        let mut val = Vec::new();
+        assert!(store
+            .namespace("ns")?
+            .get_writer("key1".to_owned(), &mut val)?);
+        assert_eq!(val, vec![0xffu8; SPOOL_MEMORY + 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn no_store() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let registry = EngineRegistry::default();
+        assert!(migrate(&registry, tmpdir.path(), Some("kvs"), "sled").is_err());
+        assert!(migrate(&registry, tmpdir.path(), None, "sled").is_err());
+        assert_eq!(fs::read_dir(tmpdir.path())?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn existing_destination() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let registry = EngineRegistry::default();
+        {
+            let (manifest, dir) = prepare_engine_creation(&registry, tmpdir.path(), Some("kvs"))?;
+            registry
+                .open(&manifest, &dir)?
+                .set("key".to_owned(), "value".to_owned())?;
+        }
+
+        // Directories not created by a migration are left alone.
+        let dst_dir = tmpdir.path().join("pna-sled");
+        fs::create_dir(&dst_dir)?;
+        fs::write(dst_dir.join("data"), "precious")?;
+        assert!(migrate(&registry, tmpdir.path(), None, "sled").is_err());
+        assert_eq!(fs::read_to_string(dst_dir.join("data"))?, "precious");
+
+        // Those left by an interrupted one are replaced.
+        fs::write(dst_dir.join(MIGRATION_MARKER), "")?;
+        assert_eq!(migrate(&registry, tmpdir.path(), None, "sled")?, 1);
+        assert!(!dst_dir.join("data").exists());
+        assert!(!dst_dir.join(MIGRATION_MARKER).exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,268 @@
+use crate::engine::{BoxedKvsEngine, KvsEngine};
+use crate::error::*;
+use crate::manifest::Manifest;
+use crate::{KvStore, KvStoreOptions, MemKvsEngine, SledKvsEngine, SledOptions};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Name of the engine used when none is selected and no data exists on disk.
+pub const DEFAULT_ENGINE: &str = "kvs";
+
+/// Function opening an engine whose data is stored in the given directory with the options
+/// recorded in the given manifest.
+pub type EngineOpener = fn(&Path, &Manifest) -> Result<BoxedKvsEngine>;
+
+struct Entry {
+    open: EngineOpener,
+    persistent: bool,
+    format_version: u32,
+    options: BTreeMap<String, String>,
+
+    /// Returns version of the on-disk format of data stored in the given directory, for engines
+    /// versioning their data.
+    detect_version: Option<fn(&Path) -> Result<u32>>,
+}
+
+/// Maps engine names to functions opening them.
+///
+/// `EngineRegistry::default()` knows about all built-in engines.  Other crates can add their own
+/// with `register()` and hand the registry to `prepare_engine_creation()`.
+pub struct EngineRegistry {
+    entries: BTreeMap<String, Entry>,
+}
+
+impl EngineRegistry {
+    /// Creates a registry knowing no engine.
+    pub fn new() -> EngineRegistry {
+        EngineRegistry {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Makes engine `E` available as `name`, replacing any engine previously registered under
+    /// this name.
+    ///
+    /// `persistent` tells whether the engine stores data on disk.  Volatile engines get no data
+    /// directory.  The engine can not be opened with limits.
+    pub fn register<E: KvsEngine>(&mut self, name: &str, persistent: bool) {
+        self.register_opener(
+            name,
+            open_boxed::<E>,
+            persistent,
+            E::FORMAT_VERSION,
+            BTreeMap::new(),
+        );
+    }
+
+    /// Makes engine opened by `open` available as `name`.
+    ///
+    /// This is for engines needing more than `KvsEngine::open()` to be created or supporting
+    /// limits.  `options` are recorded in the manifest of stores created with this engine.
+    pub fn register_opener(
+        &mut self,
+        name: &str,
+        open: EngineOpener,
+        persistent: bool,
+        format_version: u32,
+        options: BTreeMap<String, String>,
+    ) {
+        self.entries.insert(
+            name.to_owned(),
+            Entry {
+                open,
+                persistent,
+                format_version,
+                options,
+                detect_version: None,
+            },
+        );
+    }
+
+    /// Returns names of all registered engines in alphabetical order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    /// Returns whether engine `name` stores data on disk or `KvError::UnknownEngine` if there is
+    /// no such engine.
+    pub fn is_persistent(&self, name: &str) -> Result<bool> {
+        self.entry(name).map(|entry| entry.persistent)
+    }
+
+    /// Returns version of the on-disk format written by engine `name`.
+    pub fn format_version(&self, name: &str) -> Result<u32> {
+        self.entry(name).map(|entry| entry.format_version)
+    }
+
+    /// Returns manifest describing a new store of engine `name`, with its creation options.
+    pub fn new_manifest(&self, name: &str) -> Result<Manifest> {
+        let entry = self.entry(name)?;
+        let mut manifest = Manifest::new(name, entry.format_version);
+        manifest.options = entry.options.clone();
+        Ok(manifest)
+    }
+
+    /// Returns version of the on-disk format of data stored in `path` by engine `name`, assuming
+    /// it is the one the engine writes if the engine does not version its data.
+    pub(crate) fn detect_format_version(&self, name: &str, path: &Path) -> Result<u32> {
+        let entry = self.entry(name)?;
+        match entry.detect_version {
+            Some(detect) => detect(path),
+            None => Ok(entry.format_version),
+        }
+    }
+
+    /// Opens engine of `manifest` storing data in `path` with the options it records.
+    ///
+    /// Fails with `KvError::UnsupportedFormat` if the manifest records another format than the
+    /// one the engine writes, unless it records none.
+    pub fn open(&self, manifest: &Manifest, path: &Path) -> Result<BoxedKvsEngine> {
+        let entry = self.entry(&manifest.engine)?;
+        if manifest.format_version != 0 && manifest.format_version != entry.format_version {
+            return Err(KvError::UnsupportedFormat(manifest.format_version));
+        }
+        (entry.open)(path, manifest)
+    }
+
+    fn entry(&self, name: &str) -> Result<&Entry> {
+        self.entries.get(name).ok_or(KvError::UnknownEngine)
+    }
+}
+
+impl Default for EngineRegistry {
+    fn default() -> EngineRegistry {
+        let mut registry = EngineRegistry::new();
+        registry.register_opener(
+            "kvs",
+            open_kvs,
+            true,
+            KvStore::FORMAT_VERSION,
+            kvs_options(1),
+        );
+        // One shard per CPU, and at least two so that the store is sharded.
+        registry.register_opener(
+            "kvs-sharded",
+            open_kvs,
+            true,
+            KvStore::FORMAT_VERSION,
+            kvs_options(num_cpus::get().max(2)),
+        );
+        registry.register_opener(
+            "sled",
+            open_sled,
+            true,
+            SledKvsEngine::FORMAT_VERSION,
+            BTreeMap::new(),
+        );
+        registry.register::<MemKvsEngine>("mem", false);
+        for name in &["kvs", "kvs-sharded"] {
+            if let Some(entry) = registry.entries.get_mut(*name) {
+                entry.detect_version = Some(|path| KvStore::format_version(path));
+            }
+        }
+        registry
+    }
+}
+
+fn open_boxed<E: KvsEngine>(path: &Path, manifest: &Manifest) -> Result<BoxedKvsEngine> {
+    if !manifest.limits()?.is_unlimited() {
+        return Err(KvError::Other("engine does not support limits".to_owned()));
+    }
+    E::open(path).map(BoxedKvsEngine::new)
+}
+
+/// Returns creation options of `KvStore` engines.
+fn kvs_options(shards: usize) -> BTreeMap<String, String> {
+    let mut options = BTreeMap::new();
+    options.insert("shards".to_owned(), shards.to_string());
+    options.insert("mmap".to_owned(), false.to_string());
+    options
+}
+
+/// Opens `KvStore` with the shard count and read path recorded in `manifest`.
+///
+/// Manifests written before options were recorded lack them, in which case the store keeps the
+/// number of shards it was created with.
+fn open_kvs(path: &Path, manifest: &Manifest) -> Result<BoxedKvsEngine> {
+    let shards = manifest.option("shards")?;
+    let options = KvStoreOptions {
+        mmap: manifest.option("mmap")?.unwrap_or(false),
+        limits: manifest.limits()?,
+        shards: shards.unwrap_or(1),
+    };
+    let store = KvStore::open_with(path, options)?;
+    match shards {
+        Some(count) if count.max(1) != store.shard_count() => Err(KvError::Other(format!(
+            "store has {} shards but its manifest records {}",
+            store.shard_count(),
+            count
+        ))),
+        _ => Ok(BoxedKvsEngine::new(store)),
+    }
+}
+
+fn open_sled(path: &Path, manifest: &Manifest) -> Result<BoxedKvsEngine> {
+    let options = SledOptions {
+        limits: manifest.limits()?,
+        ..SledOptions::default()
+    };
+    SledKvsEngine::open_with(path, options).map(BoxedKvsEngine::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::limits::Limits;
+
+    #[test]
+    fn register_and_open() -> Result<()> {
+        let mut registry = EngineRegistry::default();
+        registry.register::<MemKvsEngine>("other", false);
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            ["kvs", "kvs-sharded", "mem", "other", "sled"]
+        );
+        assert!(!registry.is_persistent("other")?);
+
+        let mut manifest = registry.new_manifest("other")?;
+        let engine = registry.open(&manifest, Path::new(""))?;
+        engine
+            .namespace("ns")?
+            .set("k".to_owned(), "v".to_owned())?;
+        assert_eq!(engine.stats()?.live_keys, 1);
+
+        assert!(matches!(
+            registry.new_manifest("unknown"),
+            Err(KvError::UnknownEngine)
+        ));
+        manifest.set_limits(&Limits {
+            max_key_len: Some(1),
+            ..Limits::default()
+        });
+        assert!(registry.open(&manifest, Path::new("")).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn recorded_options() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let registry = EngineRegistry::default();
+        let mut manifest = registry.new_manifest("kvs-sharded")?;
+        let shards: usize = manifest.option("shards")?.unwrap();
+        assert!(shards >= 2);
+        manifest.set_limits(&Limits {
+            max_value_size: Some(4),
+            ..Limits::default()
+        });
+
+        let engine = registry.open(&manifest, tmpdir.path())?;
+        assert!(engine.set("k".to_owned(), "large".to_owned()).is_err());
+        drop(engine);
+
+        manifest
+            .options
+            .insert("shards".to_owned(), (shards + 1).to_string());
+        assert!(registry.open(&manifest, tmpdir.path()).is_err());
+        Ok(())
+    }
+}
@@ -1,99 +1,1030 @@
-use crate::{thread_pool::*, wire, KvsEngine, Result};
-use log::{debug, error};
-use std::io::{prelude::*, BufReader};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use crate::{thread_pool::*, wire, KvError, KvsEngine, Result, Stats};
+use log::{debug, error, warn};
+use polling::{Event, Events, Poller};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, prelude::*, BufReader, BufWriter, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Maximum number of requests of a connection handled concurrently.
+///
+/// The server stops reading requests from a connection while this many are pending.
+pub(crate) const MAX_IN_FLIGHT: usize = 64;
+
+/// Time to wait before accepting connections again after failing to.
+///
+/// Accepting fails when running out of file descriptors, in which case retrying at once would
+/// spin.
+pub(crate) const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Period at which shutdown checks whether connections are closed.
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+/// Period at which threads waiting for requests check whether the server stops.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Time a stopping server waits for requests already sent on a connection to arrive before
+/// closing it.
+pub(crate) const PENDING_WAIT: Duration = Duration::from_millis(10);
+
+/// Optional capabilities advertised by the handshake.
+const FEATURES: &[&str] = &["namespaces", "stats", "streaming", "pipelining", "auth"];
+
+/// Tunables for `KvsServer::new_with()`.
+#[derive(Clone, Debug)]
+pub struct KvsServerOptions {
+    /// Name of the engine reported to clients by the handshake.
+    pub engine_name: Option<String>,
+
+    /// Time a client may take to send a request once it started to, streamed value included.
+    /// `None` waits forever.
+    pub request_timeout: Option<Duration>,
+
+    /// Time a connection may stay idle between requests before the server closes it.  `None`
+    /// keeps idle connections open.
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum number of connections served at once.  Further connections are closed as soon as
+    /// they are accepted.
+    ///
+    /// Connections are not given a thread each, see `reader_threads`.
+    ///
+    /// Each connection may buffer a request of up to `max_request_size` bytes, so the defaults
+    /// bound memory used by requests being received to 256 MiB.
+    pub max_connections: usize,
+
+    /// Number of threads reading requests from connections.  Idle connections do not hold a
+    /// thread, but one sending requests or a streamed value holds one until they are received.
+    /// Ignored by `AsyncKvsServer`.
+    pub reader_threads: u32,
+
+    /// Time a client may take to accept each part of a reply.  `None` waits forever.
+    pub write_timeout: Option<Duration>,
+
+    /// Maximum size in bytes of a request, streamed values excluded.  Connections sending larger
+    /// requests are closed.  Larger values must be streamed.
+    pub max_request_size: usize,
+
+    /// Time shutdown waits for requests already sent to be handled.  Connections are closed
+    /// afterwards.  `None` waits forever.
+    pub drain_timeout: Option<Duration>,
+
+    /// Secret clients must present before sending key-value requests.  `None` serves anyone.
+    pub token: Option<String>,
+
+    /// Secret clients must present before sending admin requests such as shutdown.  It also
+    /// grants key-value requests.  `None` serves admin requests only if `token` is `None` too.
+    pub admin_token: Option<String>,
+}
+
+impl Default for KvsServerOptions {
+    fn default() -> KvsServerOptions {
+        KvsServerOptions {
+            engine_name: None,
+            request_timeout: Some(Duration::from_secs(30)),
+            idle_timeout: Some(Duration::from_secs(60)),
+            max_connections: 1024,
+            reader_threads: 16,
+            write_timeout: Some(Duration::from_secs(30)),
+            max_request_size: 256 * 1024,
+            drain_timeout: Some(Duration::from_secs(30)),
+            token: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl KvsServerOptions {
+    /// Returns what the handshake tells clients.
+    pub(crate) fn server_info(&self) -> wire::ServerInfo {
+        wire::ServerInfo {
+            protocol_version: wire::PROTOCOL_VERSION,
+            server_version: env!("CARGO_PKG_VERSION").to_owned(),
+            engine: self.engine_name.clone(),
+            features: FEATURES.iter().map(|&feature| feature.to_owned()).collect(),
+            max_request_size: self.max_request_size as u64,
+        }
+    }
+
+    /// Returns what connections may request before authenticating.
+    pub(crate) fn initial_access(&self) -> Access {
+        match (&self.token, &self.admin_token) {
+            (None, None) => Access::Admin,
+            (None, Some(_)) => Access::KeyValue,
+            (Some(_), _) => Access::Denied,
+        }
+    }
+
+    /// Returns what a connection presenting `token` may request, `None` if `token` is wrong.
+    pub(crate) fn authenticate(&self, token: &wire::Secret) -> Option<Access> {
+        if self.admin_token.as_ref().is_some_and(|t| token.matches(t)) {
+            Some(Access::Admin)
+        } else if self.token.as_ref().is_some_and(|t| token.matches(t)) {
+            Some(Access::KeyValue)
+        } else {
+            None
+        }
+    }
+}
+
+/// What requests received on a connection may do, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Access {
+    /// Only the handshake and authentication.
+    Denied,
+    KeyValue,
+    Admin,
+}
+
+impl Access {
+    /// Returns the error to reply to request `op` if it is not allowed.
+    pub(crate) fn check(self, op: &wire::Op) -> std::result::Result<(), wire::ReplyError> {
+        let required = match op {
+            wire::Op::Hello(_) | wire::Op::Auth(_) => Access::Denied,
+            wire::Op::Shutdown => Access::Admin,
+            _ => Access::KeyValue,
+        };
+        if self >= required {
+            Ok(())
+        } else if self == Access::Denied {
+            Err(wire::ReplyError::Unauthenticated)
+        } else {
+            Err(wire::ReplyError::PermissionDenied)
+        }
+    }
+
+    /// Returns what a connection may request after presenting `token`, replying to the
+    /// authentication request.
+    pub(crate) fn authenticate(
+        self,
+        options: &KvsServerOptions,
+        token: &wire::Secret,
+    ) -> (
+        Access,
+        std::result::Result<Option<String>, wire::ReplyError>,
+    ) {
+        match options.authenticate(token) {
+            Some(granted) => (self.max(granted), Ok(None)),
+            None => {
+                warn!("client presented a wrong token");
+                (self, Err(wire::ReplyError::Unauthenticated))
+            }
+        }
+    }
+}
 
 /// TCP/IP server handling requests from KvsClient instances.
+///
+/// Idle connections are watched by a single thread that hands them over to a pool of reader
+/// threads once a request arrives, so that idle connections do not hold threads.  A reader thread
+/// reads requests from a connection until none is left to read.  Requests are handed over to the
+/// thread pool, except streaming ones that are handled by the reader thread.  Reader threads do
+/// not come from the thread pool since they block while receiving requests, which would leave no
+/// thread to handle them.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     listener: TcpListener,
     engine: E,
-    thread_pool: Option<P>,
+    thread_pool: Option<Arc<P>>,
+    readers: Option<Arc<SharedQueueThreadPool>>,
+    idle: Arc<IdleConnections<E, P>>,
+    watcher: Option<JoinHandle<()>>,
+    connections: Arc<Connections>,
+    info: Arc<wire::ServerInfo>,
+    options: Arc<KvsServerOptions>,
+}
+
+/// Connection a shutdown request came from, its encoding and the request identifier.
+type Requester = (TcpStream, wire::Encoding, u64);
+
+/// Handle stopping a server from another thread, for instance on receiving a signal.
+#[derive(Clone)]
+pub struct ShutdownHandle(StopSignal);
+
+#[derive(Clone)]
+enum StopSignal {
+    Threads(Arc<Connections>),
+    #[cfg(feature = "async")]
+    EventLoop(Arc<tokio::sync::Notify>),
+}
+
+impl ShutdownHandle {
+    #[cfg(feature = "async")]
+    pub(crate) fn event_loop(stop: Arc<tokio::sync::Notify>) -> ShutdownHandle {
+        ShutdownHandle(StopSignal::EventLoop(stop))
+    }
+
+    /// Makes the server stop accepting connections, handle requests already sent, close
+    /// connections, flush the engine and return from `run()`.
+    ///
+    /// Returns without waiting for the server to stop.  Shutting down a server that is not
+    /// running yet makes it stop as soon as it runs.
+    pub fn shutdown(&self) -> Result<()> {
+        match self.0 {
+            StopSignal::Threads(ref connections) => connections.request_shutdown(None),
+            #[cfg(feature = "async")]
+            StopSignal::EventLoop(ref stop) => {
+                stop.notify_one();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Connections being served, tracked so that shutdown can close them.
+struct Connections {
+    /// Address the listener can be reached at.
+    addr: SocketAddr,
+
+    /// Open connections indexed by identifier, including those waiting for a reader thread.
+    open: Mutex<HashMap<u64, TcpStream>>,
+
+    /// Identifier of the next connection.
+    next_id: AtomicU64,
+
+    /// Whether the server must stop.
+    stopping: AtomicBool,
+
+    /// Where the shutdown request came from if it came from a client.
+    requester: Mutex<Option<Requester>>,
 }
 
-impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> KvsServer<E, P> {
     /// Creates a new server listening for requests on `addr` and delegating requests to `engine`.
     pub fn new(engine: E, pool: P, addr: SocketAddr) -> Result<KvsServer<E, P>> {
+        KvsServer::new_with(engine, pool, addr, KvsServerOptions::default())
+    }
+
+    /// Creates a new server with non-default options.
+    pub fn new_with(
+        engine: E,
+        pool: P,
+        addr: SocketAddr,
+        options: KvsServerOptions,
+    ) -> Result<KvsServer<E, P>> {
+        let listener = TcpListener::bind(addr)?;
+        let mut local_addr = listener.local_addr()?;
+        // Shutdown requests connect to the listener, through the loopback address of its family
+        // as IPv6 listeners may not accept IPv4 connections.
+        if local_addr.ip().is_unspecified() {
+            local_addr.set_ip(match local_addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
         Ok(KvsServer {
-            listener: TcpListener::bind(addr)?,
+            listener,
             engine,
-            thread_pool: Some(pool),
+            thread_pool: Some(Arc::new(pool)),
+            readers: Some(Arc::new(SharedQueueThreadPool::new(
+                options.reader_threads,
+            )?)),
+            idle: Arc::new(IdleConnections {
+                poller: Poller::new()?,
+                parked: Mutex::new(Parked {
+                    conns: HashMap::new(),
+                    closed: false,
+                }),
+            }),
+            watcher: None,
+            connections: Arc::new(Connections {
+                addr: local_addr,
+                open: Mutex::new(HashMap::new()),
+                next_id: AtomicU64::new(0),
+                stopping: AtomicBool::new(false),
+                requester: Mutex::new(None),
+            }),
+            info: Arc::new(options.server_info()),
+            options: Arc::new(options),
         })
     }
 
-    /// Serves requests until shutdown received or a fatal error occurs.
+    /// Returns a handle stopping this server.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(StopSignal::Threads(self.connections.clone()))
+    }
+
+    /// Serves requests until shutdown requested or a fatal error occurs.
     pub fn run(&mut self) -> Result<()> {
-        // Recycle buffer across iterations.
-        let mut line = String::new();
+        let idle = self.idle.clone();
+        let readers = self.readers.clone().unwrap();
+        let connections = self.connections.clone();
+        self.watcher = Some(thread::spawn(move || {
+            idle.watch(&readers, &connections.stopping)
+        }));
 
         for stream in self.listener.incoming() {
-            let mut stream = stream?;
-
-            // Decode request
-            let mut rd = BufReader::new(&stream);
-            line.clear();
-            rd.read_line(&mut line)?;
-            let cmd: wire::Request = serde_json::from_str(&line)?;
-            debug!("S: handling {:?}", cmd);
-
-            if cmd == wire::Request::Shutdown {
-                // Drop the pool to block until all worker threads complete.
-                self.thread_pool.take();
-
-                send_reply(&mut stream, wire::Reply(Ok(None)))
-                    .expect("error when replying to shutdown request");
-                break;
-            }
-
-            // Offload request processing to worker thread.
-            let engine = self.engine.clone();
-            self.thread_pool.as_ref().unwrap().spawn(move || {
-                match Self::handle_request(engine, cmd, stream) {
-                    Ok(_) => debug!("S: OK"),
-                    Err(err) => {
-                        // Errors that can not be forwarded back to clients are logged instead.
-                        error!("error while handling request: {}", err)
-                    }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("error while accepting connection: {}", err);
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
                 }
-            })
+            };
+
+            // Whoever requests shutdown wakes us up by connecting.
+            if self.connections.stopping.load(Ordering::SeqCst) {
+                return self.stop();
+            }
+
+            if self.connections.open.lock()?.len() >= self.options.max_connections {
+                warn!("too many connections, closing new one");
+                continue;
+            }
+            if let Err(err) = self.spawn_reader(stream) {
+                error!("error while setting up connection: {}", err);
+            }
         }
 
+        Ok(())
+    }
+
+    /// Stops serving requests once those already sent are handled, within the drain timeout.
+    fn stop(&mut self) -> Result<()> {
+        // Connections are closed once all requests sent before are handled.
+        if !self.connections.wait_closed(self.options.drain_timeout)? {
+            warn!("gave up waiting for requests being handled");
+            self.connections.close_all()?;
+            // Reader threads must be done with connections before the pool can be dropped.
+            self.connections.wait_closed(None)?;
+        }
+        if let Some(watcher) = self.watcher.take() {
+            if watcher.join().is_err() {
+                error!("idle connection watcher panicked");
+            }
+        }
+        self.readers.take();
+        self.thread_pool.take();
+        let res = self.engine.flush();
+
+        if let Some((requester, encoding, id)) = self.connections.requester.lock()?.take() {
+            let reply = wire::Reply::<Option<String>> {
+                id,
+                result: match res {
+                    Ok(()) => Ok(None),
+                    Err(ref err) => Err(wire::ReplyError::Other(err.to_string())),
+                },
+            };
+            if let Err(err) = send_reply(&mut &requester, encoding, reply) {
+                error!("error while replying to shutdown request: {}", err);
+            }
+        }
         debug!("S: exiting");
+        res
+    }
+
+    /// Makes `stream` wait for requests.
+    fn spawn_reader(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(self.options.write_timeout)?;
+        let conn = Connection {
+            registration: Registration {
+                id: self.connections.add(&stream)?,
+                connections: self.connections.clone(),
+            },
+            wr: Arc::new(Mutex::new(stream.try_clone()?)),
+            rd: BufReader::new(TimedStream {
+                stream,
+                deadline: None,
+                timeout: None,
+            }),
+            encoding: None,
+            access: self.options.initial_access(),
+            legacy: false,
+            in_flight: Arc::new(InFlight::default()),
+            idle_deadline: self.options.idle_timeout.map(|t| Instant::now() + t),
+            engine: self.engine.clone(),
+            pool: self.thread_pool.clone().unwrap(),
+            idle: self.idle.clone(),
+            info: self.info.clone(),
+            options: self.options.clone(),
+        };
+        // Connections accepted while stopping are dropped.
+        let _ = self.idle.park(conn);
         Ok(())
     }
+}
+
+/// Connection served by reader threads, parked in `IdleConnections` in between.
+struct Connection<E: KvsEngine, P: ThreadPool> {
+    registration: Registration,
+    rd: BufReader<TimedStream>,
+    wr: Arc<Mutex<TcpStream>>,
+
+    /// Encoding of requests, known once the first one starts arriving.
+    encoding: Option<wire::Encoding>,
+
+    access: Access,
+
+    /// Legacy clients send a single request and wait for the connection to be closed.
+    legacy: bool,
+
+    in_flight: Arc<InFlight>,
 
-    fn handle_request(engine: E, cmd: wire::Request, mut stream: TcpStream) -> Result<()> {
-        match cmd {
-            wire::Request::Get(key) => {
-                let reply = wire::Reply(engine.get(key).map_err(|err| err.to_string()));
-                send_reply(&mut stream, reply)?;
-            }
-            wire::Request::Set(key, val) => {
-                let reply = wire::Reply(
-                    engine
-                        .set(key, val)
-                        .map(|_| None)
-                        .map_err(|err| err.to_string()),
-                );
-                send_reply(&mut stream, reply)?;
-            }
-            wire::Request::Rm(key) => {
-                let reply = wire::Reply(
-                    engine
-                        .remove(key)
-                        .map(|_| None)
-                        .map_err(|err| err.to_string()),
-                );
-                send_reply(&mut stream, reply)?;
-            }
-            wire::Request::Shutdown => panic!("shutdown request not handled in server thread"),
+    /// Time by which the next request must start arriving, if any.
+    idle_deadline: Option<Instant>,
+
+    engine: E,
+    pool: Arc<P>,
+    idle: Arc<IdleConnections<E, P>>,
+
+    info: Arc<wire::ServerInfo>,
+    options: Arc<KvsServerOptions>,
+}
+
+/// What serving a connection for a while led to.
+enum Progress {
+    /// All requests received were read.
+    Idle,
+
+    /// The client closed the connection, stayed idle for too long or the server stops.
+    Closed,
+
+    /// The client requested shutdown.
+    Shutdown(Requester),
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> Connection<E, P> {
+    /// Serves requests until the connection is idle, parking it then, or until it is closed.
+    fn serve(mut self) {
+        let res = loop {
+            match self.serve_requests() {
+                Ok(Progress::Idle) => match self.idle.clone().park(self) {
+                    None => return,
+                    // The server stops so requests already sent are waited for here.
+                    Some(conn) => self = conn,
+                },
+                Ok(Progress::Closed) => break Ok(None),
+                Ok(Progress::Shutdown(requester)) => break Ok(Some(requester)),
+                Err(err) => break Err(err),
+            }
         };
+        if let Err(err) = self.in_flight.wait_idle() {
+            error!("error while waiting for requests: {}", err);
+        }
+        match res {
+            Ok(None) => debug!("S: connection closed"),
+            Ok(Some(requester)) => {
+                if let Err(err) = self
+                    .registration
+                    .connections
+                    .request_shutdown(Some(requester))
+                {
+                    error!("error while requesting shutdown: {}", err)
+                }
+            }
+            Err(err) => {
+                // Errors that can not be forwarded back to clients are logged instead.
+                error!("error while handling request: {}", err)
+            }
+        }
+    }
+
+    /// Serves requests received on the connection, which should be readable, until none is left
+    /// to read or the connection is closed.  Requests handled by the thread pool may still be
+    /// pending on return.
+    fn serve_requests(&mut self) -> Result<Progress> {
+        let options = self.options.clone();
+        let connections = self.registration.connections.clone();
+        let mut first = true;
+        loop {
+            if self.legacy {
+                return Ok(Progress::Closed);
+            }
+            // Requests already received are served before parking since the connection would
+            // not be reported readable.
+            let stopping = connections.stopping.load(Ordering::SeqCst);
+            if !first && !stopping && self.rd.buffer().is_empty() {
+                return Ok(Progress::Idle);
+            }
+            first = false;
+            if !wait_request(&mut self.rd, self.idle_deadline, &connections.stopping)? {
+                return Ok(Progress::Closed);
+            }
+            self.rd.get_mut().set_deadline(options.request_timeout);
+            let encoding = match self.encoding {
+                Some(encoding) => encoding,
+                None => match wire::Encoding::detect(&mut self.rd)? {
+                    Some(encoding) => {
+                        debug!("S: {:?} connection", encoding);
+                        *self.encoding.insert(encoding)
+                    }
+                    None => return Ok(Progress::Closed),
+                },
+            };
+            let msg = match encoding.read_message_or_skip(&mut self.rd, options.max_request_size)? {
+                Some(wire::Message::Whole(msg)) => msg,
+                Some(wire::Message::TooLarge(id)) => {
+                    // The next request can still be read since the message was skipped.
+                    match oversized_request(id, options.max_request_size) {
+                        Some(reply) => send_locked(&self.wr, encoding, reply)?,
+                        None => return Ok(Progress::Closed),
+                    }
+                    continue;
+                }
+                None => return Ok(Progress::Closed),
+            };
+            self.idle_deadline = options.idle_timeout.map(|t| Instant::now() + t);
+            let (req, encoding) = match encoding.decode_request(&msg) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    // The next request can still be read since the message was framed.
+                    if let Some(reply) = bad_request(encoding, &msg, err) {
+                        send_locked(&self.wr, encoding, reply)?;
+                    }
+                    continue;
+                }
+            };
+            self.legacy = encoding == wire::Encoding::LegacyJson;
+            debug!("S: handling {:?}", req);
+            if let Err(err) = self.access.check(&req.op) {
+                warn!("rejected {:?}: {:?}", req.op, err);
+                if let wire::Op::SetStream(..) = req.op {
+                    // Skip the value so that the next request can be read.
+                    wire::ChunkReader::new(&mut self.rd).drain()?;
+                }
+                let reply = wire::Reply::<Option<String>> {
+                    id: req.id,
+                    result: Err(err),
+                };
+                send_locked(&self.wr, encoding, reply)?;
+                continue;
+            }
+            match req.op {
+                wire::Op::Shutdown => {
+                    let stream = self.rd.get_ref().stream.try_clone()?;
+                    return Ok(Progress::Shutdown((stream, encoding, req.id)));
+                }
+                wire::Op::Hello(version) => {
+                    if version != wire::PROTOCOL_VERSION {
+                        warn!("client speaks protocol version {}", version);
+                    }
+                    let reply = wire::Reply {
+                        id: req.id,
+                        result: Ok(&*self.info),
+                    };
+                    send_locked(&self.wr, encoding, reply)?;
+                }
+                wire::Op::Auth(token) => {
+                    let (granted, result) = self.access.authenticate(&options, &token);
+                    self.access = granted;
+                    send_locked(&self.wr, encoding, wire::Reply { id: req.id, result })?;
+                }
+                // The request deadline covers streamed values too.
+                wire::Op::SetStream(..) | wire::Op::GetStream(..) => {
+                    self.handle_stream_request(encoding, req)?;
+                }
+                _ => {
+                    let guard = self.in_flight.acquire()?;
+                    let engine = self.engine.clone();
+                    let wr = self.wr.clone();
+                    self.pool.spawn(move || {
+                        let _guard = guard;
+                        let outcome = handle_request(&engine, req.op);
+                        match outcome.send(&wr, encoding, req.id) {
+                            Ok(()) => debug!("S: OK"),
+                            Err(err) => {
+                                error!("error while replying: {}", err);
+                                // What is left of the reply would garble later ones.
+                                if let Ok(wr) = wr.lock() {
+                                    let _ = wr.shutdown(Shutdown::Both);
+                                }
+                            }
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Handles request whose value is streamed on the connection.
+    fn handle_stream_request(
+        &mut self,
+        encoding: wire::Encoding,
+        req: wire::Request,
+    ) -> Result<()> {
+        let id = req.id;
+        match req.op {
+            wire::Op::SetStream(ns, key) => {
+                let mut chunks = wire::ChunkReader::new(&mut self.rd);
+                let res = self
+                    .engine
+                    .namespace(&ns)
+                    .and_then(|e| e.set_reader(key, &mut chunks));
+                // Skip what is left of the value on error so that the client can read the reply.
+                chunks.drain()?;
+                let result = res.map(|_| None::<String>).map_err(wire::ReplyError::from);
+                send_reply(&mut *self.wr.lock()?, encoding, wire::Reply { id, result })
+            }
+            wire::Op::GetStream(ns, key) => {
+                // Replies to other requests must not be interleaved with the value.
+                let mut wr = self.wr.lock()?;
+                let mut chunks = wire::ChunkWriter::new(BufWriter::new(&mut *wr));
+                let res = self
+                    .engine
+                    .namespace(&ns)
+                    .and_then(|e| e.get_writer(key, &mut chunks));
+                chunks.finish()?;
+                let result = res.map_err(wire::ReplyError::from);
+                send_reply(&mut *wr, encoding, wire::Reply { id, result })
+            }
+            op => panic!("{:?} is not a streaming request", op),
+        }
+    }
+}
+
+/// Keeps a connection tracked until dropped, even if serving it panicked.
+struct Registration {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.remove(self.id);
+    }
+}
+
+/// Connections waiting for requests.
+struct IdleConnections<E: KvsEngine, P: ThreadPool> {
+    /// Reports connections that became readable.  Each connection is added while parked only.
+    poller: Poller,
+
+    parked: Mutex<Parked<E, P>>,
+}
+
+struct Parked<E: KvsEngine, P: ThreadPool> {
+    /// Parked connections indexed by identifier.
+    conns: HashMap<u64, Connection<E, P>>,
+
+    /// Whether the server stops, connections being no longer parked then.
+    closed: bool,
+}
+
+impl<E: KvsEngine, P: ThreadPool + Send + Sync + 'static> IdleConnections<E, P> {
+    /// Parks `conn` until a request arrives on it.  Gives it back if the server stops.
+    fn park(&self, conn: Connection<E, P>) -> Option<Connection<E, P>> {
+        let mut parked = match self.parked.lock() {
+            Ok(parked) => parked,
+            Err(_) => return Some(conn),
+        };
+        if parked.closed {
+            return Some(conn);
+        }
+        let id = conn.registration.id;
+        // Safety: connections are deleted from the poller before being dropped or served.
+        let added = unsafe {
+            self.poller
+                .add(&conn.rd.get_ref().stream, Event::readable(id as usize))
+        };
+        if let Err(err) = added {
+            error!("error while watching connection: {}", err);
+            return Some(conn);
+        }
+        parked.conns.insert(id, conn);
+        None
+    }
+
+    /// Hands readable connections over to `readers` and closes those idle for too long until the
+    /// server stops.
+    fn watch(&self, readers: &SharedQueueThreadPool, stopping: &AtomicBool) {
+        let mut events = Events::new();
+        loop {
+            let stop = stopping.load(Ordering::SeqCst);
+            let timeout = if stop {
+                PENDING_WAIT
+            } else {
+                self.next_deadline()
+                    .map_or(STOP_POLL, |deadline| {
+                        deadline.saturating_duration_since(Instant::now())
+                    })
+                    .min(STOP_POLL)
+            };
+            events.clear();
+            if let Err(err) = self.poller.wait(&mut events, Some(timeout)) {
+                error!("error while watching connections: {}", err);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+
+            let mut parked = match self.parked.lock() {
+                Ok(parked) => parked,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for event in events.iter() {
+                if let Some(conn) = parked.conns.remove(&(event.key as u64)) {
+                    self.unwatch(&conn);
+                    readers.spawn(move || conn.serve());
+                }
+            }
+            let now = Instant::now();
+            let expired: Vec<u64> = parked
+                .conns
+                .iter()
+                .filter(|(_, conn)| stop || conn.idle_deadline.is_some_and(|d| now >= d))
+                .map(|(&id, _)| id)
+                .collect();
+            for id in expired {
+                if let Some(conn) = parked.conns.remove(&id) {
+                    debug!("S: closing idle connection");
+                    self.unwatch(&conn);
+                }
+            }
+            if stop {
+                parked.closed = true;
+                return;
+            }
+        }
+    }
+
+    /// Returns time by which the first parked connection expires, if any.
+    fn next_deadline(&self) -> Option<Instant> {
+        let parked = self.parked.lock().ok()?;
+        parked
+            .conns
+            .values()
+            .filter_map(|conn| conn.idle_deadline)
+            .min()
+    }
+
+    fn unwatch(&self, conn: &Connection<E, P>) {
+        if let Err(err) = self.poller.delete(&conn.rd.get_ref().stream) {
+            error!("error while unwatching connection: {}", err);
+        }
+    }
+}
+
+/// Waits for a request to start arriving on `rd` until `idle_deadline`.  Returns whether one did,
+/// `false` meaning that the connection was closed, stayed idle for too long or that the server
+/// stops and no request was sent before.
+fn wait_request(
+    rd: &mut BufReader<TimedStream>,
+    idle_deadline: Option<Instant>,
+    stopping: &AtomicBool,
+) -> Result<bool> {
+    loop {
+        let stop = stopping.load(Ordering::SeqCst);
+        let timeout = if stop {
+            PENDING_WAIT
+        } else {
+            match idle_deadline {
+                Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                None => STOP_POLL,
+            }
+            .min(STOP_POLL)
+        };
+        // Zero timeouts are refused.
+        rd.get_mut()
+            .set_timeout(Some(timeout.max(Duration::from_millis(1))));
+        match rd.fill_buf() {
+            Ok(buf) => return Ok(!buf.is_empty()),
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                if stop {
+                    return Ok(false);
+                }
+                if idle_deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    debug!("S: closing idle connection");
+                    return Ok(false);
+                }
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Connection stream cutting off clients too slow to send requests.
+struct TimedStream {
+    stream: TcpStream,
+
+    /// Time by which reads must complete, if any.
+    deadline: Option<Instant>,
+
+    /// Timeout of each read when there is no deadline.
+    timeout: Option<Duration>,
+}
+
+impl TimedStream {
+    /// Makes reads fail once `timeout` elapsed from now.
+    fn set_deadline(&mut self, timeout: Option<Duration>) {
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.timeout = None;
+    }
+
+    /// Makes each read fail if it takes longer than `timeout`.
+    fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.deadline = None;
+        self.timeout = timeout;
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if left > Duration::ZERO => Some(left),
+                _ => return Err(ErrorKind::TimedOut.into()),
+            },
+            None => self.timeout,
+        };
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.read(buf).map_err(|err| match err.kind() {
+            // Unix reports timeouts as such.
+            ErrorKind::WouldBlock => ErrorKind::TimedOut.into(),
+            _ => err,
+        })
+    }
+}
+
+/// Handles request that does not involve the connection.
+pub(crate) fn handle_request<E: KvsEngine>(engine: &E, op: wire::Op) -> Outcome {
+    let res = match op {
+        wire::Op::Get(ns, key) => engine.namespace(&ns).and_then(|e| e.get(key)),
+        wire::Op::Set(ns, key, val) => engine
+            .namespace(&ns)
+            .and_then(|e| e.set(key, val))
+            .map(|_| None),
+        wire::Op::Rm(ns, key) => engine
+            .namespace(&ns)
+            .and_then(|e| e.remove(key))
+            .map(|_| None),
+        wire::Op::DropNamespace(ns) => engine.drop_namespace(&ns).map(|_| None),
+        wire::Op::Stats => return Outcome::Stats(engine.stats().map_err(wire::ReplyError::from)),
+        op => panic!("{:?} must be handled by the connection thread", op),
+    };
+    Outcome::Value(res.map_err(wire::ReplyError::from))
+}
+
+/// Logs that request `msg` could not be decoded and returns the reply to send, if the request
+/// identifier could be told.
+pub(crate) fn bad_request(
+    encoding: wire::Encoding,
+    msg: &[u8],
+    err: KvError,
+) -> Option<wire::Reply<Option<String>>> {
+    warn!("malformed request: {}", err);
+    let id = encoding.message_id(msg).ok()?;
+    Some(wire::Reply {
+        id,
+        result: Err(wire::ReplyError::Other(format!(
+            "malformed request: {}",
+            err
+        ))),
+    })
+}
+
+/// Returns the reply to a request larger than `max_len` bytes whose identifier is `id`, or `None`
+/// if the identifier is unknown and the connection should be closed instead.
+pub(crate) fn oversized_request(
+    id: Option<u64>,
+    max_len: usize,
+) -> Option<wire::Reply<Option<String>>> {
+    warn!("request larger than {} bytes", max_len);
+    Some(wire::Reply {
+        id: id?,
+        result: Err(wire::ReplyError::RequestTooLarge(max_len as u64)),
+    })
+}
+
+/// Outcome of a request handled by the thread pool.
+pub(crate) enum Outcome {
+    Value(std::result::Result<Option<String>, wire::ReplyError>),
+    Stats(std::result::Result<Stats, wire::ReplyError>),
+}
+
+impl Outcome {
+    /// Replies to request `id` on `wr`.
+    fn send(self, wr: &Mutex<TcpStream>, encoding: wire::Encoding, id: u64) -> Result<()> {
+        let reply = self.encode(encoding, id)?;
+        wr.lock()?.write_all(&reply)?;
+        Ok(())
+    }
+
+    /// Returns reply to request `id`.
+    pub(crate) fn encode(self, encoding: wire::Encoding, id: u64) -> Result<Vec<u8>> {
+        debug!("S: replying to {}", id);
+        let mut buf = Vec::new();
+        match self {
+            Outcome::Value(result) => encoding.write_reply(&mut buf, &wire::Reply { id, result }),
+            Outcome::Stats(result) => encoding.write_reply(&mut buf, &wire::Reply { id, result }),
+        }?;
+        Ok(buf)
+    }
+}
+
+/// Counts requests of a connection being handled by the thread pool.
+#[derive(Default)]
+struct InFlight {
+    count: Mutex<usize>,
+    changed: Condvar,
+}
+
+/// Accounts for a request until dropped.
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlight {
+    /// Waits until fewer than `MAX_IN_FLIGHT` requests are pending and accounts for a new one.
+    fn acquire(self: &Arc<Self>) -> Result<InFlightGuard> {
+        let mut count = self.count.lock()?;
+        while *count >= MAX_IN_FLIGHT {
+            count = self.changed.wait(count)?;
+        }
+        *count += 1;
+        Ok(InFlightGuard(self.clone()))
+    }
+
+    /// Waits until all pending requests are handled.
+    fn wait_idle(&self) -> Result<()> {
+        let mut count = self.count.lock()?;
+        while *count > 0 {
+            count = self.changed.wait(count)?;
+        }
         Ok(())
     }
 }
 
-fn send_reply(wr: &mut impl Write, r: wire::Reply) -> Result<()> {
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        // Released even if the job panicked so that the connection thread does not wait forever.
+        let mut count = match self.0.count.lock() {
+            Ok(count) => count,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *count -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Connections {
+    /// Tracks `stream` and returns its identifier.
+    fn add(&self, stream: &TcpStream) -> Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.open.lock()?.insert(id, stream.try_clone()?);
+        Ok(id)
+    }
+
+    fn remove(&self, id: u64) {
+        if let Ok(mut open) = self.open.lock() {
+            open.remove(&id);
+        }
+    }
+
+    /// Makes the listening thread stop, handing `requester` over to it if the request came from
+    /// a client.
+    fn request_shutdown(&self, requester: Option<Requester>) -> Result<()> {
+        if let Some(requester) = requester {
+            self.requester.lock()?.get_or_insert(requester);
+        }
+        self.stopping.store(true, Ordering::SeqCst);
+        TcpStream::connect(self.addr)?;
+        Ok(())
+    }
+
+    /// Closes connections still being served.
+    fn close_all(&self) -> Result<()> {
+        for stream in self.open.lock()?.values() {
+            if let Err(err) = stream.shutdown(Shutdown::Both) {
+                warn!("failed to close connection: {}", err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits for all connections to be closed for at most `timeout`.  Returns whether they were.
+    fn wait_closed(&self, timeout: Option<Duration>) -> Result<bool> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while !self.open.lock()?.is_empty() {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(false);
+            }
+            thread::sleep(DRAIN_POLL);
+        }
+        Ok(true)
+    }
+}
+
+/// Sends reply on a connection whose replies may come from several threads.
+fn send_locked<T: Serialize + Debug>(
+    wr: &Mutex<TcpStream>,
+    encoding: wire::Encoding,
+    r: wire::Reply<T>,
+) -> Result<()> {
+    send_reply(&mut *wr.lock()?, encoding, r)
+}
+
+fn send_reply<T: Serialize + Debug>(
+    wr: &mut impl Write,
+    encoding: wire::Encoding,
+    r: wire::Reply<T>,
+) -> Result<()> {
     debug!("S: replying {:?}", r);
-    let ser = serde_json::to_string(&r)?;
-    writeln!(wr, "{}", ser)?;
+    // Sent in one write so that Nagle's algorithm does not delay the end of the reply.
+    let mut ser = Vec::new();
+    encoding.write_reply(&mut ser, &r)?;
+    wr.write_all(&ser)?;
     Ok(())
 }
@@ -0,0 +1,587 @@
+//! Simulated filesystem injecting faults, used to test crash consistency of `KvStore`.
+//!
+//! Metadata operations (creation, renaming and removal of files and directories) are atomic but
+//! only survive power loss once the directory holding them is synced.  Like `OsFs`, operations
+//! changing entries sync their directory before returning, as a separate step that may fail.
+//! File contents are only durable once synced.
+
+use crate::vfs::{ReadFile, Vfs, WriteFile};
+use memmap2::{Mmap, MmapMut};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Cursor, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct SimFile {
+    data: Vec<u8>,
+
+    /// Content surviving power loss.
+    synced: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Inode numbers of files by path.
+    files: HashMap<PathBuf, u64>,
+    dirs: HashSet<PathBuf>,
+
+    /// Entries surviving power loss, as of the last sync of their directory.
+    durable_files: HashMap<PathBuf, u64>,
+    durable_dirs: HashSet<PathBuf>,
+
+    inodes: HashMap<u64, SimFile>,
+    next_inode: u64,
+
+    /// Number of operations performed so far.
+    ops: u64,
+
+    /// Operation to fail, if any.
+    fault_at: Option<u64>,
+
+    /// Whether all operations fail after the faulty one, as if the process had died.
+    crash: bool,
+    crashed: bool,
+}
+
+impl State {
+    /// Accounts for a new operation and returns whether it must fail.
+    fn fault(&mut self) -> bool {
+        self.ops += 1;
+        if self.crashed {
+            return true;
+        }
+        if self.fault_at == Some(self.ops) {
+            self.crashed = self.crash;
+            return true;
+        }
+        false
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if self.fault() {
+            return Err(injected());
+        }
+        Ok(())
+    }
+
+    fn inode(&mut self, inode: u64) -> &mut SimFile {
+        self.inodes.get_mut(&inode).expect("inodes are never freed")
+    }
+
+    fn file(&mut self, path: &Path) -> io::Result<&mut SimFile> {
+        let inode = *self
+            .files
+            .get(path)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        Ok(self.inode(inode))
+    }
+
+    /// Makes current entries of directory `dir` durable.
+    fn sync_dir(&mut self, dir: &Path) -> io::Result<()> {
+        self.check()?;
+        let in_dir = |path: &Path| path.parent() == Some(dir);
+        self.durable_files.retain(|path, _| !in_dir(path));
+        self.durable_dirs.retain(|path| !in_dir(path));
+        let files: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(path, _)| in_dir(path))
+            .map(|(path, &inode)| (path.clone(), inode))
+            .collect();
+        let dirs: Vec<_> = self
+            .dirs
+            .iter()
+            .filter(|path| in_dir(path))
+            .cloned()
+            .collect();
+        self.durable_files.extend(files);
+        self.durable_dirs.extend(dirs);
+        Ok(())
+    }
+
+    fn sync_parent(&mut self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(dir) => self.sync_dir(dir),
+            None => Ok(()),
+        }
+    }
+}
+
+fn injected() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+/// In-memory filesystem failing on demand.
+#[derive(Clone, Default)]
+pub(crate) struct SimFs(Arc<Mutex<State>>);
+
+impl SimFs {
+    pub(crate) fn new() -> SimFs {
+        SimFs::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.0.lock().unwrap()
+    }
+
+    /// Returns number of operations performed so far.
+    pub(crate) fn ops(&self) -> u64 {
+        self.state().ops
+    }
+
+    /// Makes operation number `op` fail.  A failing write writes part of its buffer first.
+    ///
+    /// If `crash` is set, all later operations fail too.
+    pub(crate) fn fail_at(&self, op: u64, crash: bool) {
+        let mut state = self.state();
+        state.fault_at = Some(op);
+        state.crash = crash;
+    }
+
+    /// Clears pending faults, as if the process restarted.
+    pub(crate) fn restart(&self) {
+        let mut state = self.state();
+        state.fault_at = None;
+        state.crashed = false;
+    }
+
+    /// Drops data and directory entries not synced and clears pending faults, as if the machine
+    /// restarted.
+    pub(crate) fn power_loss(&self) {
+        self.restart();
+        let mut state = self.state();
+        let state = &mut *state;
+        // Entries below a directory whose own entry was lost are lost too.
+        let lost: Vec<PathBuf> = state
+            .dirs
+            .iter()
+            .filter(|dir| dir.parent().is_some() && !state.durable_dirs.contains(*dir))
+            .cloned()
+            .collect();
+        let reachable = |path: &Path| !lost.iter().any(|dir| path.starts_with(dir));
+        state.dirs.retain(|dir| reachable(dir));
+        state.durable_dirs.retain(|dir| reachable(dir));
+        state.durable_files.retain(|path, _| reachable(path));
+        state.files = state.durable_files.clone();
+        for file in state.inodes.values_mut() {
+            file.data = file.synced.clone();
+        }
+    }
+
+    /// Opens file at `path` for writing, creating it if missing.
+    ///
+    /// If `truncate` is set, an existing file is truncated.  If `exclusive` is set, it is an
+    /// error instead.  The directory is synced as by `OsFs`.
+    fn open_writer(&self, path: &Path, truncate: bool, exclusive: bool) -> io::Result<SimWriter> {
+        let mut state = self.state();
+        state.check()?;
+        if let Some(dir) = path.parent() {
+            if !state.dirs.contains(dir) {
+                return Err(ErrorKind::NotFound.into());
+            }
+        }
+        let (inode, created) = match state.files.get(path) {
+            Some(_) if exclusive => return Err(ErrorKind::AlreadyExists.into()),
+            Some(&inode) => {
+                if truncate {
+                    state.inode(inode).data.clear();
+                }
+                (inode, false)
+            }
+            None => {
+                let inode = state.next_inode;
+                state.next_inode += 1;
+                state.inodes.insert(inode, SimFile::default());
+                state.files.insert(path.to_path_buf(), inode);
+                (inode, true)
+            }
+        };
+        if created || truncate {
+            state.sync_parent(path)?;
+        }
+        Ok(SimWriter {
+            fs: self.clone(),
+            inode,
+        })
+    }
+}
+
+impl Vfs for SimFs {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        let mut state = self.state();
+        state.check()?;
+        let data = state.file(path)?.data.clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(self.open_writer(path, false, false)?))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(self.open_writer(path, false, true)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        Ok(Box::new(self.open_writer(path, true, false)?))
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        let mut state = self.state();
+        state.check()?;
+        Ok(state.file(path)?.data.len() as u64)
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.check()?;
+        let file = state.file(path)?;
+        file.data.truncate(len as usize);
+        file.synced = file.data.clone();
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check()?;
+        let inode = state
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        state.files.insert(to.to_path_buf(), inode);
+        state.sync_parent(to)?;
+        if from.parent() != to.parent() {
+            state.sync_parent(from)?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check()?;
+        state
+            .files
+            .remove(path)
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        state.sync_parent(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state();
+        state.check()?;
+        let missing = path
+            .ancestors()
+            .take_while(|dir| !state.dirs.contains(*dir))
+            .count();
+        state
+            .dirs
+            .extend(path.ancestors().map(|dir| dir.to_path_buf()));
+        let created: Vec<&Path> = path.ancestors().take(missing.max(1)).collect();
+        for dir in created.into_iter().rev() {
+            state.sync_parent(dir)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut state = self.state();
+        state.check()?;
+        if !state.dirs.contains(path) {
+            return Err(ErrorKind::NotFound.into());
+        }
+        Ok(state
+            .files
+            .keys()
+            .chain(state.dirs.iter())
+            .filter(|entry| entry.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn map(&self, path: &Path) -> io::Result<Mmap> {
+        let mut state = self.state();
+        state.check()?;
+        let data = &state.file(path)?.data;
+        let mut mmap = MmapMut::map_anon(data.len().max(1))?;
+        mmap[..data.len()].copy_from_slice(data);
+        mmap.make_read_only()
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.state().sync_dir(path)
+    }
+}
+
+/// Writer of an open file, which keeps being written if its entry is removed or replaced.
+struct SimWriter {
+    fs: SimFs,
+    inode: u64,
+}
+
+impl Write for SimWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.fs.state();
+        let fault = state.fault();
+        let file = state.inode(self.inode);
+        if fault {
+            // Torn write.
+            file.data.extend_from_slice(&buf[..buf.len() / 2]);
+            return Err(injected());
+        }
+        file.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteFile for SimWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.fs.state();
+        state.check()?;
+        let file = state.inode(self.inode);
+        file.synced = file.data.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store_be::{BLOB_THRESHOLD, MAX_DEAD_ENTRIES};
+    use crate::{KvError, KvStore, KvStoreOptions, KvsEngine, Limits, Result, DEFAULT_NAMESPACE};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::io::Read;
+
+    const DIR: &str = "/store";
+
+    /// Values each key may have after a failure.
+    ///
+    /// Acknowledged operations must be visible while failed ones may or may not have been
+    /// applied.
+    struct Model(HashMap<(String, String), Vec<Option<String>>>);
+
+    impl Model {
+        fn record(&mut self, ns: &str, key: &str, val: Option<String>, acked: bool) {
+            let vals = self
+                .0
+                .entry((ns.to_owned(), key.to_owned()))
+                .or_insert_with(|| vec![None]);
+            if acked {
+                vals.clear();
+            }
+            vals.push(val);
+        }
+
+        fn check(&self, store: &KvStore) -> Result<()> {
+            for ((ns, key), vals) in &self.0 {
+                let val = store.namespace(ns)?.get(key.clone())?;
+                assert!(
+                    vals.contains(&val),
+                    "{:?}/{:?} is {:?}, expected one of {:?}",
+                    ns,
+                    key,
+                    val,
+                    vals
+                );
+            }
+            Ok(())
+        }
+    }
+
+    /// Runs random operations on a store held in `fs` and returns what they should have done.
+    ///
+    /// Enough operations are run to trigger compaction and blob creation.
+    fn run_workload(fs: &SimFs, seed: u64) -> Model {
+        let mut model = Model(HashMap::new());
+        let mut rng = StdRng::seed_from_u64(seed);
+        let store =
+            match KvStore::open_with_vfs(DIR, KvStoreOptions::default(), Arc::new(fs.clone())) {
+                Ok(store) => store,
+                Err(_) => return model,
+            };
+        for i in 0..150 {
+            let ns = ["", "ns"][rng.gen_range(0, 2)];
+            let key = format!("key{}", rng.gen_range(0, 8));
+            let handle = store.namespace(ns).unwrap();
+            match rng.gen_range(0, 20) {
+                0..=13 => {
+                    let val = format!("value{}", i);
+                    let acked = handle.set(key.clone(), val.clone()).is_ok();
+                    model.record(ns, &key, Some(val), acked);
+                }
+                14 => {
+                    let val = "x".repeat(100_000) + &i.to_string();
+                    let acked = handle.set(key.clone(), val.clone()).is_ok();
+                    model.record(ns, &key, Some(val), acked);
+                }
+                15..=18 => {
+                    let acked = match handle.remove(key.clone()) {
+                        Ok(()) => true,
+                        Err(crate::KvError::KeyNotFound(_)) => continue,
+                        Err(_) => false,
+                    };
+                    model.record(ns, &key, None, acked);
+                }
+                _ => {
+                    let acked = store.drop_namespace(ns).is_ok();
+                    let keys: Vec<String> = model
+                        .0
+                        .keys()
+                        .filter(|(key_ns, _)| key_ns == ns)
+                        .map(|(_, key)| key.clone())
+                        .collect();
+                    for key in keys {
+                        model.record(ns, &key, None, acked);
+                    }
+                }
+            }
+        }
+        model
+    }
+
+    /// Returns filesystem holding an empty store directory.
+    fn new_fs() -> SimFs {
+        let fs = SimFs::new();
+        fs.create_dir_all(Path::new(DIR)).unwrap();
+        fs
+    }
+
+    fn reopen(fs: &SimFs) -> Result<KvStore> {
+        KvStore::open_with_vfs(DIR, KvStoreOptions::default(), Arc::new(fs.clone()))
+    }
+
+    /// Value whose end is read only once another writer filled the store.
+    struct RacingValue {
+        data: Vec<u8>,
+        pos: usize,
+        other: Option<KvStore>,
+    }
+
+    impl Read for RacingValue {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pos == self.data.len() {
+                if let Some(other) = self.other.take() {
+                    other
+                        .set("other".to_owned(), "v".repeat(2000))
+                        .map_err(io::Error::other)?;
+                }
+                return Ok(0);
+            }
+            let len = buf.len().min(self.data.len() - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn quota_exceeded_while_writing_blob() -> Result<()> {
+        let fs = new_fs();
+        let options = KvStoreOptions {
+            limits: Limits {
+                max_store_size: Some(1000),
+                ..Limits::default()
+            },
+            ..KvStoreOptions::default()
+        };
+        let store = KvStore::open_with_vfs(DIR, options, Arc::new(fs.clone()))?;
+        let mut value = RacingValue {
+            data: vec![b'x'; BLOB_THRESHOLD + 10],
+            pos: 0,
+            other: Some(store.namespace(DEFAULT_NAMESPACE)?),
+        };
+        assert!(matches!(
+            store.set_reader("key".to_owned(), &mut value),
+            Err(KvError::QuotaExceeded(1000))
+        ));
+        assert!(fs.read_dir(&Path::new(DIR).join("blobs"))?.is_empty());
+        assert_eq!(store.get("key".to_owned())?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn no_fault() -> Result<()> {
+        let fs = new_fs();
+        let model = run_workload(&fs, 0);
+        model.check(&reopen(&fs)?)?;
+        fs.power_loss();
+        model.check(&reopen(&fs)?)
+    }
+
+    // Kills the process at every operation of the workload in turn, with and without losing
+    // unsynced data.
+    #[test]
+    fn crash_at_every_operation() -> Result<()> {
+        let total_ops = {
+            let fs = new_fs();
+            run_workload(&fs, 1);
+            fs.ops()
+        };
+        for op in 1..=total_ops {
+            for &power_loss in &[false, true] {
+                let fs = new_fs();
+                fs.fail_at(op, true);
+                let model = run_workload(&fs, 1);
+                if power_loss {
+                    fs.power_loss();
+                } else {
+                    fs.restart();
+                }
+                let store = reopen(&fs)?;
+                model.check(&store)?;
+                // The recovered store must accept writes.
+                store.set("after".to_owned(), "crash".to_owned())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fails single operations while the store keeps serving requests.
+    #[test]
+    fn transient_faults() -> Result<()> {
+        let total_ops = {
+            let fs = new_fs();
+            run_workload(&fs, 2);
+            fs.ops()
+        };
+        for op in 1..=total_ops {
+            let fs = new_fs();
+            fs.fail_at(op, false);
+            let model = run_workload(&fs, 2);
+            fs.power_loss();
+            model.check(&reopen(&fs)?)?;
+        }
+        Ok(())
+    }
+
+    // Fails each operation of the request triggering compaction in turn, including syncing the
+    // directory once the log was replaced.
+    #[test]
+    fn faults_during_compaction() -> Result<()> {
+        for op in 1.. {
+            let fs = new_fs();
+            let store = reopen(&fs)?;
+            for i in 0..=MAX_DEAD_ENTRIES {
+                store.set("key".to_owned(), format!("old{}", i))?;
+            }
+            let start = fs.ops();
+            fs.fail_at(start + op, false);
+            let _ = store.set("key".to_owned(), "new".to_owned());
+            if fs.ops() < start + op {
+                return Ok(());
+            }
+
+            store.set("after".to_owned(), "fault".to_owned())?;
+            assert_eq!(store.get("after".to_owned())?, Some("fault".to_owned()));
+            fs.power_loss();
+            let store = reopen(&fs)?;
+            assert_eq!(store.get("after".to_owned())?, Some("fault".to_owned()));
+        }
+        Ok(())
+    }
+}
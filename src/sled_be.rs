@@ -1,38 +1,589 @@
-use crate::engine::KvsEngine;
+use crate::engine::{KvsEngine, DEFAULT_NAMESPACE};
 use crate::error::*;
-use sled::Db;
-use std::path::Path;
+use crate::limits::Limits;
+use crate::stats::Stats;
+use sled::{ConfigBuilder, Db, Event, Subscriber, Tree};
+use std::fs;
+use std::io::{Read, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+
+/// Period after which the disk usage of stores with a size limit is measured again.
+const USAGE_REFRESH_PERIOD: Duration = Duration::from_secs(1);
+
+/// Function computing the new value of `key` from its current one, if any, and the operand passed
+/// to `SledKvsEngine::merge()`.  Returning `None` removes the key.
+pub type MergeOperator = fn(key: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>>;
+
+/// Options for opening a `SledKvsEngine`.
+#[derive(Clone, Debug)]
+pub struct SledOptions {
+    /// Whether to flush after every write so that acknowledged writes survive crashes.
+    pub flush_every_write: bool,
+
+    /// Period in milliseconds of background flushes, `None` disabling them.
+    pub flush_every_ms: Option<u64>,
+
+    /// Operator applied by `SledKvsEngine::merge()`.
+    ///
+    /// sled refuses to open a store without a merge operator once it has been opened with one.
+    pub merge_operator: Option<MergeOperator>,
+
+    /// Bounds on keys, values and disk usage.
+    ///
+    /// sled preallocates space so disk usage grows by large steps.  It is measured every second
+    /// and estimated from the size of writes in between.  Values passed to
+    /// `SledKvsEngine::merge()` are bounded but the merged values are not.
+    pub limits: Limits,
+}
+
+impl Default for SledOptions {
+    fn default() -> SledOptions {
+        SledOptions {
+            flush_every_write: true,
+            flush_every_ms: Some(500),
+            merge_operator: None,
+            limits: Limits::default(),
+        }
+    }
+}
 
 /// sled key-value store wrapper.
 ///
 /// Note that sled::Db is a Sync type that is already reference-counted and thread-safe.
+///
+/// Each namespace but the default one is stored in its own sled::Tree.
 #[derive(Clone)]
-pub struct SledKvsEngine(Db);
+pub struct SledKvsEngine {
+    db: Db,
+    path: PathBuf,
+    ns: String,
+    flush_every_write: bool,
+    limits: Limits,
+    usage: Arc<Mutex<Usage>>,
+
+    /// Held exclusively while dropping trees so that looking up an existing tree does not race
+    /// with dropping it, sled creating trees it is asked to open.
+    trees: Arc<RwLock<()>>,
+}
+
+/// Disk usage of a store, shared by all handles on it.
+///
+/// Walking the store on every write would be as slow as the store is large, so usage is measured
+/// at most every `USAGE_REFRESH_PERIOD` and grown by the size of writes in between.
+#[derive(Default)]
+struct Usage {
+    bytes: u64,
+    measured: Option<Instant>,
+}
+
+impl SledKvsEngine {
+    /// Calls `f` with the tree holding the current namespace, creating it if needed.
+    ///
+    /// The tree is looked up on each call because another handle may have dropped it.
+    fn with_tree<T>(&self, f: impl FnOnce(&Tree) -> Result<T>) -> Result<T> {
+        if self.ns == DEFAULT_NAMESPACE {
+            f(&self.db)
+        } else {
+            let tree: Arc<Tree> = self.db.open_tree(self.ns.as_bytes())?;
+            f(&tree)
+        }
+    }
+
+    /// Calls `f` with the tree holding the current namespace if it exists.
+    fn with_existing_tree<T>(&self, f: impl FnOnce(&Tree) -> Result<T>) -> Result<Option<T>> {
+        if self.ns == DEFAULT_NAMESPACE {
+            return f(&self.db).map(Some);
+        }
+        match self.existing_tree(self.ns.as_bytes())? {
+            Some(tree) => f(&tree).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns tree `name` if it exists.
+    fn existing_tree(&self, name: &[u8]) -> Result<Option<Arc<Tree>>> {
+        let _trees = self.trees.read()?;
+        if self.db.tree_names().iter().any(|n| n == name) {
+            Ok(Some(self.db.open_tree(name)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Fails if setting `key` to a value of `size` bytes would break limits.
+    fn check_write(&self, key: &str, size: u64) -> Result<()> {
+        self.limits.check_key(key)?;
+        self.limits.check_value(size)?;
+        if let Some(mut usage) = self.check_usage()? {
+            usage.bytes += key.len() as u64 + size;
+        }
+        Ok(())
+    }
+
+    /// Fails if the store is full.  Returns its disk usage if it has a maximum size.
+    fn check_usage(&self) -> Result<Option<MutexGuard<'_, Usage>>> {
+        if self.limits.max_store_size.is_none() {
+            return Ok(None);
+        }
+        let mut usage = self.usage.lock()?;
+        if usage
+            .measured
+            .is_none_or(|at| at.elapsed() >= USAGE_REFRESH_PERIOD)
+        {
+            *usage = Usage {
+                bytes: dir_size(&self.path)?,
+                measured: Some(Instant::now()),
+            };
+        }
+        self.limits.check_store(usage.bytes)?;
+        Ok(Some(usage))
+    }
+
+    /// Accounts for `size` bytes written besides those passed to `check_write()`.
+    fn grow_usage(&self, size: u64) -> Result<()> {
+        if self.limits.max_store_size.is_some() {
+            self.usage.lock()?.bytes += size;
+        }
+        Ok(())
+    }
+
+    /// Flushes `tree` unless the engine relies on background flushes.
+    fn wrote(&self, tree: &Tree) -> Result<()> {
+        if self.flush_every_write {
+            tree.flush()?;
+        }
+        Ok(())
+    }
+}
 
 impl KvsEngine for SledKvsEngine {
     fn open<P: AsRef<Path>>(path: P) -> Result<SledKvsEngine> {
-        Ok(SledKvsEngine(Db::start_default(path.as_ref())?))
+        SledKvsEngine::open_with(path, SledOptions::default())
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.0.set(key.as_bytes(), value.as_bytes())?;
-        self.0.flush()?;
-        Ok(())
+        self.check_write(&key, value.len() as u64)?;
+        self.with_tree(|tree| {
+            tree.set(key.as_bytes(), value.as_bytes())?;
+            self.wrote(tree)
+        })
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        // TODO: is there a better way to convert the value to string?
-        Ok(self
-            .0
-            .get(key.as_bytes())?
-            .map(|val| String::from_utf8_lossy(val.as_ref()).to_string()))
+        let val = self.with_existing_tree(|tree| Ok(tree.get(key.as_bytes())?))?;
+        match val.flatten() {
+            Some(val) => Ok(Some(utf8_value(&key, &val)?)),
+            None => Ok(None),
+        }
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        if self.0.del(key.as_bytes())?.is_none() {
+        let removed = self.with_existing_tree(|tree| {
+            let removed = tree.del(key.as_bytes())?.is_some();
+            if removed {
+                self.wrote(tree)?;
+            }
+            Ok(removed)
+        })?;
+        if removed != Some(true) {
             return Err(KvError::KeyNotFound(key));
         }
-        self.0.flush()?;
+        Ok(())
+    }
+
+    fn namespace(&self, name: &str) -> Result<SledKvsEngine> {
+        Ok(SledKvsEngine {
+            db: self.db.clone(),
+            path: self.path.clone(),
+            ns: name.to_owned(),
+            flush_every_write: self.flush_every_write,
+            limits: self.limits,
+            usage: self.usage.clone(),
+            trees: self.trees.clone(),
+        })
+    }
+
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        // sled refuses to drop its default tree so we empty it instead.
+        if name == DEFAULT_NAMESPACE {
+            self.db.clear()?;
+        } else {
+            let _trees = self.trees.write()?;
+            self.db.drop_tree(name.as_bytes())?;
+        }
+        self.wrote(&self.db)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let default_name = self.db.name();
+        let mut names = vec![DEFAULT_NAMESPACE.to_owned()];
+        for name in self.db.tree_names() {
+            if name != default_name {
+                names.push(utf8_name(name)?);
+            }
+        }
+        Ok(names)
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let keys = self.with_existing_tree(|tree| {
+            tree.iter()
+                .keys()
+                .map(|key| utf8_name(key?))
+                .collect::<Result<Vec<String>>>()
+        })?;
+        Ok(keys.unwrap_or_default())
+    }
+
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        self.check_write(&key, 0)?;
+        // sled has no streaming interface.  One byte more than allowed is read to detect
+        // oversized values.
+        let mut buf = Vec::new();
+        let max_read = self.limits.max_value_size.map_or(u64::MAX, |max| max + 1);
+        value.take(max_read).read_to_end(&mut buf)?;
+        self.limits.check_value(buf.len() as u64)?;
+        self.grow_usage(buf.len() as u64)?;
+        self.with_tree(|tree| {
+            tree.set(key.as_bytes(), buf)?;
+            self.wrote(tree)
+        })
+    }
+
+    fn check_set(&self, key: &str) -> Result<Option<u64>> {
+        self.limits.check_key(key)?;
+        self.check_usage()?;
+        Ok(self.limits.max_value_size)
+    }
+
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        match self
+            .with_existing_tree(|tree| Ok(tree.get(key.as_bytes())?))?
+            .flatten()
+        {
+            Some(val) => {
+                wr.write_all(&val)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// sled does not expose garbage figures so only key count and disk usage are reported.
+    fn stats(&self) -> Result<Stats> {
+        let mut live_keys = self.db.len() as u64;
+        let default_name = self.db.name();
+        for name in self.db.tree_names() {
+            if name != default_name {
+                if let Some(tree) = self.existing_tree(&name)? {
+                    live_keys += tree.len() as u64;
+                }
+            }
+        }
+        Ok(Stats {
+            live_keys,
+            total_bytes: dir_size(&self.path)?,
+            dead_bytes: None,
+            dead_entries: None,
+            last_compaction: None,
+            compactions: None,
+        })
+    }
+
+    /// Writes all pending changes to disk.
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+impl SledKvsEngine {
+    /// Opens store at `path` with non-default options.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: SledOptions) -> Result<SledKvsEngine> {
+        let mut config = ConfigBuilder::new()
+            .path(path.as_ref())
+            .flush_every_ms(options.flush_every_ms);
+        if let Some(op) = options.merge_operator {
+            config = config.merge_operator(op);
+        }
+        Ok(SledKvsEngine {
+            db: Db::start(config.build())?,
+            path: path.as_ref().to_path_buf(),
+            ns: DEFAULT_NAMESPACE.to_owned(),
+            flush_every_write: options.flush_every_write,
+            limits: options.limits,
+            usage: Arc::default(),
+            trees: Arc::default(),
+        })
+    }
+
+    /// Returns all key-value pairs of the current namespace whose key is in `range`, ordered by
+    /// key.
+    pub fn range<R: RangeBounds<String>>(&self, range: R) -> Result<Vec<(String, String)>> {
+        let pairs = self.with_existing_tree(|tree| {
+            tree.range(range)
+                .map(|kv| {
+                    let (key, val) = kv?;
+                    let key = utf8_name(key)?;
+                    let val = utf8_value(&key, &val)?;
+                    Ok((key, val))
+                })
+                .collect()
+        })?;
+        Ok(pairs.unwrap_or_default())
+    }
+
+    /// Sets value of `key` to `new` if its current value is `old` and returns whether it did so.
+    ///
+    /// `None` stands for a missing key in both cases so that keys can be created and removed
+    /// conditionally.
+    pub fn compare_and_swap(
+        &self,
+        key: String,
+        old: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        if let Some(ref new) = new {
+            self.check_write(&key, new.len() as u64)?;
+        }
+        self.with_tree(|tree| {
+            let swapped = tree.cas(key.as_bytes(), old, new.map(String::into_bytes))?;
+            if swapped.is_ok() {
+                self.wrote(tree)?;
+            }
+            Ok(swapped.is_ok())
+        })
+    }
+
+    /// Combines `operand` with value of `key` using the merge operator the store was opened with.
+    pub fn merge(&self, key: String, operand: String) -> Result<()> {
+        self.check_write(&key, operand.len() as u64)?;
+        self.with_tree(|tree| {
+            tree.merge(key.as_bytes(), operand.into_bytes())?;
+            self.wrote(tree)
+        })
+    }
+
+    /// Returns iterator blocking until keys of the current namespace starting with `prefix` change.
+    pub fn watch_prefix(&self, prefix: &str) -> Result<SledWatch> {
+        self.with_tree(|tree| Ok(SledWatch(tree.watch_prefix(prefix.as_bytes().to_vec()))))
+    }
+}
+
+/// Change notified by `SledWatch`.
+#[derive(Debug, PartialEq)]
+pub enum WatchEvent {
+    /// Key was set to value.
+    Set(String, String),
+    /// Operand was merged into value of key.
+    Merge(String, String),
+    /// Key was removed.
+    Remove(String),
+}
+
+/// Blocking iterator over changes of keys, returned by `SledKvsEngine::watch_prefix()`.
+pub struct SledWatch(Subscriber);
+
+impl Iterator for SledWatch {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        let event = self.0.next()?;
+        Some(watch_event(event))
+    }
+}
+
+fn watch_event(event: Event) -> Result<WatchEvent> {
+    Ok(match event {
+        Event::Set(key, val) => {
+            let key = utf8_name(key)?;
+            let val = utf8_value(&key, &val)?;
+            WatchEvent::Set(key, val)
+        }
+        Event::Merge(key, val) => {
+            let key = utf8_name(key)?;
+            let val = utf8_value(&key, &val)?;
+            WatchEvent::Merge(key, val)
+        }
+        Event::Del(key) => WatchEvent::Remove(utf8_name(key)?),
+    })
+}
+
+/// Converts value of `key` to string.
+fn utf8_value(key: &str, val: &[u8]) -> Result<String> {
+    String::from_utf8(val.to_vec()).map_err(|_| KvError::InvalidUtf8(key.to_owned()))
+}
+
+/// Converts key or tree name to string.
+///
+/// They are always written from strings so failing is a sign of corruption.
+fn utf8_name(raw: Vec<u8>) -> Result<String> {
+    String::from_utf8(raw).map_err(|err| {
+        KvError::Other(format!(
+            "invalid UTF-8 name {}",
+            String::from_utf8_lossy(err.as_bytes())
+        ))
+    })
+}
+
+/// Returns cumulated size of all files in directory tree rooted at `path`.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        size += if md.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            md.len()
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn concatenate(_key: &[u8], old: Option<&[u8]>, operand: &[u8]) -> Option<Vec<u8>> {
+        let mut val = old.map(|old| old.to_vec()).unwrap_or_default();
+        val.extend_from_slice(operand);
+        Some(val)
+    }
+
+    #[test]
+    fn strict_utf8() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let engine = SledKvsEngine::open(tmpdir.path())?;
+        engine.set_reader("k".to_owned(), &mut &[0xffu8, 0xfe][..])?;
+        assert!(matches!(
+            engine.get("k".to_owned()),
+            Err(KvError::InvalidUtf8(ref key)) if key == "k"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let initial_size = dir_size(&SledKvsEngine::open(tmpdir.path())?.path)?;
+        let options = SledOptions {
+            limits: Limits {
+                max_key_len: Some(8),
+                max_value_size: Some(10_000),
+                max_store_size: Some(initial_size + 100_000),
+            },
+            ..SledOptions::default()
+        };
+        let engine = SledKvsEngine::open_with(tmpdir.path(), options)?;
+        assert!(matches!(
+            engine.set("k".repeat(9), "v".to_owned()),
+            Err(KvError::KeyTooLong(8))
+        ));
+        assert_eq!(engine.check_set("k")?, Some(10_000));
+        assert!(matches!(
+            engine.set_reader("k".to_owned(), &mut &[0u8; 10_001][..]),
+            Err(KvError::ValueTooLarge(10_000))
+        ));
+        assert!(matches!(
+            engine.compare_and_swap("k".to_owned(), None, Some("v".repeat(10_001))),
+            Err(KvError::ValueTooLarge(10_000))
+        ));
+
+        let mut refused = false;
+        for i in 0..1000 {
+            match engine.set(format!("k{}", i), "v".repeat(10_000)) {
+                Ok(()) => (),
+                Err(KvError::QuotaExceeded(_)) => {
+                    refused = true;
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        assert!(refused);
+        assert!(matches!(
+            engine.check_set("k"),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        // Reads and removals are still accepted.
+        assert_eq!(engine.get("k0".to_owned())?, Some("v".repeat(10_000)));
+        engine.remove("k0".to_owned())?;
+        Ok(())
+    }
+
+    #[test]
+    fn usage_estimated_between_measures() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let initial_size = dir_size(&SledKvsEngine::open(tmpdir.path())?.path)?;
+        let options = SledOptions {
+            flush_every_write: false,
+            flush_every_ms: None,
+            limits: Limits {
+                max_store_size: Some(initial_size + 50_000),
+                ..Limits::default()
+            },
+            ..SledOptions::default()
+        };
+        let engine = SledKvsEngine::open_with(tmpdir.path(), options)?;
+        // Writes are accounted for although they are not on disk yet.
+        for i in 0..5 {
+            engine.set(format!("k{}", i), "v".repeat(10_000))?;
+        }
+        assert!(matches!(
+            engine.set_reader("k".to_owned(), &mut &[0u8; 1][..]),
+            Err(KvError::QuotaExceeded(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn range_cas_merge_and_watch() -> Result<()> {
+        let tmpdir = TempDir::new()?;
+        let options = SledOptions {
+            flush_every_write: false,
+            merge_operator: Some(concatenate),
+            ..SledOptions::default()
+        };
+        let engine = SledKvsEngine::open_with(tmpdir.path(), options)?.namespace("ns")?;
+        let mut watch = engine.watch_prefix("a")?;
+
+        for key in &["c", "a", "b"] {
+            engine.set(key.to_string(), key.to_uppercase())?;
+        }
+        assert_eq!(
+            engine.range("a".to_owned().."c".to_owned())?,
+            vec![
+                ("a".to_owned(), "A".to_owned()),
+                ("b".to_owned(), "B".to_owned())
+            ]
+        );
+
+        assert!(!engine.compare_and_swap("a".to_owned(), None, Some("X".to_owned()))?);
+        assert!(engine.compare_and_swap("a".to_owned(), Some("A".to_owned()), None)?);
+        assert!(engine.compare_and_swap("a".to_owned(), None, Some("X".to_owned()))?);
+
+        engine.merge("a".to_owned(), "Y".to_owned())?;
+        assert_eq!(engine.get("a".to_owned())?, Some("XY".to_owned()));
+        engine.flush()?;
+
+        assert_eq!(
+            watch.next().unwrap()?,
+            WatchEvent::Set("a".to_owned(), "A".to_owned())
+        );
+        assert_eq!(watch.next().unwrap()?, WatchEvent::Remove("a".to_owned()));
+        assert_eq!(
+            watch.next().unwrap()?,
+            WatchEvent::Set("a".to_owned(), "X".to_owned())
+        );
+        assert_eq!(
+            watch.next().unwrap()?,
+            WatchEvent::Merge("a".to_owned(), "Y".to_owned())
+        );
         Ok(())
     }
 }
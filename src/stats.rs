@@ -0,0 +1,73 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Snapshot of store size and garbage figures returned by `KvsEngine::stats()`.
+///
+/// Figures an engine can not compute are set to `None`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Number of keys that can be read, all namespaces included.
+    pub live_keys: u64,
+
+    /// Size of on-disk data.
+    pub total_bytes: u64,
+
+    /// Size of on-disk data that compaction would reclaim.
+    pub dead_bytes: Option<u64>,
+
+    /// Number of on-disk records superseded by later updates.
+    pub dead_entries: Option<u64>,
+
+    /// Time of last compaction since the store was opened, in seconds since the UNIX epoch.
+    pub last_compaction: Option<u64>,
+
+    /// Number of compactions since the store was opened.
+    pub compactions: Option<u64>,
+}
+
+impl Stats {
+    /// Returns figures of a store made of parts whose figures are `parts`.
+    pub(crate) fn sum(parts: impl IntoIterator<Item = Stats>) -> Stats {
+        fn add(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+            Some(a? + b?)
+        }
+
+        let mut total = Stats {
+            live_keys: 0,
+            total_bytes: 0,
+            dead_bytes: Some(0),
+            dead_entries: Some(0),
+            last_compaction: None,
+            compactions: Some(0),
+        };
+        for part in parts {
+            total.live_keys += part.live_keys;
+            total.total_bytes += part.total_bytes;
+            total.dead_bytes = add(total.dead_bytes, part.dead_bytes);
+            total.dead_entries = add(total.dead_entries, part.dead_entries);
+            total.last_compaction = total.last_compaction.max(part.last_compaction);
+            total.compactions = add(total.compactions, part.compactions);
+        }
+        total
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fn opt(val: Option<u64>) -> String {
+            val.map_or_else(|| "n/a".to_owned(), |v| v.to_string())
+        }
+
+        writeln!(f, "live keys: {}", self.live_keys)?;
+        writeln!(f, "total bytes: {}", self.total_bytes)?;
+        writeln!(f, "dead bytes: {}", opt(self.dead_bytes))?;
+        writeln!(f, "dead entries: {}", opt(self.dead_entries))?;
+        writeln!(
+            f,
+            "last compaction: {}",
+            self.last_compaction
+                .map_or_else(|| "never".to_owned(), |t| t.to_string())
+        )?;
+        write!(f, "compactions: {}", opt(self.compactions))
+    }
+}
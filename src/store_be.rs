@@ -1,206 +1,1106 @@
+use log::warn;
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{self, File, OpenOptions};
-use std::io::{prelude::*, BufReader, BufWriter, ErrorKind, SeekFrom};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::io::{self, prelude::*, BufReader, BufWriter, ErrorKind, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-use tempfile::NamedTempFile;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::engine::KvsEngine;
+use crate::engine::{KvsEngine, DEFAULT_NAMESPACE};
 use crate::error::*;
+use crate::limits::Limits;
+use crate::stats::Stats;
+use crate::vfs::{OsFs, ReadFile, Vfs, WriteFile};
 
-type Index = HashMap<String, u64>;
+/// Location of a value.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ValueLoc {
+    /// Offset of the serialized value in the log.
+    Log(u64),
+
+    /// Identifier of the blob file holding the raw value.
+    Blob(u64),
+}
+
+/// Location of a live record in the log.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RecordPos {
+    value: ValueLoc,
+
+    /// Size of the whole record in the log, header included.
+    len: u64,
+}
+
+type Index = HashMap<String, RecordPos>;
+
+/// Maps namespace names to their own index.
+type Namespaces = HashMap<String, Index>;
 
 /// Thread-safe key-value store.
+///
+/// A store can be sharded: keys are then partitioned by hash across independent logs, each with
+/// its own lock and compaction, so that writes to different shards proceed in parallel.
 #[derive(Clone)]
 pub struct KvStore {
     // TODO: use RwLock instead?
-    raw: Arc<Mutex<RawStore>>,
+    /// Shards the store is made of, a single one if the store is not sharded.
+    shards: Arc<[Mutex<RawStore>]>,
+
+    /// Namespace targeted by operations on this handle.
+    ns: String,
+}
+
+/// Tunables for `KvStore::open_with()`.
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreOptions {
+    /// Reads values from a memory mapping of the log rather than with file reads.
+    ///
+    /// This speeds up read-mostly workloads.  The log must not be modified by other processes
+    /// while the store is open.
+    pub mmap: bool,
+
+    /// Bounds on keys, values and log plus blob sizes.
+    ///
+    /// Each shard of a sharded store gets an equal part of the maximum store size.
+    pub limits: Limits,
+
+    /// Number of shards of a store created by `KvStore::open_with()`.  Values below 2 create an
+    /// unsharded store.
+    ///
+    /// Existing stores keep the number of shards they were created with.
+    pub shards: usize,
 }
 
 /// Store data shared between worker threads.
-#[derive(Clone)]
 struct RawStore {
+    vfs: Arc<dyn Vfs>,
+
     filename: PathBuf,
-    map: Index,
+
+    /// Length of the log up to the end of the last record fully written.
+    log_len: u64,
+
+    /// Whether a failed append may have left a partial record after `log_len`.
+    torn_tail: bool,
+
+    /// Whether compaction replaced the log but failed to make the replacement durable.
+    unsynced_log_dir: bool,
+
+    /// Directory holding values stored out of line.
+    blob_dir: PathBuf,
+
+    /// Identifier of the next blob to create.
+    next_blob_id: u64,
+
+    /// Cumulated size of live blobs.
+    blob_bytes: u64,
+
+    limits: Limits,
+
+    /// Mapping of the log if enabled.
+    ///
+    /// The log is append-only so mapped bytes never change.  Records appended after the mapping
+    /// was created are mapped lazily on first access.  Compaction replaces the log and so
+    /// remaps it.
+    mmap: Option<Mmap>,
+
+    namespaces: Namespaces,
     dead_entries: i32,
+
+    /// Number of compactions since the store was opened.
+    compactions: u64,
+
+    /// Time of last compaction, if any.
+    last_compaction: Option<SystemTime>,
 }
 
+/// File holding the number of shards of sharded stores.
+const SHARDS_FILE: &str = "shards";
+
+/// Identifies files holding a `KvStore` log.
+const MAGIC: &str = "pna-kvs";
+
+/// Version of the on-disk format written by this code.
+///
+/// Version 1 is the original layout: JSON records without file header.  Version 2 adds the file
+/// header.  Version 3 adds blob records.
+pub(crate) const FORMAT_VERSION: u32 = 3;
+
+/// Values at least this large, or that are not valid UTF-8, are stored out of line in blob files.
+pub(crate) const BLOB_THRESHOLD: usize = 64 * 1024;
+
+/// First line of log files since format version 2.
 #[derive(Serialize, Deserialize, Debug)]
-enum Tag {
+struct FileHeader {
+    magic: String,
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub(crate) enum Tag {
     Set,
     Rm,
+    /// Removes all keys in namespace.
+    Drop,
+    /// Sets value to content of blob file with given identifier.
+    SetBlob(u64),
 }
 
+// Strings are borrowed from the serialized header unless they contain escape sequences.
 #[derive(Serialize, Deserialize, Debug)]
-struct Header<'a> {
-    tag: Tag,
-    key: &'a str,
-    value_size: usize,
+pub(crate) struct Header<'a> {
+    pub tag: Tag,
+    // Omitted for the default namespace so that logs written before namespaces existed are still
+    // readable.
+    #[serde(borrow, default, skip_serializing_if = "is_default_namespace")]
+    pub ns: Cow<'a, str>,
+    #[serde(borrow)]
+    pub key: Cow<'a, str>,
+    pub value_size: usize,
+}
+
+// serde hands a reference to the field itself.
+#[allow(clippy::ptr_arg)]
+fn is_default_namespace(ns: &Cow<str>) -> bool {
+    ns == DEFAULT_NAMESPACE
 }
 
-const MAX_DEAD_ENTRIES: i32 = 64;
+pub(crate) const MAX_DEAD_ENTRIES: i32 = 64;
 
 impl KvsEngine for KvStore {
+    const FORMAT_VERSION: u32 = FORMAT_VERSION;
+
     fn open<P: AsRef<Path>>(path: P) -> Result<KvStore> {
-        RawStore::open(path).map(|raw| KvStore {
-            raw: Arc::new(Mutex::new(raw)),
-        })
+        KvStore::open_with(path, KvStoreOptions::default())
     }
 
     fn set(&self, key: String, value: String) -> Result<()> {
-        self.raw.lock()?.set(key, value)
+        if value.len() >= BLOB_THRESHOLD {
+            return self.set_reader(key, &mut value.as_bytes());
+        }
+        self.shard(&key).lock()?.set(&self.ns, key, value)
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
-        self.raw.lock()?.get(key)
+        self.shard(&key).lock()?.get(&self.ns, key)
     }
 
     fn remove(&self, key: String) -> Result<()> {
-        self.raw.lock()?.remove(key)
+        self.shard(&key).lock()?.remove(&self.ns, key)
+    }
+
+    fn namespace(&self, name: &str) -> Result<KvStore> {
+        Ok(KvStore {
+            shards: self.shards.clone(),
+            ns: name.to_owned(),
+        })
+    }
+
+    /// The namespace is dropped from all shards at once as seen by other threads.  A crash may
+    /// however leave it dropped from some shards only.
+    fn drop_namespace(&self, name: &str) -> Result<()> {
+        for mut raw in self.lock_all()? {
+            raw.drop_namespace(name)?;
+        }
+        Ok(())
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut names = HashSet::new();
+        for raw in self.lock_all()? {
+            names.extend(raw.namespaces.keys().cloned());
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for raw in self.lock_all()? {
+            if let Some(index) = raw.namespaces.get(&self.ns) {
+                keys.extend(index.keys().cloned());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let parts = self
+            .lock_all()?
+            .iter()
+            .map(|raw| raw.stats())
+            .collect::<Result<Vec<Stats>>>()?;
+        Ok(Stats::sum(parts))
+    }
+
+    fn set_reader(&self, key: String, value: &mut dyn Read) -> Result<()> {
+        let mut head = Vec::new();
+        value.take(BLOB_THRESHOLD as u64).read_to_end(&mut head)?;
+        if head.len() < BLOB_THRESHOLD {
+            match String::from_utf8(head) {
+                Ok(small) => return self.shard(&key).lock()?.set(&self.ns, key, small),
+                Err(err) => head = err.into_bytes(),
+            }
+        }
+
+        // The lock is not held while writing the blob so that other requests are not blocked.
+        let (id, path, vfs, limits) = {
+            let mut raw = self.shard(&key).lock()?;
+            // Fail early rather than after writing the whole blob.
+            raw.check_write(&key)?;
+            let (id, path) = raw.new_blob();
+            (id, path, raw.vfs.clone(), raw.limits)
+        };
+        // Read one byte more than allowed to detect oversized values.
+        let mut tail = value.take(limits.max_value_size.map_or(u64::MAX, |max| max + 1));
+        let size = match write_blob(&*vfs, &path, &head, &mut tail)
+            .and_then(|size| limits.check_value(size).map(|_| size))
+        {
+            Ok(size) => size,
+            Err(err) => {
+                let _ = vfs.remove_file(&path);
+                return Err(err);
+            }
+        };
+        self.shard(&key).lock()?.set_blob(&self.ns, key, id, size)
+    }
+
+    fn check_set(&self, key: &str) -> Result<Option<u64>> {
+        let raw = self.shard(key).lock()?;
+        raw.check_write(key)?;
+        Ok(raw.limits.max_value_size)
+    }
+
+    fn get_writer(&self, key: String, wr: &mut dyn Write) -> Result<bool> {
+        let mut raw = self.shard(&key).lock()?;
+        match raw.lookup(&self.ns, &key) {
+            Some(ValueLoc::Log(off)) => {
+                let val = raw.read_value(off)?;
+                drop(raw);
+                wr.write_all(val.as_bytes())?;
+            }
+            Some(ValueLoc::Blob(id)) => {
+                // The open file remains readable even if the blob is deleted once unlocked.
+                let mut file = raw.open_blob(id)?;
+                drop(raw);
+                io::copy(&mut file, wr)?;
+            }
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl KvStore {
+    /// Opens store at `path` with non-default options.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: KvStoreOptions) -> Result<KvStore> {
+        KvStore::open_with_vfs(path, options, Arc::new(OsFs))
+    }
+
+    /// Opens store at `path` accessing files through `vfs`.
+    pub(crate) fn open_with_vfs<P: AsRef<Path>>(
+        path: P,
+        options: KvStoreOptions,
+        vfs: Arc<dyn Vfs>,
+    ) -> Result<KvStore> {
+        let path = path.as_ref();
+        let shards = match read_shard_count(&*vfs, path)? {
+            Some(count) => {
+                // An earlier creation may have failed to make the shard count durable.
+                vfs.sync_dir(path)?;
+                open_shards(path, count, &options, &vfs)?
+            }
+            None if options.shards > 1 && vfs.len(&path.join("kv.db")).is_err() => {
+                let shards = open_shards(path, options.shards, &options, &vfs)?;
+                // Written last so that a store whose creation failed can be created again.
+                write_shard_count(&*vfs, path, options.shards)?;
+                shards
+            }
+            None => vec![Mutex::new(RawStore::open(path, &options, vfs)?)],
+        };
+        Ok(KvStore {
+            shards: shards.into(),
+            ns: DEFAULT_NAMESPACE.to_owned(),
+        })
+    }
+
+    /// Returns number of shards, 1 if the store is not sharded.
+    pub(crate) fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns shard holding `key`.
+    fn shard(&self, key: &str) -> &Mutex<RawStore> {
+        &self.shards[(fnv1a(key.as_bytes()) % self.shards.len() as u64) as usize]
+    }
+
+    /// Locks all shards, always in the same order so that concurrent callers do not deadlock.
+    fn lock_all(&self) -> Result<Vec<MutexGuard<'_, RawStore>>> {
+        self.shards.iter().map(|shard| Ok(shard.lock()?)).collect()
+    }
+
+    /// Returns version of the on-disk format of store at `path`, that of its oldest shard if it is
+    /// sharded.  Missing stores are created in the current format.
+    pub fn format_version<P: AsRef<Path>>(path: P) -> Result<u32> {
+        if let Some(count) = read_shard_count(&OsFs, path.as_ref())? {
+            let mut oldest = FORMAT_VERSION;
+            for i in 0..count {
+                oldest = oldest.min(KvStore::format_version(shard_path(path.as_ref(), i))?);
+            }
+            return Ok(oldest);
+        }
+
+        match OsFs.open_read(&path.as_ref().join("kv.db")) {
+            Ok(file) => read_format_version(&mut BufReader::new(file)),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(FORMAT_VERSION),
+            Err(err) => Err(KvError::Io(err)),
+        }
+    }
+
+    /// Migrates store at `path` to the current on-disk format.
+    ///
+    /// The store must not be opened concurrently.  Returns the format version found before
+    /// migration.
+    pub fn upgrade<P: AsRef<Path>>(path: P) -> Result<u32> {
+        if let Some(count) = read_shard_count(&OsFs, path.as_ref())? {
+            let mut oldest = FORMAT_VERSION;
+            for i in 0..count {
+                oldest = oldest.min(KvStore::upgrade(shard_path(path.as_ref(), i))?);
+            }
+            return Ok(oldest);
+        }
+
+        let filename = path.as_ref().join("kv.db");
+        let version = match OsFs.open_read(&filename) {
+            Ok(file) => read_format_version(&mut BufReader::new(file))?,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(FORMAT_VERSION),
+            Err(err) => return Err(KvError::Io(err)),
+        };
+
+        match version {
+            FORMAT_VERSION => (),
+            // Older versions differ from the current one by their file header only.
+            1 | 2 => rewrite_file_header(&OsFs, &filename)?,
+            _ => return Err(KvError::UnsupportedFormat(version)),
+        }
+
+        Ok(version)
     }
 }
 
 impl RawStore {
-    fn open<P: AsRef<Path>>(path: P) -> Result<RawStore> {
+    fn open<P: AsRef<Path>>(
+        path: P,
+        options: &KvStoreOptions,
+        vfs: Arc<dyn Vfs>,
+    ) -> Result<RawStore> {
         let filename = path.as_ref().join("kv.db");
-        let (map, dead_entries) = load_map_from(&filename)?;
+        create_log_if_missing(&*vfs, &filename)?;
+        let (namespaces, dead_entries, log_len) = load_map_from(&*vfs, &filename)?;
+        if log_len < vfs.len(&filename)? {
+            warn!("discarding partial record at end of log");
+            vfs.truncate(&filename, log_len)?;
+        }
+        let blob_dir = path.as_ref().join("blobs");
+        let next_blob_id = sweep_blobs(&*vfs, &blob_dir, &namespaces)?;
+        let mut blob_bytes = 0;
+        for index in namespaces.values() {
+            for pos in index.values() {
+                if let ValueLoc::Blob(id) = pos.value {
+                    // Only the keys whose blob is missing are unreadable, until `kvs fsck` drops
+                    // them.
+                    match vfs.len(&blob_dir.join(id.to_string())) {
+                        Ok(len) => blob_bytes += len,
+                        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                            warn!("blob {} is missing", id)
+                        }
+                        Err(err) => return Err(KvError::Io(err)),
+                    }
+                }
+            }
+        }
+        let mmap = if options.mmap {
+            Some(vfs.map(&filename)?)
+        } else {
+            None
+        };
         Ok(RawStore {
+            vfs,
             filename,
-            map,
+            log_len,
+            torn_tail: false,
+            unsynced_log_dir: false,
+            blob_dir,
+            next_blob_id,
+            blob_bytes,
+            limits: options.limits,
+            mmap,
+            namespaces,
             dead_entries,
+            compactions: 0,
+            last_compaction: None,
         })
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
+    fn set(&mut self, ns: &str, key: String, value: String) -> Result<()> {
+        self.check_write(&key)?;
+        self.limits.check_value(value.len() as u64)?;
         // Update the in-ram map if and only if on-disk log updated.
-        let off = append_to_log(&self.filename, Tag::Set, &key, Some(&value))?;
-        if self.map.insert(key, off).is_some() {
-            self.add_dead_entry()?;
+        let pos = self.append(Tag::Set, ns, &key, Some(&value))?;
+        self.insert(ns, key, pos)
+    }
+
+    /// Reserves identifier for new blob and returns it along with the blob path.
+    fn new_blob(&mut self) -> (u64, PathBuf) {
+        let id = self.next_blob_id;
+        self.next_blob_id += 1;
+        (id, self.blob_path(id))
+    }
+
+    /// Sets value of `key` to blob `id` of `size` bytes which must have been fully written.
+    ///
+    /// The blob is removed if no record ends up referencing it, for instance because the store
+    /// filled up while it was written.
+    fn set_blob(&mut self, ns: &str, key: String, id: u64, size: u64) -> Result<()> {
+        let appended = self
+            .check_write(&key)
+            .and_then(|_| self.append(Tag::SetBlob(id), ns, &key, None));
+        let pos = match appended {
+            Ok(pos) => pos,
+            Err(err) => {
+                let _ = self.vfs.remove_file(&self.blob_path(id));
+                return Err(err);
+            }
+        };
+        self.blob_bytes += size;
+        self.insert(ns, key, pos)
+    }
+
+    /// Fails if setting `key` would break limits on keys or on store size.
+    fn check_write(&self, key: &str) -> Result<()> {
+        self.limits.check_key(key)?;
+        self.limits.check_store(self.log_len + self.blob_bytes)
+    }
+
+    fn insert(&mut self, ns: &str, key: String, pos: RecordPos) -> Result<()> {
+        let index = self.namespaces.entry(ns.to_owned()).or_default();
+        if let Some(old_pos) = index.insert(key, pos) {
+            self.discard(old_pos);
+            self.add_dead_entries(1)?;
         }
         Ok(())
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(match self.map.get(&key) {
-            Some(off) => Some(self.read_value_from_log(*off)?),
+    fn lookup(&self, ns: &str, key: &str) -> Option<ValueLoc> {
+        self.namespaces
+            .get(ns)
+            .and_then(|index| index.get(key))
+            .map(|pos| pos.value)
+    }
+
+    fn get(&mut self, ns: &str, key: String) -> Result<Option<String>> {
+        Ok(match self.lookup(ns, &key) {
+            Some(ValueLoc::Log(off)) => Some(self.read_value(off)?),
+            Some(ValueLoc::Blob(id)) => {
+                let mut raw_val = Vec::new();
+                self.open_blob(id)?.read_to_end(&mut raw_val)?;
+                Some(String::from_utf8(raw_val).map_err(|_| KvError::InvalidUtf8(key))?)
+            }
             None => None,
         })
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.map.get(&key) {
-            Some(_) => {
-                // Update the in-ram map if and only if on-disk log updated.
-                append_to_log(&self.filename, Tag::Rm, &key, None).and_then(|_| {
-                    if self.map.remove(&key).is_some() {
-                        self.add_dead_entry()?;
+    fn remove(&mut self, ns: &str, key: String) -> Result<()> {
+        if self.lookup(ns, &key).is_none() {
+            return Err(KvError::KeyNotFound(key));
+        }
+        // Update the in-ram map if and only if on-disk log updated.
+        self.append(Tag::Rm, ns, &key, None)?;
+        let removed = self
+            .namespaces
+            .get_mut(ns)
+            .and_then(|index| index.remove(&key));
+        if let Some(old_pos) = removed {
+            self.discard(old_pos);
+        }
+        self.add_dead_entries(1)
+    }
+
+    fn drop_namespace(&mut self, ns: &str) -> Result<()> {
+        // Keys set by failed writes would otherwise survive the drop.
+        if self.torn_tail {
+            self.truncate_torn_tail()?;
+        }
+        if self.namespaces.contains_key(ns) {
+            // Update the in-ram map if and only if on-disk log updated.
+            self.append(Tag::Drop, ns, "", None)?;
+            let index = self.namespaces.remove(ns).unwrap_or_default();
+            for pos in index.values() {
+                self.discard(*pos);
+            }
+            self.add_dead_entries(index.len() as i32)?;
+        }
+        Ok(())
+    }
+
+    /// Releases resources held by value superseded by a record already in the log.
+    fn discard(&mut self, pos: RecordPos) {
+        if let ValueLoc::Blob(id) = pos.value {
+            let path = self.blob_path(id);
+            let size = self.vfs.len(&path).unwrap_or(0);
+            // Leftovers are swept when the store is next opened.
+            match self.vfs.remove_file(&path) {
+                Ok(()) => self.blob_bytes -= size,
+                Err(err) => warn!("failed to remove blob {}: {}", id, err),
+            }
+        }
+    }
+
+    fn blob_path(&self, id: u64) -> PathBuf {
+        self.blob_dir.join(id.to_string())
+    }
+
+    fn open_blob(&self, id: u64) -> Result<Box<dyn ReadFile>> {
+        self.vfs.open_read(&self.blob_path(id)).map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                KvError::Io(io::Error::new(
+                    ErrorKind::NotFound,
+                    format!(
+                        "blob {} is missing (check and repair the store with `kvs fsck`)",
+                        id
+                    ),
+                ))
+            } else {
+                KvError::Io(err)
+            }
+        })
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let mut total_bytes = match self.vfs.len(&self.filename) {
+            Ok(len) => len,
+            Err(ref err) if err.kind() == ErrorKind::NotFound => 0,
+            Err(err) => return Err(KvError::Io(err)),
+        };
+        for path in read_blob_dir(&*self.vfs, &self.blob_dir)? {
+            total_bytes += self.vfs.len(&path)?;
+        }
+
+        let mut live_keys = 0;
+        let mut live_bytes = file_header_line()?.len() as u64;
+        for index in self.namespaces.values() {
+            live_keys += index.len() as u64;
+            for pos in index.values() {
+                live_bytes += pos.len;
+                if let ValueLoc::Blob(id) = pos.value {
+                    match self.vfs.len(&self.blob_path(id)) {
+                        Ok(len) => live_bytes += len,
+                        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+                        Err(err) => return Err(KvError::Io(err)),
                     }
-                    Ok(())
-                })
+                }
             }
-            None => Err(KvError::KeyNotFound(key)),
+        }
+        Ok(Stats {
+            live_keys,
+            total_bytes,
+            dead_bytes: Some(total_bytes.saturating_sub(live_bytes)),
+            dead_entries: Some(self.dead_entries as u64),
+            last_compaction: self.last_compaction.map(|t| {
+                t.duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+            }),
+            compactions: Some(self.compactions),
+        })
+    }
+
+    /// Reads value stored in the log at offset `off`.
+    fn read_value(&mut self, off: u64) -> Result<String> {
+        if self.mmap.is_some() {
+            self.read_value_from_map(off)
+        } else {
+            self.read_value_from_log(off)
         }
     }
 
     fn read_value_from_log(&self, off: u64) -> Result<String> {
-        let file = OpenOptions::new().read(true).open(&self.filename)?;
-        let mut rd = BufReader::new(&file);
-        self.read_value_from_open_log(&mut rd, off)
+        let mut rd = BufReader::new(self.vfs.open_read(&self.filename)?);
+        read_value_from_open_log(&mut rd, off)
+    }
+
+    fn read_value_from_map(&mut self, off: u64) -> Result<String> {
+        let off = off as usize;
+        if off >= self.mmap.as_ref().map_or(0, |mmap| mmap.len()) {
+            // The value was appended after the log was mapped.  Records are written in full
+            // before being indexed so the new mapping covers the whole value.
+            self.mmap = Some(self.vfs.map(&self.filename)?);
+        }
+        let mmap = self.mmap.as_ref().expect("mmap enabled");
+        let len = mmap[off..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| KvError::Other(format!("unterminated value at offset {}", off)))?;
+        serde_json::from_slice(&mmap[off..off + len]).map_err(KvError::Serde)
+    }
+
+    /// Appends record to the log and makes it durable.
+    fn append(
+        &mut self,
+        tag: Tag,
+        ns: &str,
+        key: &str,
+        val_opt: Option<&str>,
+    ) -> Result<RecordPos> {
+        if self.torn_tail {
+            self.truncate_torn_tail()?;
+        }
+        let result = self.try_append(tag, ns, key, val_opt);
+        if result.is_err() {
+            self.torn_tail = true;
+            // Otherwise the record may reappear when the store is next opened.
+            if let Err(err) = self.truncate_torn_tail() {
+                warn!("failed to remove partial record from log: {}", err);
+            }
+        }
+        result
+    }
+
+    fn try_append(
+        &mut self,
+        tag: Tag,
+        ns: &str,
+        key: &str,
+        val_opt: Option<&str>,
+    ) -> Result<RecordPos> {
+        if self.unsynced_log_dir {
+            // Records appended to a log lost on power loss would be lost with it.
+            if let Some(dir) = self.filename.parent() {
+                self.vfs.sync_dir(dir)?;
+            }
+            self.unsynced_log_dir = false;
+        }
+        let mut wr = LogWriter::new(self.vfs.open_append(&self.filename)?, self.log_len);
+        let pos = append_to_open_log(&mut wr, tag, ns, key, val_opt)?;
+        wr.sync()?;
+        self.log_len = wr.pos();
+        Ok(pos)
     }
 
-    fn read_value_from_open_log(&self, rd: &mut BufReader<&File>, off: u64) -> Result<String> {
-        rd.seek(SeekFrom::Start(off))?;
-        let mut ser_val = String::new();
-        rd.read_line(&mut ser_val)?;
-        // TODO: For some reason the conversion from serde o KvError does not kick in here hence
-        // the map_err() call.
-        serde_json::from_str(&ser_val).map_err(KvError::Serde)
+    /// Removes bytes written by failed appends.
+    ///
+    /// Records appended after a partial one would be unreachable.
+    fn truncate_torn_tail(&mut self) -> Result<()> {
+        self.vfs.truncate(&self.filename, self.log_len)?;
+        self.torn_tail = false;
+        if self.mmap.is_some() {
+            self.mmap = Some(self.vfs.map(&self.filename)?);
+        }
+        Ok(())
     }
 
-    fn add_dead_entry(&mut self) -> Result<()> {
-        self.dead_entries += 1;
+    fn add_dead_entries(&mut self, n: i32) -> Result<()> {
+        self.dead_entries += n;
         if self.dead_entries > MAX_DEAD_ENTRIES {
-            self.compact_log()?;
+            // The record triggering compaction is already durable so failing to compact must not
+            // fail the request.  Compaction is attempted again on the next dead entry.
+            if let Err(err) = self.compact_log() {
+                warn!("log compaction failed: {}", err);
+            }
         }
         Ok(())
     }
 
     fn compact_log(&mut self) -> Result<()> {
-        let tmp_file = NamedTempFile::new_in(".")?;
-        let mut tmp_wr = BufWriter::new(tmp_file.as_file());
+        let tmp_filename = tmp_path(&self.filename);
+        let result = self.write_compacted_log(&tmp_filename);
+        if result.is_err() {
+            let _ = self.vfs.remove_file(&tmp_filename);
+        }
+        result
+    }
 
-        let old_file = File::open(&self.filename)?;
-        let mut old_rd = BufReader::new(&old_file);
+    /// Writes live records to `tmp_filename` and replaces the log with it.
+    fn write_compacted_log(&mut self, tmp_filename: &Path) -> Result<()> {
+        let mut tmp_wr = LogWriter::new(self.vfs.create(tmp_filename)?, 0);
+        write_file_header(&mut tmp_wr)?;
 
-        let mut new_map = Index::new();
-        for (key, off) in &self.map {
-            let val = self.read_value_from_open_log(&mut old_rd, *off)?;
-            let new_off = append_to_open_log(&mut tmp_wr, Tag::Set, &key, Some(&val))?;
-            // TODO: move keys from old map rather than clone them.
-            new_map.insert(key.to_string(), new_off);
+        let mut old_rd = BufReader::new(self.vfs.open_read(&self.filename)?);
+
+        let mut new_namespaces = Namespaces::new();
+        for (ns, index) in &self.namespaces {
+            let mut new_index = Index::new();
+            for (key, pos) in index {
+                let new_pos = match pos.value {
+                    ValueLoc::Log(off) => {
+                        let val = read_value_from_open_log(&mut old_rd, off)?;
+                        append_to_open_log(&mut tmp_wr, Tag::Set, ns, key, Some(&val))?
+                    }
+                    // Blobs are left in place.
+                    ValueLoc::Blob(id) => {
+                        append_to_open_log(&mut tmp_wr, Tag::SetBlob(id), ns, key, None)?
+                    }
+                };
+                // TODO: move keys from old map rather than clone them.
+                new_index.insert(key.to_string(), new_pos);
+            }
+            new_namespaces.insert(ns.to_string(), new_index);
         }
 
-        fs::rename(tmp_file.path(), &self.filename)?;
-        self.map = new_map;
+        tmp_wr.sync()?;
+        let renamed = self.vfs.rename(tmp_filename, &self.filename);
+        if renamed.is_err() {
+            // The rename may have taken effect before syncing the directory failed, in which case
+            // the log must be considered replaced.
+            match self.vfs.len(tmp_filename) {
+                Err(ref err) if err.kind() == ErrorKind::NotFound => self.unsynced_log_dir = true,
+                _ => return renamed.map_err(KvError::Io),
+            }
+        }
+        self.log_len = tmp_wr.pos();
+        self.torn_tail = false;
+        self.namespaces = new_namespaces;
         self.dead_entries = 0;
+        self.compactions += 1;
+        self.last_compaction = Some(SystemTime::now());
+        if self.mmap.is_some() {
+            self.mmap = Some(self.vfs.map(&self.filename)?);
+        }
 
-        Ok(())
+        renamed.map_err(KvError::Io)
     }
 }
 
-fn load_map_from(path: &Path) -> Result<(Index, i32)> {
-    let mut kvs = HashMap::new();
+/// Loads index from log at `path` and returns it along with the number of dead entries and the
+/// length of the log up to the end of its last complete record.
+fn load_map_from(vfs: &dyn Vfs, path: &Path) -> Result<(Namespaces, i32, u64)> {
+    let mut kvs = Namespaces::new();
     let mut dead_entries = 0;
 
-    let file = match OpenOptions::new().read(true).open(&path) {
-        Ok(f) => f,
-        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok((kvs, dead_entries)),
-        Err(err) => return Err(KvError::Io(err)),
-    };
-    let mut rd = BufReader::new(&file);
+    let len = vfs.len(path)?;
+    let mut rd = BufReader::new(vfs.open_read(path)?);
+
+    let version = read_format_version(&mut rd)?;
+    if version != FORMAT_VERSION {
+        return Err(KvError::UnsupportedFormat(version));
+    }
 
+    let mut off = rd.stream_position()?;
+    let mut ser_hdr = Vec::new();
     loop {
-        let mut ser_hdr = String::new();
-        match rd.read_line(&mut ser_hdr) {
-            Ok(0) => break,
-            Err(err) => return Err(KvError::Io(err)),
-            _ => (),
+        ser_hdr.clear();
+        if rd.read_until(b'\n', &mut ser_hdr)? == 0 {
+            break;
+        }
+        // A crash while appending can only cut the last record short.
+        if ser_hdr.last() != Some(&b'\n') {
+            break;
+        }
+        let hdr =
+            serde_json::from_slice::<Header>(&ser_hdr).map_err(|_| KvError::CorruptLog(off))?;
+        let value_off = off + ser_hdr.len() as u64;
+        let end = value_off.checked_add(hdr.value_size as u64);
+        let end = match end {
+            Some(end) if end <= len => end,
+            // Values are serialized on a single line so a value cut short by a crash is not
+            // followed by anything.  Otherwise value_size is corrupt and truncating the log
+            // would discard every record after it.
+            _ => {
+                let mut rest = Vec::new();
+                rd.read_until(b'\n', &mut rest)?;
+                if rest.last() == Some(&b'\n') {
+                    return Err(KvError::CorruptLog(off));
+                }
+                break;
+            }
         };
-        match serde_json::from_str::<Header>(&ser_hdr) {
-            Ok(hdr) => match hdr.tag {
-                Tag::Set => {
-                    let off = rd.seek(SeekFrom::Current(0))?;
-                    rd.seek(SeekFrom::Current(hdr.value_size as i64))?;
-                    if kvs.insert(hdr.key.to_string(), off).is_some() {
-                        dead_entries += 1;
-                    }
+        match hdr.tag {
+            Tag::Set | Tag::SetBlob(_) => {
+                let pos = RecordPos {
+                    value: match hdr.tag {
+                        Tag::SetBlob(id) => ValueLoc::Blob(id),
+                        _ => ValueLoc::Log(value_off),
+                    },
+                    len: end - off,
+                };
+                let index = kvs.entry(hdr.ns.into_owned()).or_default();
+                if index.insert(hdr.key.into_owned(), pos).is_some() {
+                    dead_entries += 1;
                 }
-                Tag::Rm => {
-                    if kvs.remove(hdr.key).is_some() {
-                        dead_entries += 1;
-                    }
+            }
+            Tag::Rm => {
+                let removed = kvs
+                    .get_mut(&*hdr.ns)
+                    .and_then(|index| index.remove(&*hdr.key));
+                if removed.is_some() {
+                    dead_entries += 1;
                 }
-            },
-            Err(err) => return Err(KvError::Serde(err)),
+            }
+            Tag::Drop => {
+                if let Some(index) = kvs.remove(&*hdr.ns) {
+                    dead_entries += index.len() as i32;
+                }
+            }
+        }
+        rd.seek_relative(hdr.value_size as i64)?;
+        off = end;
+    }
+
+    Ok((kvs, dead_entries, off))
+}
+
+fn read_value_from_open_log(rd: &mut (impl BufRead + Seek), off: u64) -> Result<String> {
+    rd.seek(SeekFrom::Start(off))?;
+    let mut ser_val = String::new();
+    rd.read_line(&mut ser_val)?;
+    // TODO: For some reason the conversion from serde o KvError does not kick in here hence
+    // the map_err() call.
+    serde_json::from_str(&ser_val).map_err(KvError::Serde)
+}
+
+fn read_blob_dir(vfs: &dyn Vfs, dir: &Path) -> Result<Vec<PathBuf>> {
+    match vfs.read_dir(dir) {
+        Ok(paths) => Ok(paths),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(KvError::Io(err)),
+    }
+}
+
+/// Deletes blobs not referenced from `namespaces` and returns identifier of next blob.
+///
+/// Unreferenced blobs are left behind when the store stops while writing a blob or before
+/// discarding a superseded one.
+fn sweep_blobs(vfs: &dyn Vfs, dir: &Path, namespaces: &Namespaces) -> Result<u64> {
+    let live: HashSet<u64> = namespaces
+        .values()
+        .flat_map(|index| index.values())
+        .filter_map(|pos| match pos.value {
+            ValueLoc::Blob(id) => Some(id),
+            ValueLoc::Log(_) => None,
+        })
+        .collect();
+
+    let mut next_id = live.iter().max().map_or(0, |id| id + 1);
+    for path in read_blob_dir(vfs, dir)? {
+        match path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse::<u64>().ok())
+        {
+            Some(id) if live.contains(&id) => (),
+            Some(id) => {
+                vfs.remove_file(&path)?;
+                next_id = next_id.max(id + 1);
+            }
+            None => warn!("unexpected file in blob directory: {}", path.display()),
         }
     }
 
-    Ok((kvs, dead_entries))
+    Ok(next_id)
+}
+
+/// Opens `count` shards of store at `path`.
+fn open_shards(
+    path: &Path,
+    count: usize,
+    options: &KvStoreOptions,
+    vfs: &Arc<dyn Vfs>,
+) -> Result<Vec<Mutex<RawStore>>> {
+    let mut options = options.clone();
+    options.limits.max_store_size = options.limits.max_store_size.map(|max| max / count as u64);
+    (0..count)
+        .map(|i| {
+            let shard_path = shard_path(path, i);
+            vfs.create_dir_all(&shard_path)?;
+            Ok(Mutex::new(RawStore::open(
+                shard_path,
+                &options,
+                vfs.clone(),
+            )?))
+        })
+        .collect()
 }
 
-fn append_to_log(path: &Path, tag: Tag, key: &str, val_opt: Option<&str>) -> Result<u64> {
-    let file = OpenOptions::new().append(true).create(true).open(path)?;
-    let mut wr = BufWriter::new(&file);
-    append_to_open_log(&mut wr, tag, key, val_opt)
+pub(crate) fn shard_path(path: &Path, i: usize) -> PathBuf {
+    path.join(format!("shard-{}", i))
 }
 
-fn append_to_open_log(
-    wr: &mut BufWriter<&File>,
+/// Returns number of shards of store at `path` or `None` if it is not sharded.
+pub(crate) fn read_shard_count(vfs: &dyn Vfs, path: &Path) -> Result<Option<usize>> {
+    let mut file = match vfs.open_read(&path.join(SHARDS_FILE)) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(KvError::Io(err)),
+    };
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    match content.trim().parse() {
+        Ok(count) if count > 0 => Ok(Some(count)),
+        _ => Err(KvError::Other(format!(
+            "invalid shard count: {:?}",
+            content
+        ))),
+    }
+}
+
+fn write_shard_count(vfs: &dyn Vfs, path: &Path, count: usize) -> Result<()> {
+    let filename = path.join(SHARDS_FILE);
+    let tmp_filename = tmp_path(&filename);
+    let mut wr = vfs.create(&tmp_filename)?;
+    writeln!(wr, "{}", count)?;
+    wr.sync()?;
+    vfs.rename(&tmp_filename, &filename)?;
+    Ok(())
+}
+
+/// 64-bit FNV-1a hash.
+///
+/// Keys must map to the same shard across runs and Rust versions, which `std` hashers do not
+/// guarantee.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Writes `head` followed by everything read from `tail` to new blob file at `path` and returns
+/// its size.
+fn write_blob(vfs: &dyn Vfs, path: &Path, head: &[u8], tail: &mut dyn Read) -> Result<u64> {
+    if let Some(dir) = path.parent() {
+        vfs.create_dir_all(dir)?;
+    }
+    let mut wr = LogWriter::new(vfs.create_new(path)?, 0);
+    wr.write_all(head)?;
+    io::copy(tail, &mut wr)?;
+    wr.sync()?;
+    Ok(wr.pos())
+}
+
+/// Returns path of the temporary file used to replace file at `path`.
+///
+/// It is in the same directory so that renaming it over `path` is atomic.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// Creates empty log holding only the file header unless it exists already.
+fn create_log_if_missing(vfs: &dyn Vfs, path: &Path) -> Result<()> {
+    match vfs.len(path) {
+        // An earlier attempt may have failed to make the log durable.
+        Ok(len) if len > 0 => return Ok(vfs.sync_dir(path.parent().unwrap_or(Path::new(".")))?),
+        // Left behind by a crash before anything was written to the log.
+        Ok(_) => (),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(KvError::Io(err)),
+    };
+
+    // The header is written aside so that a crash cannot leave a partial one.
+    let tmp_filename = tmp_path(path);
+    let mut wr = LogWriter::new(vfs.create(&tmp_filename)?, 0);
+    write_file_header(&mut wr)?;
+    wr.sync()?;
+    vfs.rename(&tmp_filename, path)?;
+    Ok(())
+}
+
+/// Returns serialized file header, newline included.
+fn file_header_line() -> Result<String> {
+    let hdr = FileHeader {
+        magic: MAGIC.to_owned(),
+        version: FORMAT_VERSION,
+    };
+    Ok(format!("{}\n", serde_json::to_string(&hdr)?))
+}
+
+pub(crate) fn write_file_header(wr: &mut impl Write) -> Result<()> {
+    wr.write_all(file_header_line()?.as_bytes())?;
+    wr.flush()?;
+    Ok(())
+}
+
+/// Reads file header from start of log and returns format version.
+///
+/// The read position is left after the file header if any.
+pub(crate) fn read_format_version(rd: &mut (impl BufRead + Seek)) -> Result<u32> {
+    let mut line = String::new();
+    rd.read_line(&mut line)?;
+    if let Ok(hdr) = serde_json::from_str::<FileHeader>(&line) {
+        if hdr.magic == MAGIC {
+            return Ok(hdr.version);
+        }
+    }
+
+    // Version 1 logs start directly with a record.
+    serde_json::from_str::<Header>(&line)?;
+    rd.seek(SeekFrom::Start(0))?;
+    Ok(1)
+}
+
+/// Replaces file header of log at `path`, if any, with the current one.
+fn rewrite_file_header(vfs: &dyn Vfs, path: &Path) -> Result<()> {
+    let tmp_filename = tmp_path(path);
+    {
+        let mut old_rd = BufReader::new(vfs.open_read(path)?);
+        read_format_version(&mut old_rd)?;
+        let mut tmp_wr = LogWriter::new(vfs.create(&tmp_filename)?, 0);
+        write_file_header(&mut tmp_wr)?;
+        io::copy(&mut old_rd, &mut tmp_wr)?;
+        tmp_wr.sync()?;
+    }
+    vfs.rename(&tmp_filename, path)?;
+    Ok(())
+}
+
+/// Buffered writer keeping track of the log offset of the next byte written.
+pub(crate) struct LogWriter<W: Write> {
+    wr: BufWriter<W>,
+    pos: u64,
+}
+
+impl<W: Write> LogWriter<W> {
+    /// Creates writer appending to `inner` whose length is `pos`.
+    pub(crate) fn new(inner: W, pos: u64) -> LogWriter<W> {
+        LogWriter {
+            wr: BufWriter::new(inner),
+            pos,
+        }
+    }
+
+    pub(crate) fn pos(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl LogWriter<Box<dyn WriteFile>> {
+    /// Makes all bytes written so far durable.
+    fn sync(&mut self) -> io::Result<()> {
+        self.wr.flush()?;
+        self.wr.get_mut().sync()
+    }
+}
+
+impl<W: Write> Write for LogWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.wr.write(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wr.flush()
+    }
+}
+
+pub(crate) fn append_to_open_log(
+    wr: &mut LogWriter<impl Write>,
     tag: Tag,
+    ns: &str,
     key: &str,
     val_opt: Option<&str>,
-) -> Result<u64> {
+) -> Result<RecordPos> {
     let ser_val_opt = match val_opt {
         Some(val) => Some(serde_json::to_string(val)?),
         None => None,
@@ -208,7 +1108,8 @@ fn append_to_open_log(
 
     let hdr = Header {
         tag,
-        key,
+        ns: Cow::Borrowed(ns),
+        key: Cow::Borrowed(key),
         value_size: match ser_val_opt {
             Some(ref ser) => ser.len() + "\n".len(),
             None => 0,
@@ -219,17 +1120,24 @@ fn append_to_open_log(
 
     // TODO: What if the write fails halfway through?
     wr.write_fmt(format_args!("{}\n", ser_hdr))?;
-    let off = wr.seek(SeekFrom::Current(0))?;
-    if ser_val_opt.is_some() {
-        wr.write_fmt(format_args!("{}\n", ser_val_opt.unwrap()))?;
+    let value_off = wr.pos();
+    if let Some(ser_val) = ser_val_opt {
+        wr.write_fmt(format_args!("{}\n", ser_val))?;
     }
 
-    Ok(off)
+    Ok(RecordPos {
+        value: match tag {
+            Tag::SetBlob(id) => ValueLoc::Blob(id),
+            _ => ValueLoc::Log(value_off),
+        },
+        len: (ser_hdr.len() + "\n".len() + hdr.value_size) as u64,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs::{self, OpenOptions};
 
     #[test]
     fn reopen() -> Result<()> {
@@ -242,4 +1150,317 @@ mod tests {
         assert_eq!(kvs2.get("k".to_string())?, Some("v".to_string()));
         Ok(())
     }
+
+    #[test]
+    fn mmap_growth_and_compaction() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let options = KvStoreOptions {
+            mmap: true,
+            ..KvStoreOptions::default()
+        };
+        {
+            let kvs = KvStore::open_with(&tmpdir, options.clone())?;
+            kvs.set("k".to_string(), "v".to_string())?;
+            assert_eq!(kvs.get("k".to_string())?, Some("v".to_string()));
+        }
+        let kvs = KvStore::open_with(&tmpdir, options)?;
+        assert_eq!(kvs.get("k".to_string())?, Some("v".to_string()));
+        for i in 0..=MAX_DEAD_ENTRIES {
+            kvs.set("k".to_string(), format!("{}", i))?;
+            kvs.set(format!("k{}", i), format!("{}", i))?;
+            assert_eq!(kvs.get("k".to_string())?, Some(format!("{}", i)));
+        }
+        assert_eq!(kvs.stats()?.compactions, Some(1));
+        for i in 0..=MAX_DEAD_ENTRIES {
+            assert_eq!(kvs.get(format!("k{}", i))?, Some(format!("{}", i)));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sharded() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let options = KvStoreOptions {
+            shards: 4,
+            ..KvStoreOptions::default()
+        };
+        {
+            let kvs = KvStore::open_with(&tmpdir, options.clone())?;
+            for i in 0..100 {
+                kvs.set(format!("k{}", i), format!("{}", i))?;
+                kvs.namespace("ns")?
+                    .set(format!("k{}", i), format!("{}", i))?;
+            }
+            kvs.drop_namespace("ns")?;
+            for shard in kvs.shards.iter() {
+                assert!(!shard.lock()?.namespaces[DEFAULT_NAMESPACE].is_empty());
+            }
+        }
+        assert!(!tmpdir.path().join("kv.db").exists());
+
+        // The shard count the store was created with prevails.
+        let kvs = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs.shards.len(), 4);
+        for i in 0..100 {
+            assert_eq!(kvs.get(format!("k{}", i))?, Some(format!("{}", i)));
+        }
+        assert_eq!(kvs.keys()?.len(), 100);
+        assert_eq!(kvs.namespaces()?, vec![DEFAULT_NAMESPACE.to_owned()]);
+        assert_eq!(kvs.stats()?.live_keys, 100);
+        assert_eq!(KvStore::upgrade(&tmpdir)?, FORMAT_VERSION);
+
+        // Unsharded stores remain so.
+        let tmpdir = tempfile::tempdir()?;
+        KvStore::open(&tmpdir)?.set("k".to_owned(), "v".to_owned())?;
+        let kvs = KvStore::open_with(&tmpdir, options)?;
+        assert_eq!(kvs.shards.len(), 1);
+        assert_eq!(kvs.get("k".to_owned())?, Some("v".to_owned()));
+        Ok(())
+    }
+
+    #[test]
+    fn limits() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let options = KvStoreOptions {
+            limits: Limits {
+                max_key_len: Some(8),
+                max_value_size: Some(100),
+                max_store_size: Some(4096),
+            },
+            ..KvStoreOptions::default()
+        };
+        let kvs = KvStore::open_with(&tmpdir, options)?;
+        assert!(matches!(
+            kvs.set("k".repeat(9), "v".to_owned()),
+            Err(KvError::KeyTooLong(8))
+        ));
+        assert!(matches!(
+            kvs.set("k".to_owned(), "v".repeat(101)),
+            Err(KvError::ValueTooLarge(100))
+        ));
+        let binary = [0xffu8; 101];
+        assert!(matches!(
+            kvs.set_reader("k".to_owned(), &mut &binary[..]),
+            Err(KvError::ValueTooLarge(100))
+        ));
+        assert_eq!(fs::read_dir(tmpdir.path().join("blobs"))?.count(), 0);
+        kvs.set_reader("k".to_owned(), &mut &binary[..100])?;
+        assert_eq!(kvs.check_set("k")?, Some(100));
+        assert!(matches!(
+            kvs.check_set(&"k".repeat(9)),
+            Err(KvError::KeyTooLong(8))
+        ));
+
+        let mut i = 0;
+        let err = loop {
+            match kvs.set(format!("k{}", i), "v".repeat(100)) {
+                Ok(()) => i += 1,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, KvError::QuotaExceeded(4096)));
+        assert!(matches!(
+            kvs.check_set("k"),
+            Err(KvError::QuotaExceeded(4096))
+        ));
+        assert!(kvs.stats()?.total_bytes >= 4096);
+        // Reads and removals are still accepted.
+        assert_eq!(kvs.get("k0".to_owned())?, Some("v".repeat(100)));
+        kvs.remove("k".to_owned())?;
+        assert_eq!(fs::read_dir(tmpdir.path().join("blobs"))?.count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn blobs() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let blob_count = || fs::read_dir(tmpdir.path().join("blobs")).unwrap().count();
+        let large = "x".repeat(BLOB_THRESHOLD);
+        let binary = [0xffu8; 10];
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("large".to_string(), large.clone())?;
+            kvs.set_reader("binary".to_string(), &mut &binary[..])?;
+            assert_eq!(blob_count(), 2);
+            assert_eq!(kvs.get("large".to_string())?, Some(large.clone()));
+            match kvs.get("binary".to_string()) {
+                Err(KvError::InvalidUtf8(_)) => (),
+                _ => panic!("invalid UTF-8 not detected"),
+            }
+
+            // Superseded blobs are deleted while live ones survive compaction.
+            kvs.set("binary".to_string(), "small".to_string())?;
+            assert_eq!(blob_count(), 1);
+            for i in 0..=MAX_DEAD_ENTRIES {
+                kvs.set("k".to_string(), format!("{}", i))?;
+            }
+            assert_eq!(kvs.stats()?.compactions, Some(1));
+        }
+
+        let kvs = KvStore::open(&tmpdir)?;
+        let mut val = Vec::new();
+        assert!(kvs.get_writer("large".to_string(), &mut val)?);
+        assert_eq!(val, large.as_bytes());
+        assert_eq!(kvs.get("binary".to_string())?, Some("small".to_string()));
+        kvs.remove("large".to_string())?;
+        assert_eq!(blob_count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_blob() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let large = "x".repeat(BLOB_THRESHOLD);
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("lost".to_string(), large.clone())?;
+            kvs.set("kept".to_string(), large.clone())?;
+            kvs.set("small".to_string(), "v".to_string())?;
+        }
+        fs::remove_file(tmpdir.path().join("blobs").join("0"))?;
+
+        // Only the key whose blob is missing is unreadable.
+        let kvs = KvStore::open(&tmpdir)?;
+        match kvs.get("lost".to_string()) {
+            Err(KvError::Io(ref err)) if err.kind() == ErrorKind::NotFound => (),
+            res => panic!("missing blob not reported: {:?}", res),
+        }
+        assert!(kvs.get_writer("lost".to_string(), &mut Vec::new()).is_err());
+        assert_eq!(kvs.get("kept".to_string())?, Some(large));
+        assert_eq!(kvs.get("small".to_string())?, Some("v".to_string()));
+        assert_eq!(kvs.stats()?.live_keys, 3);
+
+        kvs.set("lost".to_string(), "found".to_string())?;
+        assert_eq!(kvs.get("lost".to_string())?, Some("found".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_with_escaped_key() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("\"k\\".to_string(), "v".to_string())?;
+        }
+        let kvs2 = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs2.get("\"k\\".to_string())?, Some("v".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn reopen_after_drop_namespace() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.namespace("a")?.set("k".to_string(), "va".to_string())?;
+            kvs.namespace("b")?.set("k".to_string(), "vb".to_string())?;
+            kvs.drop_namespace("a")?;
+        }
+        let kvs2 = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs2.namespace("a")?.get("k".to_string())?, None);
+        assert_eq!(
+            kvs2.namespace("b")?.get("k".to_string())?,
+            Some("vb".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let kvs = KvStore::open(&tmpdir)?;
+        let log_size = || fs::metadata(tmpdir.path().join("kv.db")).map(|md| md.len());
+        let hdr_size = log_size()?;
+        kvs.set("k".to_string(), "v1".to_string())?;
+        let rec_size = log_size()? - hdr_size;
+        kvs.set("k".to_string(), "v2".to_string())?;
+        let stats = kvs.stats()?;
+        assert_eq!(stats.live_keys, 1);
+        assert_eq!(stats.total_bytes, hdr_size + 2 * rec_size);
+        assert_eq!(stats.dead_bytes, Some(rec_size));
+        assert_eq!(stats.dead_entries, Some(1));
+        assert_eq!(stats.compactions, Some(0));
+
+        for i in 0..MAX_DEAD_ENTRIES {
+            kvs.set("k".to_string(), format!("{}", i))?;
+        }
+        let stats = kvs.stats()?;
+        assert_eq!(stats.compactions, Some(1));
+        assert!(stats.last_compaction.is_some());
+        assert_eq!(stats.dead_entries, Some(0));
+        assert_eq!(stats.dead_bytes, Some(0));
+        Ok(())
+    }
+
+    const V1_LOG: &str = "{\"tag\":\"Set\",\"key\":\"k\",\"value_size\":4}\n\"v\"\n";
+
+    #[test]
+    fn open_rejects_old_and_unknown_versions() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let filename = tmpdir.path().join("kv.db");
+
+        fs::write(&filename, V1_LOG)?;
+        match KvStore::open(&tmpdir) {
+            Err(err @ KvError::UnsupportedFormat(1)) => {
+                assert!(err.to_string().contains("kvs upgrade"))
+            }
+            _ => panic!("version 1 log not rejected"),
+        }
+
+        fs::write(&filename, "{\"magic\":\"pna-kvs\",\"version\":999}\n")?;
+        match KvStore::open(&tmpdir) {
+            Err(err @ KvError::UnsupportedFormat(999)) => {
+                assert!(err.to_string().contains("newer kvs"))
+            }
+            _ => panic!("unknown version not rejected"),
+        }
+        match KvStore::upgrade(&tmpdir) {
+            Err(KvError::UnsupportedFormat(999)) => (),
+            _ => panic!("unknown version upgraded"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade_from_v1() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        fs::write(tmpdir.path().join("kv.db"), V1_LOG)?;
+        assert_eq!(KvStore::upgrade(&tmpdir)?, 1);
+        assert_eq!(KvStore::upgrade(&tmpdir)?, FORMAT_VERSION);
+        let kvs = KvStore::open(&tmpdir)?;
+        assert_eq!(kvs.get("k".to_string())?, Some("v".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn open_truncates_torn_tail_only() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let filename = tmpdir.path().join("kv.db");
+        {
+            let kvs = KvStore::open(&tmpdir)?;
+            kvs.set("k1".to_string(), "v1".to_string())?;
+        }
+        let len = fs::metadata(&filename)?.len();
+
+        let mut file = OpenOptions::new().append(true).open(&filename)?;
+        file.write_all(b"{\"tag\":\"Set\",\"key\":\"k2\",\"value_size\":100}\n\"v")?;
+        drop(file);
+        assert_eq!(
+            KvStore::open(&tmpdir)?.get("k1".to_string())?,
+            Some("v1".to_string())
+        );
+        assert_eq!(fs::metadata(&filename)?.len(), len);
+
+        let mut file = OpenOptions::new().append(true).open(&filename)?;
+        file.write_all(b"{\"tag\":\"Set\",\"key\":\"k2\",\"value_size\":100}\n\"v2\"\n")?;
+        file.write_all(b"{\"tag\":\"Set\",\"key\":\"k3\",\"value_size\":5}\n\"v3\"\n")?;
+        let corrupt_len = file.metadata()?.len();
+        drop(file);
+        match KvStore::open(&tmpdir) {
+            Err(KvError::CorruptLog(off)) => assert_eq!(off, len),
+            _ => panic!("corrupt record not rejected"),
+        }
+        assert_eq!(fs::metadata(&filename)?.len(), corrupt_len);
+        Ok(())
+    }
 }
@@ -0,0 +1,285 @@
+//! Conformance suite checking that a `KvsEngine` implementation behaves like the built-in ones.
+//!
+//! Each check opens fresh engines in a temporary directory with the given function and panics on
+//! misbehavior.  Engines not storing data on disk should be checked with `persistent` unset so
+//! that their content is not expected to survive reopening.
+
+use crate::engine::{KvsEngine, DEFAULT_NAMESPACE};
+use crate::error::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use tempfile::TempDir;
+
+/// Function opening the engine under test in the given directory.
+pub type Opener<E> = fn(&Path) -> Result<E>;
+
+/// Runs all checks on engines opened with `KvsEngine::open()`.
+pub fn run_all<E: KvsEngine>(persistent: bool) -> Result<()> {
+    run_all_with(|path| E::open(path), persistent)
+}
+
+/// Runs all checks on engines opened with `open`.
+pub fn run_all_with<E: KvsEngine>(open: Opener<E>, persistent: bool) -> Result<()> {
+    missing_keys(open)?;
+    namespaces(open)?;
+    streaming(open)?;
+    if persistent {
+        reopen(open)?;
+    }
+    compaction(open, persistent)?;
+    concurrency(open)?;
+    for seed in 0..4 {
+        model(open, seed, persistent)?;
+    }
+    Ok(())
+}
+
+fn temp_dir() -> Result<TempDir> {
+    Ok(TempDir::new()?)
+}
+
+/// Checks that getting a missing key returns `None` while removing it fails with
+/// `KvError::KeyNotFound`.
+pub fn missing_keys<E: KvsEngine>(open: Opener<E>) -> Result<()> {
+    let dir = temp_dir()?;
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key".to_owned()),
+        Err(KvError::KeyNotFound(ref key)) if key == "key"
+    ));
+
+    engine.set("key".to_owned(), "value".to_owned())?;
+    engine.remove("key".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key".to_owned()),
+        Err(KvError::KeyNotFound(_))
+    ));
+    Ok(())
+}
+
+/// Checks that namespaces have distinct keyspaces and can be dropped independently, and that
+/// reading from a missing namespace does not create it.
+pub fn namespaces<E: KvsEngine>(open: Opener<E>) -> Result<()> {
+    let dir = temp_dir()?;
+    let engine = open(dir.path())?;
+    let ns1 = engine.namespace("ns1")?;
+    let ns2 = engine.namespace("ns2")?;
+
+    engine.set("key".to_owned(), "value0".to_owned())?;
+    ns1.set("key".to_owned(), "value1".to_owned())?;
+    ns2.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(ns1.get("key".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(ns2.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(ns1.keys()?, vec!["key".to_owned()]);
+    let names: BTreeSet<String> = engine.namespaces()?.into_iter().collect();
+    for name in &[DEFAULT_NAMESPACE, "ns1", "ns2"] {
+        assert!(names.contains(*name), "namespace {:?} not listed", name);
+    }
+
+    engine.drop_namespace("ns1")?;
+    assert_eq!(ns1.get("key".to_owned())?, None);
+    assert!(ns1.keys()?.is_empty());
+    assert!(!ns1.get_writer("key".to_owned(), &mut Vec::new())?);
+    assert!(ns1.remove("key".to_owned()).is_err());
+    assert_eq!(ns2.get("key".to_owned())?, Some("value2".to_owned()));
+    let missing = engine.namespace("missing")?;
+    assert_eq!(missing.get("key".to_owned())?, None);
+    assert!(missing.keys()?.is_empty());
+    let names = engine.namespaces()?;
+    for name in &["ns1", "missing"] {
+        assert!(
+            !names.iter().any(|n| n == name),
+            "namespace {:?} listed",
+            name
+        );
+    }
+
+    engine.drop_namespace(DEFAULT_NAMESPACE)?;
+    assert_eq!(engine.get("key".to_owned())?, None);
+    assert_eq!(ns2.get("key".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.stats()?.live_keys, 1);
+    Ok(())
+}
+
+/// Checks that streamed values round-trip, binary ones included.
+pub fn streaming<E: KvsEngine>(open: Opener<E>) -> Result<()> {
+    let dir = temp_dir()?;
+    let engine = open(dir.path())?;
+    let large: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    engine.set_reader("large".to_owned(), &mut large.as_slice())?;
+    engine.set_reader("text".to_owned(), &mut "value".as_bytes())?;
+
+    let mut val = Vec::new();
+    assert!(engine.get_writer("large".to_owned(), &mut val)?);
+    assert_eq!(val, large);
+    assert!(!engine.get_writer("missing".to_owned(), &mut val)?);
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+    assert!(matches!(
+        engine.get("large".to_owned()),
+        Err(KvError::InvalidUtf8(_))
+    ));
+    Ok(())
+}
+
+/// Checks that data survives reopening.
+pub fn reopen<E: KvsEngine>(open: Opener<E>) -> Result<()> {
+    let dir = temp_dir()?;
+    {
+        let engine = open(dir.path())?;
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        engine.set("key2".to_owned(), "value2".to_owned())?;
+        engine.remove("key2".to_owned())?;
+        engine
+            .namespace("ns")?
+            .set("key1".to_owned(), "value3".to_owned())?;
+    }
+    let engine = open(dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(
+        engine.namespace("ns")?.get("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
+    Ok(())
+}
+
+/// Overwrites keys enough to trigger compaction in log-structured engines and checks that the
+/// latest values are kept.
+pub fn compaction<E: KvsEngine>(open: Opener<E>, persistent: bool) -> Result<()> {
+    let dir = temp_dir()?;
+    let engine = open(dir.path())?;
+    for round in 0..20 {
+        for key_id in 0..50 {
+            engine.set(format!("key{}", key_id), format!("{}", round))?;
+        }
+    }
+    let check = |engine: &E| -> Result<()> {
+        for key_id in 0..50 {
+            assert_eq!(engine.get(format!("key{}", key_id))?, Some("19".to_owned()));
+        }
+        assert_eq!(engine.stats()?.live_keys, 50);
+        Ok(())
+    };
+    check(&engine)?;
+    if persistent {
+        drop(engine);
+        check(&open(dir.path())?)?;
+    }
+    Ok(())
+}
+
+/// Checks that handles can be used concurrently from several threads.
+pub fn concurrency<E: KvsEngine>(open: Opener<E>) -> Result<()> {
+    const THREADS: usize = 8;
+    const KEYS: usize = 50;
+
+    let dir = temp_dir()?;
+    let engine = open(dir.path())?;
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|thread_id| {
+            let engine = engine.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || -> Result<()> {
+                barrier.wait();
+                for i in 0..KEYS {
+                    let key = format!("key{}-{}", thread_id, i);
+                    engine.set(key.clone(), format!("value{}", i))?;
+                    assert_eq!(engine.get(key)?, Some(format!("value{}", i)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("worker thread panicked")?;
+    }
+
+    for thread_id in 0..THREADS {
+        for i in 0..KEYS {
+            assert_eq!(
+                engine.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    assert_eq!(engine.stats()?.live_keys, (THREADS * KEYS) as u64);
+    Ok(())
+}
+
+/// Runs random operations seeded with `seed` against both the engine and a `BTreeMap` and checks
+/// that they agree.  Persistent engines are reopened from time to time.
+pub fn model<E: KvsEngine>(open: Opener<E>, seed: u64, persistent: bool) -> Result<()> {
+    const NAMESPACES: [&str; 3] = [DEFAULT_NAMESPACE, "a", "b"];
+
+    let dir = temp_dir()?;
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut model: BTreeMap<&str, BTreeMap<String, String>> = BTreeMap::new();
+    let mut engine = open(dir.path())?;
+
+    for i in 0..500 {
+        let ns = NAMESPACES[rng.gen_range(0, NAMESPACES.len())];
+        let handle = engine.namespace(ns)?;
+        let key = format!("key{}", rng.gen_range(0, 20));
+        match rng.gen_range(0, 100) {
+            0..=39 => {
+                let index = model.entry(ns).or_default();
+                let val = format!("value{}", i);
+                handle.set(key.clone(), val.clone())?;
+                index.insert(key, val);
+            }
+            40..=64 => {
+                let expected = model.get(ns).and_then(|index| index.get(&key));
+                assert_eq!(handle.get(key.clone())?.as_ref(), expected);
+            }
+            65..=84 => {
+                let index = model.entry(ns).or_default();
+                match handle.remove(key.clone()) {
+                    Ok(()) => assert!(index.remove(&key).is_some(), "removed missing {}", key),
+                    Err(KvError::KeyNotFound(_)) => {
+                        assert!(!index.contains_key(&key), "failed to remove {}", key)
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            85..=89 => {
+                let mut keys = handle.keys()?;
+                keys.sort();
+                let expected: Vec<String> = model
+                    .get(ns)
+                    .map(|index| index.keys().cloned().collect())
+                    .unwrap_or_default();
+                assert_eq!(keys, expected);
+            }
+            90..=92 => {
+                engine.drop_namespace(ns)?;
+                model.remove(ns);
+            }
+            93..=95 => {
+                let live_keys: usize = model.values().map(BTreeMap::len).sum();
+                assert_eq!(engine.stats()?.live_keys, live_keys as u64);
+            }
+            _ if persistent => {
+                drop(handle);
+                drop(engine);
+                engine = open(dir.path())?;
+            }
+            _ => (),
+        }
+    }
+
+    for (ns, index) in &model {
+        let handle = engine.namespace(ns)?;
+        for (key, val) in index {
+            assert_eq!(handle.get(key.clone())?.as_ref(), Some(val));
+        }
+    }
+    Ok(())
+}
@@ -3,6 +3,7 @@
 // We consider throughout this module that mutex poisoning are fatal errors and so unwrap()
 // LockResult.
 
+use std::collections::VecDeque;
 use std::panic::{self, AssertUnwindSafe};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread::{self, JoinHandle};
@@ -23,8 +24,8 @@ enum Msg {
 
 /// Thread-safe message FIFO allowing consumers to block.
 struct MsgQueue {
-    /// Accumulates messages not yet processed by worker threads.
-    msgs: Mutex<Vec<Msg>>,
+    /// Accumulates messages not yet processed by worker threads, oldest first.
+    msgs: Mutex<VecDeque<Msg>>,
 
     /// Signaled when a message is pushed in `msgs`.
     cv: Condvar,
@@ -33,7 +34,7 @@ struct MsgQueue {
 impl MsgQueue {
     /// Offloads execution of `msg` to an arbitrary worker thread.
     pub fn push(&self, msg: Msg) {
-        self.msgs.lock().unwrap().push(msg);
+        self.msgs.lock().unwrap().push_back(msg);
         self.cv.notify_one();
     }
 
@@ -43,7 +44,7 @@ impl MsgQueue {
         while msgs.is_empty() {
             msgs = self.cv.wait(msgs).unwrap();
         }
-        msgs.pop().expect("message queue unexpectedly empty")
+        msgs.pop_front().expect("message queue unexpectedly empty")
     }
 }
 
@@ -71,7 +72,7 @@ impl ThreadPool for SharedQueueThreadPool {
         assert!(nthreads > 0);
 
         let local_queue = Arc::new(MsgQueue {
-            msgs: Mutex::new(Vec::new()),
+            msgs: Mutex::new(VecDeque::new()),
             cv: Condvar::new(),
         });
 
@@ -90,7 +91,7 @@ impl ThreadPool for SharedQueueThreadPool {
 
     fn spawn<F>(&self, job: F)
     where
-        F: FnOnce() -> () + Send + 'static,
+        F: FnOnce() + Send + 'static,
     {
         self.queue.push(Msg::Job(Box::new(job)));
     }
@@ -104,7 +105,7 @@ fn worker_thread(queue: Arc<MsgQueue>) {
         // the pool implementation is correct.
         // TODO: Is is correct to AssertUnwindSafe()?
         {
-            if panic::catch_unwind(AssertUnwindSafe(|| job())).is_err() {
+            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
                 error!("closure executed by worker thread panicked");
             }
         }
@@ -0,0 +1,175 @@
+//! Filesystem abstraction used by `KvStore` so that tests can inject faults.
+
+use memmap2::Mmap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Readable and seekable file.
+pub(crate) trait ReadFile: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ReadFile for T {}
+
+/// File opened for writing.  Bytes are always written at the end of the file.
+pub(crate) trait WriteFile: Write + Send {
+    /// Makes all bytes written so far durable.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+impl WriteFile for File {
+    fn sync(&mut self) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+/// Operations `KvStore` performs on its directory.
+///
+/// Metadata operations are expected to be atomic.  Operations adding, renaming or removing
+/// entries sync the directories holding them before returning, so the change survives power loss
+/// once they succeed.  If they fail, the change may have taken effect without being durable.
+pub(crate) trait Vfs: Send + Sync {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>>;
+
+    /// Opens file at `path` for appending, creating it if missing.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Creates file at `path`, failing with `ErrorKind::AlreadyExists` if it exists.
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    /// Creates file at `path`, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>>;
+
+    fn len(&self, path: &Path) -> io::Result<u64>;
+
+    /// Truncates file at `path` to `len` bytes.
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()>;
+
+    /// Atomically replaces `to` with `from`.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates directory `path` and its missing parents.  The entry of `path` is made durable
+    /// even if it existed already.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Returns paths of all entries of directory `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Maps file at `path` in memory.  Later modifications of the file may not be visible.
+    fn map(&self, path: &Path) -> io::Result<Mmap>;
+
+    /// Makes the entries of directory `path` durable.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+}
+
+/// Syncs the directory holding `path`.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => sync_dir(dir),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // Relative paths without directory are in the current one.
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Directories cannot be opened, and entries are durable once created, on other platforms.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The real filesystem.
+pub(crate) struct OsFs;
+
+impl Vfs for OsFs {
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn ReadFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let file = match OpenOptions::new().append(true).open(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let file = OpenOptions::new().append(true).create(true).open(path)?;
+                sync_parent(path)?;
+                file
+            }
+            res => res?,
+        };
+        Ok(Box::new(file))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        sync_parent(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WriteFile>> {
+        let file = File::create(path)?;
+        sync_parent(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn len(&self, path: &Path) -> io::Result<u64> {
+        fs::metadata(path).map(|md| md.len())
+    }
+
+    fn truncate(&self, path: &Path, len: u64) -> io::Result<()> {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(len)?;
+        file.sync_all()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)?;
+        sync_parent(to)?;
+        if from.parent() != to.parent() {
+            sync_parent(from)?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)?;
+        sync_parent(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let missing = path.ancestors().take_while(|dir| !dir.exists()).count();
+        fs::create_dir_all(path)?;
+        // `path` is synced even if it existed as an earlier call may have failed to sync it.
+        let created: Vec<&Path> = path.ancestors().take(missing.max(1)).collect();
+        for dir in created.into_iter().rev() {
+            sync_parent(dir)?;
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn map(&self, path: &Path) -> io::Result<Mmap> {
+        let file = File::open(path)?;
+        // SAFETY: The log is only modified by appending to it or by replacing it with rename(2)
+        // so mapped bytes are never modified.  Concurrent modification by other processes is not
+        // supported.
+        unsafe { Mmap::map(&file) }
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        sync_dir(path)
+    }
+}
@@ -1,13 +1,1185 @@
-use serde::{Deserialize, Serialize};
+use crate::{KvError, Result, DEFAULT_NAMESPACE};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::{self, prelude::*, ErrorKind};
+#[cfg(feature = "async")]
+use std::time::Duration;
+#[cfg(feature = "async")]
+use tokio::{io::AsyncBufRead, sync::mpsc};
+
+/// Version of the protocol described by this module.
+///
+/// Bumped whenever a change prevents clients and servers of different versions from understanding
+/// each other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Requests sent by clients.
+///
+/// Clients pick identifiers so as to match replies with requests.  Requests sent on the same
+/// connection without waiting for replies may be handled concurrently and so replied to out of
+/// order, except for streaming requests that are handled in order.
+#[derive(PartialEq, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub id: u64,
+    pub op: Op,
+}
 
 // TODO: Use &str instead of String
+/// Operations requested by clients.
+///
+/// The first field of key-value operations is the namespace they target.
 #[derive(PartialEq, Debug, Serialize, Deserialize)]
-pub enum Request {
+pub enum Op {
+    Get(String, String),
+    Set(String, String, String),
+    Rm(String, String),
+    DropNamespace(String),
+    Stats,
+
+    /// Sets value sent as chunks after the request.
+    SetStream(String, String),
+
+    /// Gets value sent as chunks before the reply.  The reply tells whether the key exists.
+    GetStream(String, String),
+
+    /// Stops the server once all requests sent before are handled.
+    Shutdown,
+
+    /// Tells the protocol version of the client.  Replied to with `ServerInfo`.
+    ///
+    /// Clients send it first on each connection so as to fail early if the server is
+    /// incompatible.  Servers accept connections without it for compatibility.
+    Hello(u32),
+
+    /// Proves that the client knows the token or the admin token of the server.  Requests sent
+    /// on the connection afterwards are served accordingly.
+    Auth(Secret),
+}
+
+/// Requests sent by clients predating request identifiers and namespaces, one per connection.
+///
+/// They target the default namespace.
+#[derive(Serialize, Deserialize)]
+enum LegacyRequest {
     Get(String),
     Set(String, String),
     Rm(String),
     Shutdown,
 }
 
+/// Token that is not logged.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Secret {
+    /// Compares secrets in constant time so that timing does not leak how much of them match.
+    pub fn matches(&self, other: &str) -> bool {
+        let (a, b) = (self.0.as_bytes(), other.as_bytes());
+        a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+    }
+}
+
+/// Description of a server returned by the handshake.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub protocol_version: u32,
+
+    /// Version of the `kvs` crate the server was built from.
+    pub server_version: String,
+
+    /// Name of the engine backing the server, if known.
+    pub engine: Option<String>,
+
+    /// Optional capabilities of the server, such as `"streaming"`.
+    pub features: Vec<String>,
+
+    /// Size in bytes of the largest request the server accepts.  Larger values must be streamed.
+    pub max_request_size: u64,
+}
+
+/// Replies sent by server.
+///
+/// `id` is that of the request replied to.  `T` depends on the request: `Stats` for `Op::Stats`,
+/// `ServerInfo` for `Op::Hello`, whether the key exists for `Op::GetStream` and the value if any
+/// otherwise.
 #[derive(Debug, Serialize, Deserialize)]
-pub struct Reply(pub Result<Option<String>, String>);
+pub struct Reply<T = Option<String>> {
+    pub id: u64,
+    pub result: std::result::Result<T, ReplyError>,
+}
+
+/// Errors sent in replies.
+///
+/// Errors clients can act upon are typed.  Others are only described.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplyError {
+    KeyTooLong(usize),
+    ValueTooLarge(u64),
+    QuotaExceeded(u64),
+    Other(String),
+    Unauthenticated,
+    PermissionDenied,
+    RequestTooLarge(u64),
+}
+
+impl From<KvError> for ReplyError {
+    fn from(err: KvError) -> ReplyError {
+        match err {
+            KvError::KeyTooLong(max) => ReplyError::KeyTooLong(max),
+            KvError::ValueTooLarge(max) => ReplyError::ValueTooLarge(max),
+            KvError::QuotaExceeded(max) => ReplyError::QuotaExceeded(max),
+            KvError::Unauthenticated => ReplyError::Unauthenticated,
+            KvError::PermissionDenied => ReplyError::PermissionDenied,
+            KvError::RequestTooLarge(max) => ReplyError::RequestTooLarge(max),
+            err => ReplyError::Other(err.to_string()),
+        }
+    }
+}
+
+impl From<ReplyError> for KvError {
+    fn from(err: ReplyError) -> KvError {
+        match err {
+            ReplyError::KeyTooLong(max) => KvError::KeyTooLong(max),
+            ReplyError::ValueTooLarge(max) => KvError::ValueTooLarge(max),
+            ReplyError::QuotaExceeded(max) => KvError::QuotaExceeded(max),
+            ReplyError::Other(msg) => KvError::Server(msg),
+            ReplyError::Unauthenticated => KvError::Unauthenticated,
+            ReplyError::PermissionDenied => KvError::PermissionDenied,
+            ReplyError::RequestTooLarge(max) => KvError::RequestTooLarge(max),
+        }
+    }
+}
+
+/// Message read by `Encoding::read_message_or_skip()`.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    /// Whole message, to be decoded.
+    Whole(Vec<u8>),
+
+    /// Message exceeding the size limit, skipped.  Holds the identifier of the request if it
+    /// could be read from the start of the message.
+    TooLarge(Option<u64>),
+}
+
+/// Largest frame accepted by the binary encoding.
+///
+/// Bounds memory allocated before a frame is decoded.  Larger values must be streamed.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// Encodings of requests and replies.
+///
+/// `Json` sends each message as a line of JSON.  `Binary` sends each message as a frame made of
+/// the big-endian `u32` length of the rest of the frame, an opcode and a bincode payload.  Request
+/// opcodes tell the operation and payloads hold the request identifier followed by the operation
+/// fields.  Reply opcodes tell whether the request succeeded and payloads hold the request
+/// identifier followed by the result or the error.
+///
+/// `LegacyJson` is the JSON schema predating request identifiers: requests are untagged
+/// operations on the default namespace and replies bare results with errors described.  Servers
+/// fall back to it for JSON requests in this schema and close the connection after replying, as
+/// clients using it expect.
+///
+/// Streamed values are sent as chunks with all encodings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    Binary,
+    LegacyJson,
+}
+
+const OP_GET: u8 = 1;
+const OP_SET: u8 = 2;
+const OP_RM: u8 = 3;
+const OP_DROP_NAMESPACE: u8 = 4;
+const OP_STATS: u8 = 5;
+const OP_SET_STREAM: u8 = 6;
+const OP_GET_STREAM: u8 = 7;
+const OP_SHUTDOWN: u8 = 8;
+const OP_HELLO: u8 = 9;
+const OP_AUTH: u8 = 10;
+
+const REPLY_OK: u8 = 0x80;
+const REPLY_ERR: u8 = 0x81;
+
+impl Encoding {
+    /// Detects the encoding used by a client from the first bytes it sent.
+    ///
+    /// JSON requests start with `{`, or `"` for the legacy shutdown request, whereas a binary
+    /// frame starting with these bytes would exceed `MAX_FRAME_LEN`.  Returns `None` if the
+    /// connection was closed before anything was sent.  `LegacyJson` is told from `Json` by
+    /// `decode_request()`.
+    pub fn detect(rd: &mut impl BufRead) -> io::Result<Option<Encoding>> {
+        Ok(rd
+            .fill_buf()?
+            .first()
+            .map(|&first| Encoding::from_first_byte(first)))
+    }
+
+    fn from_first_byte(first: u8) -> Encoding {
+        if first == b'{' || first == b'"' {
+            Encoding::Json
+        } else {
+            Encoding::Binary
+        }
+    }
+
+    /// Appends `req` to `buf`.
+    pub fn write_request(self, buf: &mut Vec<u8>, req: &Request) -> Result<()> {
+        let id = req.id;
+        match (self, &req.op) {
+            (Encoding::Json, _) => write_json(buf, req),
+            (Encoding::LegacyJson, op) => {
+                let legacy = match op {
+                    Op::Get(ns, key) if ns == DEFAULT_NAMESPACE => LegacyRequest::Get(key.clone()),
+                    Op::Set(ns, key, val) if ns == DEFAULT_NAMESPACE => {
+                        LegacyRequest::Set(key.clone(), val.clone())
+                    }
+                    Op::Rm(ns, key) if ns == DEFAULT_NAMESPACE => LegacyRequest::Rm(key.clone()),
+                    Op::Shutdown => LegacyRequest::Shutdown,
+                    op => return Err(bad_data(format!("{:?} has no legacy encoding", op))),
+                };
+                write_json(buf, &legacy)
+            }
+            (Encoding::Binary, Op::Get(ns, key)) => write_frame(buf, OP_GET, &(id, ns, key)),
+            (Encoding::Binary, Op::Set(ns, key, val)) => {
+                write_frame(buf, OP_SET, &(id, ns, key, val))
+            }
+            (Encoding::Binary, Op::Rm(ns, key)) => write_frame(buf, OP_RM, &(id, ns, key)),
+            (Encoding::Binary, Op::DropNamespace(ns)) => {
+                write_frame(buf, OP_DROP_NAMESPACE, &(id, ns))
+            }
+            (Encoding::Binary, Op::Stats) => write_frame(buf, OP_STATS, &id),
+            (Encoding::Binary, Op::SetStream(ns, key)) => {
+                write_frame(buf, OP_SET_STREAM, &(id, ns, key))
+            }
+            (Encoding::Binary, Op::GetStream(ns, key)) => {
+                write_frame(buf, OP_GET_STREAM, &(id, ns, key))
+            }
+            (Encoding::Binary, Op::Shutdown) => write_frame(buf, OP_SHUTDOWN, &id),
+            (Encoding::Binary, Op::Hello(version)) => write_frame(buf, OP_HELLO, &(id, version)),
+            (Encoding::Binary, Op::Auth(token)) => write_frame(buf, OP_AUTH, &(id, token)),
+        }
+    }
+
+    /// Returns the size of the `Op::Set` request with identifier `id` that `write_request()`
+    /// appends, without copying `key` and `val`.
+    pub fn set_request_len(self, id: u64, ns: &str, key: &str, val: &str) -> Result<u64> {
+        #[derive(Serialize)]
+        #[serde(rename = "Request")]
+        struct SetRequest<'a> {
+            id: u64,
+            op: SetOp<'a>,
+        }
+        #[derive(Serialize)]
+        #[serde(rename = "Op")]
+        enum SetOp<'a> {
+            Set(&'a str, &'a str, &'a str),
+        }
+        #[derive(Serialize)]
+        #[serde(rename = "LegacyRequest")]
+        enum LegacySet<'a> {
+            Set(&'a str, &'a str),
+        }
+
+        match self {
+            Encoding::Json => json_len(&SetRequest {
+                id,
+                op: SetOp::Set(ns, key, val),
+            }),
+            Encoding::LegacyJson => json_len(&LegacySet::Set(key, val)),
+            // Frame length and opcode, then the payload.
+            Encoding::Binary => Ok(5 + bincode::serialized_size(&(id, ns, key, val))?),
+        }
+    }
+
+    /// Reads a message of at most `max_len` bytes without decoding it.  Returns `None` if the
+    /// connection was closed between messages.
+    ///
+    /// Failing to decode a message read this way does not prevent reading the next ones.
+    pub fn read_message(self, rd: &mut impl BufRead, max_len: usize) -> Result<Option<Vec<u8>>> {
+        match self.read_message_or_skip(rd, max_len)? {
+            Some(Message::Whole(msg)) => Ok(Some(msg)),
+            Some(Message::TooLarge(_)) => Err(too_large(max_len)),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a message like `read_message()`, but skips messages larger than `max_len` bytes
+    /// instead of failing so that the next message can be read.
+    pub fn read_message_or_skip(
+        self,
+        rd: &mut impl BufRead,
+        max_len: usize,
+    ) -> Result<Option<Message>> {
+        let mut msg = Vec::new();
+        match self {
+            Encoding::Json | Encoding::LegacyJson => {
+                if (&mut *rd)
+                    .take(max_len as u64)
+                    .read_until(b'\n', &mut msg)?
+                    == 0
+                {
+                    return Ok(None);
+                }
+                if cut_short(&msg, max_len) {
+                    skip_line(rd)?;
+                    return Ok(Some(Message::TooLarge(self.json_id_prefix(&msg))));
+                }
+            }
+            Encoding::Binary => {
+                if rd.fill_buf()?.is_empty() {
+                    return Ok(None);
+                }
+                let mut header = [0; 4];
+                rd.read_exact(&mut header)?;
+                let len = frame_len(header)?;
+                if 4 + len > max_len {
+                    let mut head = [0; FRAME_HEAD_LEN];
+                    let head_len = len.min(FRAME_HEAD_LEN);
+                    rd.read_exact(&mut head[..head_len])?;
+                    let skipped = io::copy(
+                        &mut (&mut *rd).take((len - head_len) as u64),
+                        &mut io::sink(),
+                    )?;
+                    if skipped < (len - head_len) as u64 {
+                        return Err(KvError::Io(ErrorKind::UnexpectedEof.into()));
+                    }
+                    return Ok(Some(Message::TooLarge(frame_id(&head[..head_len]))));
+                }
+                msg.extend_from_slice(&header);
+                msg.resize(4 + len, 0);
+                rd.read_exact(&mut msg[4..])?;
+            }
+        }
+        Ok(Some(Message::Whole(msg)))
+    }
+
+    /// Returns the identifier of the JSON request starting with `head`, which may be cut short,
+    /// if it holds all of it.  The identifier comes first in the current schema.
+    fn json_id_prefix(self, head: &[u8]) -> Option<u64> {
+        if self == Encoding::LegacyJson {
+            return Some(0);
+        }
+        let rest = head.strip_prefix(b"{\"id\":")?;
+        let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == rest.len() {
+            // The identifier may go on past `head`.
+            return None;
+        }
+        std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()
+    }
+
+    /// Receives a request.  Returns `None` if the connection was closed between requests.
+    ///
+    /// Servers use `read_message()` and `decode_request()` instead to keep serving a connection
+    /// after a malformed request.
+    #[cfg(test)]
+    pub fn read_request(self, rd: &mut impl BufRead) -> Result<Option<Request>> {
+        match self.read_message(rd, MAX_FRAME_LEN as usize)? {
+            Some(msg) => self.decode_request(&msg).map(|(req, _)| Some(req)),
+            None => Ok(None),
+        }
+    }
+
+    /// Decodes request read by `read_message()` and returns it along with the encoding to reply
+    /// with.
+    ///
+    /// JSON requests in the legacy schema get identifier 0 and must be replied to with
+    /// `LegacyJson`.
+    pub fn decode_request(self, msg: &[u8]) -> Result<(Request, Encoding)> {
+        match self {
+            Encoding::Json => {
+                return match serde_json::from_slice(msg) {
+                    Ok(req) => Ok((req, self)),
+                    // Reports why the request is malformed in the current schema.
+                    Err(err) => Encoding::LegacyJson
+                        .decode_request(msg)
+                        .map_err(|_| err.into()),
+                };
+            }
+            Encoding::LegacyJson => {
+                let op = match serde_json::from_slice(msg)? {
+                    LegacyRequest::Get(key) => Op::Get(DEFAULT_NAMESPACE.to_owned(), key),
+                    LegacyRequest::Set(key, val) => Op::Set(DEFAULT_NAMESPACE.to_owned(), key, val),
+                    LegacyRequest::Rm(key) => Op::Rm(DEFAULT_NAMESPACE.to_owned(), key),
+                    LegacyRequest::Shutdown => Op::Shutdown,
+                };
+                return Ok((Request { id: 0, op }, self));
+            }
+            Encoding::Binary => (),
+        }
+
+        let (opcode, p) = frame_parts(msg)?;
+        let req = match opcode {
+            OP_GET => {
+                let (id, ns, key) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::Get(ns, key),
+                }
+            }
+            OP_SET => {
+                let (id, ns, key, val) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::Set(ns, key, val),
+                }
+            }
+            OP_RM => {
+                let (id, ns, key) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::Rm(ns, key),
+                }
+            }
+            OP_DROP_NAMESPACE => {
+                let (id, ns) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::DropNamespace(ns),
+                }
+            }
+            OP_STATS => Request {
+                id: bincode::deserialize(p)?,
+                op: Op::Stats,
+            },
+            OP_SET_STREAM => {
+                let (id, ns, key) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::SetStream(ns, key),
+                }
+            }
+            OP_GET_STREAM => {
+                let (id, ns, key) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::GetStream(ns, key),
+                }
+            }
+            OP_SHUTDOWN => Request {
+                id: bincode::deserialize(p)?,
+                op: Op::Shutdown,
+            },
+            OP_HELLO => {
+                let (id, version) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::Hello(version),
+                }
+            }
+            OP_AUTH => {
+                let (id, token) = bincode::deserialize(p)?;
+                Request {
+                    id,
+                    op: Op::Auth(token),
+                }
+            }
+            _ => return Err(bad_data(format!("unknown request opcode {}", opcode))),
+        };
+        Ok((req, self))
+    }
+
+    /// Appends `reply` to `buf`.
+    pub fn write_reply<T: Serialize>(self, buf: &mut Vec<u8>, reply: &Reply<T>) -> Result<()> {
+        match (self, &reply.result) {
+            (Encoding::Json, _) => write_json(buf, reply),
+            (Encoding::LegacyJson, result) => {
+                let result = result.as_ref().map_err(legacy_error);
+                write_json(buf, &result)
+            }
+            (Encoding::Binary, Ok(val)) => write_frame(buf, REPLY_OK, &(reply.id, val)),
+            (Encoding::Binary, Err(err)) => write_frame(buf, REPLY_ERR, &(reply.id, err)),
+        }
+    }
+
+    /// Returns the identifier of request or reply `msg` read by `read_message()`, without decoding
+    /// the rest of it.
+    pub fn message_id(self, msg: &[u8]) -> Result<u64> {
+        match self {
+            Encoding::LegacyJson => Ok(0),
+            Encoding::Json => {
+                #[derive(Deserialize)]
+                struct Id {
+                    id: u64,
+                }
+                Ok(serde_json::from_slice::<Id>(msg)?.id)
+            }
+            Encoding::Binary => Ok(bincode::deserialize(frame_parts(msg)?.1)?),
+        }
+    }
+
+    /// Receives a reply.  Fails if the connection was closed.
+    pub fn read_reply<T: DeserializeOwned>(self, rd: &mut impl BufRead) -> Result<Reply<T>> {
+        match self.read_message(rd, MAX_FRAME_LEN as usize)? {
+            Some(msg) => self.decode_reply(&msg),
+            None => Err(KvError::Io(ErrorKind::UnexpectedEof.into())),
+        }
+    }
+
+    /// Decodes reply read by `read_message()`.
+    pub fn decode_reply<T: DeserializeOwned>(self, msg: &[u8]) -> Result<Reply<T>> {
+        match self {
+            Encoding::Json => return Ok(serde_json::from_slice(msg)?),
+            Encoding::LegacyJson => {
+                let result: std::result::Result<T, String> = serde_json::from_slice(msg)?;
+                return Ok(Reply {
+                    id: 0,
+                    result: result.map_err(ReplyError::Other),
+                });
+            }
+            Encoding::Binary => (),
+        }
+
+        match frame_parts(msg)? {
+            (REPLY_OK, payload) => {
+                let (id, val) = bincode::deserialize(payload)?;
+                Ok(Reply {
+                    id,
+                    result: Ok(val),
+                })
+            }
+            (REPLY_ERR, payload) => {
+                let (id, err) = bincode::deserialize(payload)?;
+                Ok(Reply {
+                    id,
+                    result: Err(err),
+                })
+            }
+            (opcode, _) => Err(bad_data(format!("unknown reply opcode {}", opcode))),
+        }
+    }
+}
+
+/// Counterparts of `Encoding` and `ChunkReader` functions for asynchronous streams.
+///
+/// Messages are read whole and then decoded with the synchronous functions.
+#[cfg(feature = "async")]
+impl Encoding {
+    /// Asynchronous version of `detect()`.
+    pub async fn detect_async<R: AsyncBufRead + Unpin>(rd: &mut R) -> io::Result<Option<Encoding>> {
+        use tokio::io::AsyncBufReadExt;
+
+        Ok(rd
+            .fill_buf()
+            .await?
+            .first()
+            .map(|&first| Encoding::from_first_byte(first)))
+    }
+
+    /// Asynchronous version of `read_message_or_skip()`.
+    pub async fn read_message_or_skip_async<R: AsyncBufRead + Unpin>(
+        self,
+        rd: &mut R,
+        max_len: usize,
+    ) -> Result<Option<Message>> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+        let mut msg = Vec::new();
+        match self {
+            Encoding::Json | Encoding::LegacyJson => {
+                if (&mut *rd)
+                    .take(max_len as u64)
+                    .read_until(b'\n', &mut msg)
+                    .await?
+                    == 0
+                {
+                    return Ok(None);
+                }
+                if cut_short(&msg, max_len) {
+                    loop {
+                        let buf = rd.fill_buf().await?;
+                        let (len, done) = line_rest(buf);
+                        rd.consume(len);
+                        if done {
+                            break;
+                        }
+                    }
+                    return Ok(Some(Message::TooLarge(self.json_id_prefix(&msg))));
+                }
+            }
+            Encoding::Binary => {
+                if rd.fill_buf().await?.is_empty() {
+                    return Ok(None);
+                }
+                let mut header = [0; 4];
+                rd.read_exact(&mut header).await?;
+                let len = frame_len(header)?;
+                if 4 + len > max_len {
+                    let mut head = [0; FRAME_HEAD_LEN];
+                    let head_len = len.min(FRAME_HEAD_LEN);
+                    rd.read_exact(&mut head[..head_len]).await?;
+                    let rest = (len - head_len) as u64;
+                    let skipped =
+                        tokio::io::copy(&mut (&mut *rd).take(rest), &mut tokio::io::sink()).await?;
+                    if skipped < rest {
+                        return Err(KvError::Io(ErrorKind::UnexpectedEof.into()));
+                    }
+                    return Ok(Some(Message::TooLarge(frame_id(&head[..head_len]))));
+                }
+                msg.extend_from_slice(&header);
+                msg.resize(4 + len, 0);
+                rd.read_exact(&mut msg[4..]).await?;
+            }
+        }
+        Ok(Some(Message::Whole(msg)))
+    }
+
+    /// Asynchronous version of `read_message()`.
+    pub async fn read_message_async<R: AsyncBufRead + Unpin>(
+        self,
+        rd: &mut R,
+        max_len: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        match self.read_message_or_skip_async(rd, max_len).await? {
+            Some(Message::Whole(msg)) => Ok(Some(msg)),
+            Some(Message::TooLarge(_)) => Err(too_large(max_len)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Receives the size line of the next chunk sent by `ChunkWriter`, 0 meaning end of data.
+#[cfg(feature = "async")]
+pub async fn read_chunk_len<R: AsyncBufRead + Unpin>(rd: &mut R) -> Result<u64> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut line = String::new();
+    (&mut *rd)
+        .take(MAX_CHUNK_LINE_LEN)
+        .read_line(&mut line)
+        .await?;
+    Ok(parse_chunk_len(&line)?)
+}
+
+/// Asynchronous version of `ChunkReader::drain()`.  Fails if receiving all chunks takes longer
+/// than `timeout`.
+#[cfg(feature = "async")]
+pub async fn skip_chunks<R: AsyncBufRead + Unpin>(
+    rd: &mut R,
+    timeout: Option<Duration>,
+) -> Result<()> {
+    copy_chunks(rd, None, u64::MAX, timeout).await.map(|_| ())
+}
+
+/// Receives data sent by `ChunkWriter` on `rd` and sends it to `tx` piece by piece as it arrives.
+/// Returns the number of bytes received, or `None` if there were more than `max_len`, sending
+/// stopping as soon as that is known.  Fails if receiving all chunks takes longer than `timeout`.
+///
+/// All chunks are received even if `tx` is closed so that the next message can be read.
+#[cfg(feature = "async")]
+pub async fn copy_chunks<R: AsyncBufRead + Unpin>(
+    rd: &mut R,
+    mut tx: Option<&mpsc::Sender<Vec<u8>>>,
+    max_len: u64,
+    timeout: Option<Duration>,
+) -> Result<Option<u64>> {
+    use tokio::io::AsyncBufReadExt;
+
+    let copy = async {
+        let mut total = 0;
+        loop {
+            let mut left = read_chunk_len(rd).await?;
+            if left == 0 {
+                return Ok(Some(total).filter(|&total| total <= max_len));
+            }
+            while left > 0 {
+                let buf = rd.fill_buf().await?;
+                if buf.is_empty() {
+                    return Err(KvError::Io(ErrorKind::UnexpectedEof.into()));
+                }
+                let len = (buf.len() as u64).min(left) as usize;
+                total += len as u64;
+                if total > max_len {
+                    tx = None;
+                }
+                if let Some(sender) = tx {
+                    let piece = buf[..len].to_vec();
+                    if sender.send(piece).await.is_err() {
+                        tx = None;
+                    }
+                }
+                rd.consume(len);
+                left -= len as u64;
+            }
+        }
+    };
+    with_timeout(timeout, copy).await?
+}
+
+/// Runs `fut` to completion, failing if it takes longer than `timeout`.
+#[cfg(feature = "async")]
+pub async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl std::future::Future<Output = T>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "timed out")),
+        None => Ok(fut.await),
+    }
+}
+
+/// Describes `err` as servers predating typed errors did.
+fn legacy_error(err: &ReplyError) -> String {
+    match err {
+        ReplyError::Other(msg) => msg.clone(),
+        err => KvError::from(err.clone()).to_string(),
+    }
+}
+
+fn write_json(buf: &mut Vec<u8>, msg: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(&mut *buf, msg)?;
+    buf.push(b'\n');
+    Ok(())
+}
+
+/// Returns the size of `msg` once written by `write_json()`.
+fn json_len(msg: &impl Serialize) -> Result<u64> {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, msg)?;
+    Ok(counter.0 + 1)
+}
+
+/// Writer discarding bytes but counting them.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn write_frame(buf: &mut Vec<u8>, opcode: u8, payload: &impl Serialize) -> Result<()> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 4]);
+    buf.push(opcode);
+    bincode::serialize_into(&mut *buf, payload)?;
+    let len = buf.len() - start - 4;
+    if len > MAX_FRAME_LEN as usize {
+        buf.truncate(start);
+        return Err(KvError::Other(format!(
+            "message larger than {} bytes: stream large values instead",
+            MAX_FRAME_LEN
+        )));
+    }
+    buf[start..start + 4].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(())
+}
+
+/// Returns the length of the rest of a frame starting with `header`.
+fn frame_len(header: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(header);
+    if len == 0 || len > MAX_FRAME_LEN {
+        return Err(bad_data(format!("bad frame length {}", len)));
+    }
+    Ok(len as usize)
+}
+
+/// Length of the opcode and request identifier starting binary request payloads.
+const FRAME_HEAD_LEN: usize = 9;
+
+/// Returns the request identifier following the opcode in `head`, the start of the rest of a
+/// frame, if it is long enough to hold it.
+fn frame_id(head: &[u8]) -> Option<u64> {
+    bincode::deserialize(head.get(1..FRAME_HEAD_LEN)?).ok()
+}
+
+/// Returns whether JSON line `msg` read with at most `max_len` bytes was cut short.
+fn cut_short(msg: &[u8], max_len: usize) -> bool {
+    msg.len() == max_len && msg.last() != Some(&b'\n')
+}
+
+/// Consumes what is left of the current line on `rd`.
+fn skip_line(rd: &mut impl BufRead) -> io::Result<()> {
+    loop {
+        let (len, done) = line_rest(rd.fill_buf()?);
+        rd.consume(len);
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Returns how many bytes of `buf` belong to the current line and whether the line ends there,
+/// an empty buffer meaning the end of the stream.
+fn line_rest(buf: &[u8]) -> (usize, bool) {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => (end + 1, true),
+        None => (buf.len(), buf.is_empty()),
+    }
+}
+
+fn too_large(max_len: usize) -> KvError {
+    bad_data(format!("message larger than {} bytes", max_len))
+}
+
+/// Returns the opcode and the payload of frame `msg`.
+fn frame_parts(msg: &[u8]) -> Result<(u8, &[u8])> {
+    match msg.get(4..) {
+        Some([opcode, payload @ ..]) => Ok((*opcode, payload)),
+        _ => Err(bad_data("truncated frame".to_owned())),
+    }
+}
+
+fn bad_data(msg: String) -> KvError {
+    KvError::Io(io::Error::new(ErrorKind::InvalidData, msg))
+}
+
+/// Longest chunk size line: the 20 digits of the largest `u64` and a newline.
+const MAX_CHUNK_LINE_LEN: u64 = 21;
+
+/// Decodes chunk size `line` read with at most `MAX_CHUNK_LINE_LEN` bytes.
+fn parse_chunk_len(line: &str) -> io::Result<u64> {
+    if line.is_empty() {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    match line.strip_suffix('\n').map(str::parse) {
+        Some(Ok(len)) => Ok(len),
+        _ => Err(io::Error::new(ErrorKind::InvalidData, "bad chunk size")),
+    }
+}
+
+/// Sends data of unknown size as a sequence of chunks.
+///
+/// Each chunk is made of its size on its own line followed by its bytes.  An empty chunk marks the
+/// end of data.
+pub struct ChunkWriter<W: Write> {
+    wr: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(wr: W) -> ChunkWriter<W> {
+        ChunkWriter { wr }
+    }
+
+    /// Sends end of data marker.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.wr.write_all(b"0\n")?;
+        self.wr.flush()?;
+        Ok(self.wr)
+    }
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // An empty chunk would be mistaken for the end of data.
+        if !buf.is_empty() {
+            writeln!(self.wr, "{}", buf.len())?;
+            self.wr.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.wr.flush()
+    }
+}
+
+/// Receives data sent by `ChunkWriter`.
+pub struct ChunkReader<R: BufRead> {
+    rd: R,
+
+    /// Bytes left to read in current chunk.
+    remaining: u64,
+
+    /// Whether end of data marker was received.
+    done: bool,
+}
+
+impl<R: BufRead> ChunkReader<R> {
+    pub fn new(rd: R) -> ChunkReader<R> {
+        ChunkReader {
+            rd,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Skips data not read yet.
+    pub fn drain(&mut self) -> io::Result<()> {
+        io::copy(self, &mut io::sink()).map(|_| ())
+    }
+}
+
+impl<R: BufRead> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.remaining == 0 {
+            let mut line = String::new();
+            (&mut self.rd)
+                .take(MAX_CHUNK_LINE_LEN)
+                .read_line(&mut line)?;
+            self.remaining = parse_chunk_len(&line)?;
+            if self.remaining == 0 {
+                self.done = true;
+                return Ok(0);
+            }
+        }
+
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        let nread = self.rd.read(&mut buf[..len])?;
+        if nread == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= nread as u64;
+        Ok(nread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stats;
+
+    fn sample_ops() -> Vec<Op> {
+        vec![
+            Op::Get("ns".to_owned(), "key".to_owned()),
+            Op::Set("ns".to_owned(), "key".to_owned(), "val\n\u{0}".to_owned()),
+            Op::Rm("ns".to_owned(), "key".to_owned()),
+            Op::DropNamespace("ns".to_owned()),
+            Op::Stats,
+            Op::SetStream("ns".to_owned(), "key".to_owned()),
+            Op::GetStream("ns".to_owned(), "key".to_owned()),
+            Op::Shutdown,
+            Op::Hello(PROTOCOL_VERSION),
+            Op::Auth(Secret("token".to_owned())),
+        ]
+    }
+
+    #[test]
+    fn encodings() -> Result<()> {
+        for encoding in &[Encoding::Json, Encoding::Binary] {
+            let mut buf = Vec::new();
+            for (id, op) in sample_ops().into_iter().enumerate() {
+                let req = Request { id: id as u64, op };
+                encoding.write_request(&mut buf, &req)?;
+            }
+            let mut rd = &buf[..];
+            assert_eq!(Encoding::detect(&mut rd)?, Some(*encoding));
+            for (id, op) in sample_ops().into_iter().enumerate() {
+                let req = encoding.read_request(&mut rd)?.unwrap();
+                assert_eq!(req, Request { id: id as u64, op });
+            }
+            assert!(encoding.read_request(&mut rd)?.is_none());
+
+            let mut buf = Vec::new();
+            let value = Reply {
+                id: 1,
+                result: Ok(Some("val".to_owned())),
+            };
+            encoding.write_reply(&mut buf, &value)?;
+            assert_eq!(encoding.message_id(&buf)?, 1);
+            let stats = Reply::<Stats> {
+                id: 2,
+                result: Err(ReplyError::QuotaExceeded(10)),
+            };
+            encoding.write_reply(&mut buf, &stats)?;
+            let mut rd = &buf[..];
+            let reply = encoding.read_reply::<Option<String>>(&mut rd)?;
+            assert_eq!((reply.id, reply.result), (1, Ok(Some("val".to_owned()))));
+            let reply = encoding.read_reply::<Stats>(&mut rd)?;
+            assert_eq!(
+                (reply.id, reply.result),
+                (2, Err(ReplyError::QuotaExceeded(10)))
+            );
+            assert!(encoding.read_reply::<Stats>(&mut rd).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bad_frames() {
+        let mut huge = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+        huge.push(OP_GET);
+        assert!(Encoding::Binary.read_request(&mut &huge[..]).is_err());
+        let unknown = [0, 0, 0, 1, 0xff];
+        assert!(Encoding::Binary.read_request(&mut &unknown[..]).is_err());
+        let truncated = [0, 0, 0, 9, OP_STATS, 0];
+        assert!(Encoding::Binary.read_request(&mut &truncated[..]).is_err());
+    }
+
+    #[test]
+    fn malformed_requests() -> Result<()> {
+        for &encoding in &[Encoding::Json, Encoding::Binary] {
+            let mut buf = Vec::new();
+            encoding.write_request(
+                &mut buf,
+                &Request {
+                    id: 7,
+                    op: Op::Stats,
+                },
+            )?;
+            // Unknown operation.
+            match encoding {
+                Encoding::Json => buf.splice(buf.len() - 4.., b"Foo\"}\n".iter().copied()),
+                Encoding::Binary => buf.splice(4..5, b"\xff".iter().copied()),
+                Encoding::LegacyJson => unreachable!(),
+            };
+            encoding.write_request(
+                &mut buf,
+                &Request {
+                    id: 8,
+                    op: Op::Stats,
+                },
+            )?;
+
+            let mut rd = &buf[..];
+            let msg = encoding.read_message(&mut rd, 100)?.unwrap();
+            assert!(encoding.decode_request(&msg).is_err());
+            assert_eq!(encoding.message_id(&msg)?, 7);
+            let msg = encoding.read_message(&mut rd, 100)?.unwrap();
+            assert_eq!(encoding.decode_request(&msg)?.0.id, 8);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn legacy_json() -> Result<()> {
+        let msg = br#"{"Set":["key","value"]}"#;
+        let (req, encoding) = Encoding::Json.decode_request(msg)?;
+        assert_eq!(encoding, Encoding::LegacyJson);
+        assert_eq!(
+            req.op,
+            Op::Set("".to_owned(), "key".to_owned(), "value".to_owned())
+        );
+        let mut buf = Vec::new();
+        encoding.write_request(&mut buf, &req)?;
+        assert_eq!(buf, b"{\"Set\":[\"key\",\"value\"]}\n");
+        let ns_op = Op::Get("ns".to_owned(), "key".to_owned());
+        assert!(encoding
+            .write_request(&mut buf, &Request { id: 0, op: ns_op })
+            .is_err());
+
+        let mut buf = Vec::new();
+        let reply = Reply::<Option<String>> {
+            id: 0,
+            result: Err(ReplyError::QuotaExceeded(10)),
+        };
+        encoding.write_reply(&mut buf, &reply)?;
+        let reply: Reply<Option<String>> = encoding.decode_reply(&buf)?;
+        assert_eq!(
+            reply.result,
+            Err(ReplyError::Other(KvError::QuotaExceeded(10).to_string()))
+        );
+
+        assert!(Encoding::Json.decode_request(br#"{"Foo":"key"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_messages() -> Result<()> {
+        for &encoding in &[Encoding::Json, Encoding::Binary] {
+            let mut buf = Vec::new();
+            encoding.write_request(
+                &mut buf,
+                &Request {
+                    id: 1,
+                    op: Op::Stats,
+                },
+            )?;
+            let len = buf.len();
+            assert!(encoding.read_message(&mut &buf[..], len)?.is_some());
+            assert!(encoding.read_message(&mut &buf[..], len - 1).is_err());
+
+            // Oversized requests are skipped, keeping their identifier.
+            encoding.write_request(
+                &mut buf,
+                &Request {
+                    id: 2,
+                    op: Op::Stats,
+                },
+            )?;
+            let mut rd = &buf[..];
+            assert_eq!(
+                encoding.read_message_or_skip(&mut rd, len - 1)?,
+                Some(Message::TooLarge(Some(1)))
+            );
+            let msg = encoding.read_message_or_skip(&mut rd, len)?;
+            match msg {
+                Some(Message::Whole(msg)) => assert_eq!(encoding.message_id(&msg)?, 2),
+                msg => panic!("unexpected {:?}", msg),
+            }
+            assert_eq!(encoding.read_message_or_skip(&mut rd, len)?, None);
+            if encoding == Encoding::Json {
+                // Too little of the line was read to tell the identifier.
+                assert_eq!(
+                    encoding.read_message_or_skip(&mut &buf[..], 7)?,
+                    Some(Message::TooLarge(None))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn set_request_len() -> Result<()> {
+        for &encoding in &[Encoding::Json, Encoding::Binary, Encoding::LegacyJson] {
+            let req = Request {
+                id: 12345,
+                op: Op::Set(
+                    DEFAULT_NAMESPACE.to_owned(),
+                    "key".to_owned(),
+                    "\"value\"\n".to_owned(),
+                ),
+            };
+            let mut buf = Vec::new();
+            encoding.write_request(&mut buf, &req)?;
+            assert_eq!(
+                encoding.set_request_len(12345, DEFAULT_NAMESPACE, "key", "\"value\"\n")?,
+                buf.len() as u64
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn secrets() {
+        let secret = Secret("hunter2".to_owned());
+        assert!(secret.matches("hunter2"));
+        assert!(!secret.matches("hunter3"));
+        assert!(!secret.matches("hunter"));
+        assert!(!format!("{:?}", Op::Auth(secret)).contains("hunter2"));
+    }
+
+    #[test]
+    fn chunks() -> io::Result<()> {
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut wr = ChunkWriter::new(Vec::new());
+        io::copy(&mut &data[..], &mut wr)?;
+        let mut ser = wr.finish()?;
+        ser.extend_from_slice(b"trailer");
+
+        let mut rd = ChunkReader::new(&ser[..]);
+        let mut received = Vec::new();
+        rd.read_to_end(&mut received)?;
+        assert_eq!(received, data);
+        let mut trailer = String::new();
+        rd.rd.read_to_string(&mut trailer)?;
+        assert_eq!(trailer, "trailer");
+
+        let long_line = format!("{}\nx", "1".repeat(1000));
+        let mut rd = ChunkReader::new(long_line.as_bytes());
+        let err = rd.read_to_end(&mut received).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let mut rd = ChunkReader::new(&b"10\nabc"[..]);
+        let err = rd.read_to_end(&mut received).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+}
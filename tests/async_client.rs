@@ -0,0 +1,215 @@
+use kvs::{
+    AsyncKvsClient, AsyncKvsServer, KvError, KvStore, KvsEngine, KvsServer, KvsServerOptions,
+    SharedQueueThreadPool, ThreadPool,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::task::JoinSet;
+
+fn start_server(
+    port: u16,
+) -> (
+    TempDir,
+    SocketAddr,
+    std::thread::JoinHandle<kvs::Result<()>>,
+) {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    (tmpdir, addr, server_thread)
+}
+
+#[tokio::test]
+async fn operations() {
+    let (_tmpdir, addr, server_thread) = start_server(5200);
+    let mut client = AsyncKvsClient::new(addr);
+    let info = client.server_info().await.unwrap();
+    assert_eq!(info.protocol_version, kvs::PROTOCOL_VERSION);
+
+    client.set("key1", "value1").await.unwrap();
+    assert_eq!(
+        client.get("key1").await.unwrap(),
+        Some("value1".to_string())
+    );
+    client.rm("key1").await.unwrap();
+    assert_eq!(client.get("key1").await.unwrap(), None);
+    assert!(matches!(client.rm("key1").await, Err(KvError::Server(_))));
+
+    let other = client.clone();
+    client.set_namespace("ns");
+    client.set("key2", "value2").await.unwrap();
+    assert_eq!(other.get("key2").await.unwrap(), None);
+    assert_eq!(client.stats().await.unwrap().live_keys, 1);
+    client.drop_namespace("ns").await.unwrap();
+    assert_eq!(client.get("key2").await.unwrap(), None);
+
+    let value: Vec<u8> = (0..300_000u32).map(|i| i as u8).collect();
+    client.set_reader("large", &mut &value[..]).await.unwrap();
+    let mut received = Vec::new();
+    assert!(client.get_writer("large", &mut received).await.unwrap());
+    assert_eq!(received, value);
+    assert!(!client.get_writer("missing", &mut received).await.unwrap());
+    // Streamed since larger than the server accepts in a single request.
+    let value = "V".repeat(1024 * 1024);
+    client.set("large", &value).await.unwrap();
+    assert_eq!(client.get("large").await.unwrap(), Some(value));
+
+    client.shutdown().await.unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn pipelining() {
+    let (_tmpdir, addr, server_thread) = start_server(5201);
+    let client = AsyncKvsClient::new(addr);
+    let mut tasks = JoinSet::new();
+    for i in 0..500 {
+        let client = client.clone();
+        tasks.spawn(async move {
+            let key = format!("key{}", i);
+            client.set(&key, &format!("value{}", i)).await.unwrap();
+            assert_eq!(client.get(&key).await.unwrap(), Some(format!("value{}", i)));
+        });
+    }
+    while let Some(res) = tasks.join_next().await {
+        res.unwrap();
+    }
+    assert_eq!(client.stats().await.unwrap().live_keys, 500);
+    client.shutdown().await.unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn reconnect_after_server_restart() {
+    let tmpdir = TempDir::new().unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5202));
+    let client = AsyncKvsClient::new(addr);
+    for i in 0..2 {
+        let engine = KvStore::open(&tmpdir).unwrap();
+        let pool = SharedQueueThreadPool::new(2).unwrap();
+        let mut server = KvsServer::new(engine, pool, addr).unwrap();
+        let server_thread = std::thread::spawn(move || server.run());
+        client.set(&format!("key{}", i), "value").await.unwrap();
+        assert_eq!(client.get("key0").await.unwrap(), Some("value".to_string()));
+        client.shutdown().await.unwrap();
+        assert!(server_thread.join().unwrap().is_ok());
+    }
+}
+
+#[tokio::test]
+async fn dropped_client_closes_connection() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5205));
+    // Connections beyond the first one are closed by the server until it is.
+    let options = KvsServerOptions {
+        max_connections: 1,
+        ..KvsServerOptions::default()
+    };
+    let mut server = KvsServer::new_with(engine, pool, addr, options).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let client = AsyncKvsClient::new(addr);
+    let clone = client.clone();
+    client.set("key", "value").await.unwrap();
+    drop(client);
+    assert_eq!(clone.get("key").await.unwrap(), Some("value".to_string()));
+    drop(clone);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let other = AsyncKvsClient::new(addr);
+    loop {
+        match other.get("key").await {
+            Ok(val) => {
+                assert_eq!(val, Some("value".to_string()));
+                break;
+            }
+            Err(_) if Instant::now() < deadline => {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Err(err) => panic!("connection of dropped client still open: {}", err),
+        }
+    }
+    other.shutdown().await.unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn async_server() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5203));
+    let mut server = AsyncKvsServer::new(engine, pool, addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    let client = AsyncKvsClient::new(addr);
+    client.set("key", "value").await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+    client.shutdown().await.unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn authentication() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5204));
+    let options = KvsServerOptions {
+        token: Some("user".to_string()),
+        admin_token: Some("admin".to_string()),
+        ..KvsServerOptions::default()
+    };
+    let mut server = AsyncKvsServer::new_with(engine, pool, addr, options).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let client = AsyncKvsClient::new(addr);
+    assert!(matches!(
+        client.get("key").await,
+        Err(KvError::Unauthenticated)
+    ));
+    assert!(matches!(
+        client.set_reader("key", &mut &b"value"[..]).await,
+        Err(KvError::Unauthenticated)
+    ));
+    client.set_token("wrong").await.unwrap();
+    assert!(matches!(
+        client.get("key").await,
+        Err(KvError::Unauthenticated)
+    ));
+
+    client.set_token("user").await.unwrap();
+    client.set_reader("key", &mut &b"value"[..]).await.unwrap();
+    assert_eq!(client.get("key").await.unwrap(), Some("value".to_string()));
+    assert!(matches!(
+        client.shutdown().await,
+        Err(KvError::PermissionDenied)
+    ));
+
+    client.clone().set_token("admin").await.unwrap();
+    client.shutdown().await.unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn server_predating_handshake() {
+    // Closes the connection on receiving a request it can not decode.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 4];
+        std::io::Read::read_exact(&mut stream, &mut buf).unwrap();
+    });
+    let client = AsyncKvsClient::new(addr);
+    assert!(matches!(
+        client.get("key").await,
+        Err(KvError::IncompatibleServer(_))
+    ));
+    server.join().unwrap();
+}
@@ -0,0 +1,218 @@
+use kvs::{
+    AsyncKvsServer, KvError, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServerOptions,
+    Limits, SharedQueueThreadPool, ThreadPool,
+};
+use std::io::{prelude::*, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start(
+    port: u16,
+) -> (
+    TempDir,
+    SocketAddr,
+    std::thread::JoinHandle<kvs::Result<()>>,
+) {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let options = KvsServerOptions {
+        engine_name: Some("kvs".to_string()),
+        ..KvsServerOptions::default()
+    };
+    let mut server = AsyncKvsServer::new_with(engine, pool, addr, options).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    (tmpdir, addr, server_thread)
+}
+
+#[test]
+fn operations() {
+    let (_tmpdir, addr, server_thread) = start(5100);
+    let mut client = KvsClient::new(addr).unwrap();
+    assert_eq!(client.server_info().unwrap().engine.as_deref(), Some("kvs"));
+    client.set("key1", "value1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_string()));
+    assert!(client.rm("missing").is_err());
+    client.set_namespace("ns");
+    assert_eq!(client.get("key1").unwrap(), None);
+    client.set("key1", "value2").unwrap();
+    assert_eq!(client.stats().unwrap().live_keys, 2);
+
+    let value: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+    client.set_reader("large", &mut &value[..]).unwrap();
+    let mut received = Vec::new();
+    assert!(client.get_writer("large", &mut received).unwrap());
+    assert_eq!(received, value);
+
+    let mut pipeline = client.pipeline().unwrap();
+    for i in 0..200 {
+        pipeline.set(&format!("K{}", i), "V").unwrap();
+    }
+    assert!(pipeline.finish().unwrap().iter().all(|res| res.is_ok()));
+    assert_eq!(client.get("K199").unwrap(), Some("V".to_string()));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn many_idle_connections() {
+    let (_tmpdir, addr, server_thread) = start(5101);
+    let idle: Vec<_> = (0..1000)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+
+    // Shutdown does not wait for idle clients to leave.
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+    drop(idle);
+}
+
+#[test]
+fn json_clients() {
+    let (_tmpdir, addr, server_thread) = start(5102);
+    let stream = TcpStream::connect(addr).unwrap();
+    writeln!(&stream, r#"{{"id":1,"op":{{"Set":["","key","value"]}}}}"#).unwrap();
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "{\"id\":1,\"result\":{\"Ok\":null}}\n");
+
+    let mut client = KvsClient::new(addr).unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn shutdown_handle() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5103));
+    let options = KvsServerOptions {
+        drain_timeout: Some(Duration::from_millis(200)),
+        ..KvsServerOptions::default()
+    };
+    let mut server = AsyncKvsServer::new_with(engine.clone(), pool, addr, options).unwrap();
+    let handle = server.shutdown_handle();
+    let server_thread = std::thread::spawn(move || server.run());
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+
+    // Starts streaming a value and never completes it.
+    let stalled = TcpStream::connect(addr).unwrap();
+    writeln!(&stalled, r#"{{"id":1,"op":{{"SetStream":["","big"]}}}}"#).unwrap();
+    writeln!(&stalled, "1000").unwrap();
+    std::thread::sleep(Duration::from_millis(100));
+
+    let start = Instant::now();
+    handle.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(
+        engine.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn stream_over_limit() {
+    let tmpdir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        limits: Limits {
+            max_key_len: Some(8),
+            max_value_size: Some(1000),
+            ..Limits::default()
+        },
+        ..KvStoreOptions::default()
+    };
+    let engine = KvStore::open_with(&tmpdir, options).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], 5104));
+    let mut server = AsyncKvsServer::new(engine, pool, addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    // Rejected once past the limit, the rest of the value being skipped.
+    let mut client = KvsClient::new(addr).unwrap();
+    let value = vec![0u8; 10_000_000];
+    assert!(matches!(
+        client.set_reader("large", &mut &value[..]),
+        Err(KvError::ValueTooLarge(1000))
+    ));
+    // Refused before receiving the value.
+    assert!(matches!(
+        client.set_reader("too long key", &mut &value[..]),
+        Err(KvError::KeyTooLong(8))
+    ));
+    client.set("key", "value").unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+/// Value sent by a client that stalls until `release` is dropped.
+struct StalledValue {
+    sent: bool,
+    release: std::sync::mpsc::Receiver<()>,
+}
+
+impl Read for StalledValue {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.sent {
+            self.sent = true;
+            buf[0] = b'x';
+            return Ok(1);
+        }
+        let _ = self.release.recv();
+        Ok(0)
+    }
+}
+
+#[test]
+fn slow_streams_leave_pool_free() {
+    const POOL_SIZE: usize = 2;
+    let (_tmpdir, addr, server_thread) = start(5105);
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+
+    // As many clients as threads in the pool start streaming a value and stall.
+    let mut releases = Vec::new();
+    let mut stalled = Vec::new();
+    for i in 0..POOL_SIZE {
+        let (release, rx) = std::sync::mpsc::channel();
+        releases.push(release);
+        stalled.push(std::thread::spawn(move || {
+            let mut client = KvsClient::new(addr).unwrap();
+            let mut value = StalledValue {
+                sent: false,
+                release: rx,
+            };
+            client.set_reader(&format!("stalled{}", i), &mut value)
+        }));
+    }
+    std::thread::sleep(Duration::from_millis(200));
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(client.get("key").map(|val| (val, client)));
+    });
+    let (val, mut client) = rx
+        .recv_timeout(Duration::from_secs(5))
+        .expect("get blocked by stalled streams")
+        .unwrap();
+    assert_eq!(val, Some("value".to_string()));
+
+    drop(releases);
+    for thread in stalled {
+        thread.join().unwrap().unwrap();
+    }
+    assert_eq!(client.get("stalled0").unwrap(), Some("x".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
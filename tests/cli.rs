@@ -20,28 +20,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +52,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +91,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +123,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +134,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +145,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +157,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().unwrap();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +179,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +199,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +220,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +241,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +249,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +257,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +265,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +273,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +281,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +289,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +302,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +340,431 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// The memory engine serves requests but leaves nothing behind on disk.
+#[test]
+fn cli_access_server_mem_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4006";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
+
+// The event-loop server serves the same clients.
+#[cfg(feature = "async")]
+#[test]
+fn cli_async_runtime() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4007";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--runtime", "async", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// SIGTERM stops the server gracefully whatever its runtime.
+#[cfg(unix)]
+#[test]
+fn cli_sigterm() {
+    let runtimes = [("sync", "127.0.0.1:4008"), ("async", "127.0.0.1:4009")];
+    for (runtime, addr) in runtimes
+        .iter()
+        .filter(|(runtime, _)| cfg!(feature = "async") || *runtime == "sync")
+    {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--runtime", runtime, "--addr", addr, "--drain-timeout", "5"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+
+        Command::new("kill")
+            .args(["-TERM", &child.id().to_string()])
+            .assert()
+            .success();
+        assert!(child.wait().unwrap().success());
+
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args(["get", "key1"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+}
+
+// Servers started with tokens serve only clients presenting them.
+#[test]
+fn cli_tokens() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("token"), "user\n").unwrap();
+    fs::write(temp_dir.path().join("admin-token"), "admin\n").unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--token-file", "token"])
+        .args(["--admin-token-file", "admin-token"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("token"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr, "--token", "user"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .env("KVS_TOKEN", "user")
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shutdown", "--addr", addr])
+        .env("KVS_TOKEN", "user")
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shutdown", "--addr", addr, "--token", "admin"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn cli_namespace() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--namespace", "ns1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--namespace", "ns1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["drop-namespace", "ns1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--namespace", "ns1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+}
+
+// `kvs --data-dir` should work from any directory and record the engine in the manifest.
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("store");
+    let data_dir = data_dir.to_str().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args([
+            "--engine",
+            "sled",
+            "--data-dir",
+            data_dir,
+            "set",
+            "key1",
+            "value1",
+        ])
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    let manifest = fs::read_to_string(temp_dir.path().join("store").join("manifest.json")).unwrap();
+    assert!(manifest.contains("\"sled\""));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--data-dir", data_dir])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs", "get", "key1", "--data-dir", data_dir])
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_upgrade() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::create_dir(temp_dir.path().join("pna-kvs")).unwrap();
+    fs::write(
+        temp_dir.path().join("pna-kvs").join("kv.db"),
+        "{\"tag\":\"Set\",\"key\":\"key1\",\"value_size\":9}\n\"value1\"\n",
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs upgrade"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["upgrade"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn cli_fsck() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("clean"));
+
+    let log = temp_dir.path().join("pna-kvs").join("kv.db");
+    let mut content = fs::read(&log).unwrap();
+    content.extend_from_slice(b"garbage\n");
+    fs::write(&log, content).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair-to", "repaired.db"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("damaged"));
+
+    fs::rename(temp_dir.path().join("repaired.db"), &log).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
+
+#[test]
+fn cli_fsck_without_store() {
+    let temp_dir = TempDir::new().unwrap();
+    for cmd in &["fsck", "upgrade"] {
+        Command::cargo_bin("kvs")
+            .unwrap()
+            .args([cmd])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("no store found"));
+    }
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let log = temp_dir.path().join("pna-kvs").join("kv.db");
+    let content = fs::read(&log).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck", "--repair-to", "pna-kvs/kv.db"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert_eq!(fs::read(&log).unwrap(), content);
+}
+
+#[test]
+fn cli_fsck_sharded() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "kvs-sharded", "set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("live keys: 1"))
+        .stdout(contains("clean"));
+
+    let log = temp_dir
+        .path()
+        .join("pna-kvs-sharded")
+        .join("shard-0")
+        .join("kv.db");
+    let mut content = fs::read(&log).unwrap();
+    content.extend_from_slice(b"garbage\n");
+    fs::write(&log, content).unwrap();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["fsck"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stdout(contains("shard 0: corrupt region"))
+        .stdout(contains("damaged"));
+}
+
+// `kvs migrate` should carry data over to the new engine and make it the store engine.
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Migrated 1 keys"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--engine", "sled", "get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+}
@@ -1,5 +1,9 @@
-use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SharedQueueThreadPool, ThreadPool};
-use std::net::SocketAddr;
+use kvs::{
+    KvError, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsServer, KvsServerOptions, Limits,
+    SharedQueueThreadPool, ThreadPool, DEFAULT_NAMESPACE,
+};
+use std::io::{prelude::*, BufReader};
+use std::net::{SocketAddr, TcpStream};
 use tempfile::TempDir;
 
 #[test]
@@ -15,6 +19,46 @@ fn shutdown() {
     assert!(server_thread.join().is_ok());
 }
 
+#[test]
+fn shutdown_handle() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5010".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server_thread = std::thread::spawn(move || server.run());
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    handle.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+    assert!(client.get("key").is_err());
+
+    // Requesting shutdown before running makes the server stop at once.
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let mut server = KvsServer::new(engine.clone(), pool, addr).unwrap();
+    server.shutdown_handle().shutdown().unwrap();
+    assert!(server.run().is_ok());
+    assert_eq!(
+        engine.get("key".to_owned()).unwrap(),
+        Some("value".to_owned())
+    );
+}
+
+#[test]
+fn shutdown_ipv6_wildcard() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "[::]:0".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let handle = server.shutdown_handle();
+    let server_thread = std::thread::spawn(move || server.run());
+    handle.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
 #[test]
 fn several_operations_from_single_client() {
     let tmpdir = TempDir::new().unwrap();
@@ -29,3 +73,282 @@ fn several_operations_from_single_client() {
     client.shutdown().unwrap();
     assert!(server_thread.join().is_ok());
 }
+
+#[test]
+fn namespaces() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5002".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    client.set("K1", "V1").unwrap();
+    client.set_namespace("NS");
+    assert_eq!(client.get("K1").unwrap(), None);
+    client.set("K1", "V2").unwrap();
+    assert_eq!(client.get("K1").unwrap(), Some("V2".to_string()));
+    client.drop_namespace("NS").unwrap();
+    assert_eq!(client.get("K1").unwrap(), None);
+    client.set_namespace(DEFAULT_NAMESPACE);
+    assert_eq!(client.get("K1").unwrap(), Some("V1".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn stats() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5003".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    client.set("K1", "V1").unwrap();
+    client.set("K1", "V2").unwrap();
+    client.set("K2", "V3").unwrap();
+    let stats = client.stats().unwrap();
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.dead_entries, Some(1));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn streaming() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5004".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    let value: Vec<u8> = (0..1_000_000).map(|i| i as u8).collect();
+    client.set_reader("K1", &mut &value[..]).unwrap();
+    let mut received = Vec::new();
+    assert!(client.get_writer("K1", &mut received).unwrap());
+    assert_eq!(received, value);
+    assert!(!client.get_writer("K2", &mut received).unwrap());
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn large_values() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = "127.0.0.1:5012".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    // Larger than the server accepts in a single request.
+    let value = "V".repeat(1024 * 1024);
+    client.set("K1", &value).unwrap();
+    assert_eq!(client.get("K1").unwrap(), Some(value.clone()));
+
+    let mut pipeline = client.pipeline().unwrap();
+    pipeline.set("K2", "small").unwrap();
+    pipeline.set("K3", &value).unwrap();
+    pipeline.set("K4", "small").unwrap();
+    let results = pipeline.finish().unwrap();
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|res| matches!(res, Ok(None))));
+    assert_eq!(client.get("K3").unwrap(), Some(value));
+    assert_eq!(client.get("K4").unwrap(), Some("small".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn limits() {
+    let tmpdir = TempDir::new().unwrap();
+    let options = KvStoreOptions {
+        limits: Limits {
+            max_key_len: Some(8),
+            max_value_size: Some(1000),
+            max_store_size: Some(10_000),
+        },
+        ..KvStoreOptions::default()
+    };
+    let engine = KvStore::open_with(&tmpdir, options).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5005".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    assert!(matches!(
+        client.set("too long key", "V"),
+        Err(KvError::KeyTooLong(8))
+    ));
+    let value = vec![b'V'; 1001];
+    assert!(matches!(
+        client.set_reader("K", &mut &value[..]),
+        Err(KvError::ValueTooLarge(1000))
+    ));
+    let value = "V".repeat(1000);
+    let mut i = 0;
+    while client.set(&format!("K{}", i), &value).is_ok() {
+        i += 1;
+    }
+    assert!(matches!(
+        client.set("K", "V"),
+        Err(KvError::QuotaExceeded(10_000))
+    ));
+    assert_eq!(client.get("K0").unwrap(), Some(value));
+    client.rm("K0").unwrap();
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn reconnect_after_server_restart() {
+    let tmpdir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:5006".parse::<SocketAddr>().unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    for i in 0..2 {
+        let engine = KvStore::open(&tmpdir).unwrap();
+        let pool = SharedQueueThreadPool::new(2).unwrap();
+        let mut server = KvsServer::new(engine, pool, addr).unwrap();
+        let server_thread = std::thread::spawn(move || server.run());
+        // The connection opened by the first iteration was closed by the server shutdown.
+        client.set(&format!("K{}", i), "V").unwrap();
+        assert_eq!(client.get("K0").unwrap(), Some("V".to_string()));
+        KvsClient::new(addr).unwrap().shutdown().unwrap();
+        assert!(server_thread.join().is_ok());
+    }
+}
+
+#[test]
+fn pipeline() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(4).unwrap();
+    let addr = "127.0.0.1:5007".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let mut client = KvsClient::new(addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let mut pipeline = client.pipeline().unwrap();
+    for i in 0..500 {
+        pipeline
+            .set(&format!("K{}", i), &format!("V{}", i))
+            .unwrap();
+    }
+    let results = pipeline.finish().unwrap();
+    assert_eq!(results.len(), 500);
+    assert!(results.iter().all(|res| matches!(res, Ok(None))));
+
+    let mut pipeline = client.pipeline().unwrap();
+    pipeline.get("K1").unwrap();
+    pipeline.rm("K0").unwrap();
+    pipeline.rm("missing").unwrap();
+    pipeline.get("K499").unwrap();
+    let results = pipeline.finish().unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), &Some("V1".to_string()));
+    assert!(results[1].is_ok());
+    assert!(results[2].is_err());
+    assert_eq!(results[3].as_ref().unwrap(), &Some("V499".to_string()));
+    assert_eq!(client.get("K0").unwrap(), None);
+
+    // Dropping an unfinished pipeline must not confuse later requests.
+    let mut pipeline = client.pipeline().unwrap();
+    pipeline.get("K2").unwrap();
+    drop(pipeline);
+    assert_eq!(client.get("K3").unwrap(), Some("V3".to_string()));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn json_clients() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = "127.0.0.1:5008".parse::<SocketAddr>().unwrap();
+    let mut server = KvsServer::new(engine, pool, addr).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut rd = BufReader::new(stream.try_clone().unwrap());
+    let mut exchange = |req: &str| {
+        writeln!(&stream, "{}", req).unwrap();
+        let mut reply = String::new();
+        rd.read_line(&mut reply).unwrap();
+        reply
+    };
+    assert_eq!(
+        exchange(r#"{"id":1,"op":{"Set":["","key","value"]}}"#),
+        "{\"id\":1,\"result\":{\"Ok\":null}}\n"
+    );
+    assert_eq!(
+        exchange(r#"{"id":2,"op":{"Get":["","key"]}}"#),
+        "{\"id\":2,\"result\":{\"Ok\":\"value\"}}\n"
+    );
+
+    // Binary clients see what JSON ones wrote.
+    let mut client = KvsClient::new(addr).unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn handshake() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5009".parse::<SocketAddr>().unwrap();
+    let options = KvsServerOptions {
+        engine_name: Some("kvs".to_string()),
+        ..KvsServerOptions::default()
+    };
+    let mut server = KvsServer::new_with(engine, pool, addr, options).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+    let mut client = KvsClient::new(addr).unwrap();
+    let info = client.server_info().unwrap();
+    assert_eq!(info.protocol_version, kvs::PROTOCOL_VERSION);
+    assert_eq!(info.server_version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(info.engine.as_deref(), Some("kvs"));
+    assert!(info.features.iter().any(|feature| feature == "streaming"));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().is_ok());
+}
+
+#[test]
+fn authentication() {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(1).unwrap();
+    let addr = "127.0.0.1:5011".parse::<SocketAddr>().unwrap();
+    let options = KvsServerOptions {
+        token: Some("user".to_string()),
+        admin_token: Some("admin".to_string()),
+        ..KvsServerOptions::default()
+    };
+    let mut server = KvsServer::new_with(engine, pool, addr, options).unwrap();
+    let server_thread = std::thread::spawn(move || server.run());
+
+    let mut client = KvsClient::new(addr).unwrap();
+    assert!(client.server_info().is_ok());
+    assert!(matches!(client.get("key"), Err(KvError::Unauthenticated)));
+    assert!(matches!(
+        client.set_reader("key", &mut &b"value"[..]),
+        Err(KvError::Unauthenticated)
+    ));
+    assert!(matches!(client.shutdown(), Err(KvError::Unauthenticated)));
+    client.set_token("wrong");
+    assert!(matches!(client.get("key"), Err(KvError::Unauthenticated)));
+
+    client.set_token("user");
+    client.set("key", "value").unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    assert!(matches!(client.shutdown(), Err(KvError::PermissionDenied)));
+
+    client.set_token("admin");
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
@@ -0,0 +1,82 @@
+use kvs::{
+    testing, BoxedKvsEngine, KvError, KvStore, KvStoreOptions, KvsEngine, MemKvsEngine, Result,
+    SledKvsEngine, DEFAULT_NAMESPACE,
+};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[test]
+fn kvs_engine() -> Result<()> {
+    testing::run_all::<KvStore>(true)
+}
+
+#[test]
+fn sharded_kvs_engine() -> Result<()> {
+    fn open(path: &Path) -> Result<KvStore> {
+        let options = KvStoreOptions {
+            shards: 4,
+            ..KvStoreOptions::default()
+        };
+        KvStore::open_with(path, options)
+    }
+    testing::run_all_with(open, true)
+}
+
+#[test]
+fn sled_engine() -> Result<()> {
+    testing::run_all::<SledKvsEngine>(true)
+}
+
+#[test]
+fn mem_engine() -> Result<()> {
+    testing::run_all::<MemKvsEngine>(false)
+}
+
+#[test]
+fn boxed_engine() -> Result<()> {
+    testing::run_all::<BoxedKvsEngine>(true)
+}
+
+/// Engine implementing only the required methods, as third-party engines written before
+/// namespaces were added do.
+#[derive(Clone, Default)]
+struct MinimalEngine(Arc<Mutex<HashMap<String, String>>>);
+
+impl KvsEngine for MinimalEngine {
+    fn open<P: AsRef<Path>>(_path: P) -> Result<MinimalEngine> {
+        Ok(MinimalEngine::default())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.lock()?.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.lock()?.get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0
+            .lock()?
+            .remove(&key)
+            .map(|_| ())
+            .ok_or(KvError::KeyNotFound(key))
+    }
+}
+
+#[test]
+fn default_methods() -> Result<()> {
+    let engine = MinimalEngine::default();
+    engine
+        .namespace(DEFAULT_NAMESPACE)?
+        .set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+    assert!(engine.namespace("ns").is_err());
+    assert_eq!(engine.namespaces()?, vec![DEFAULT_NAMESPACE.to_owned()]);
+    assert!(engine.keys().is_err());
+    assert!(engine.stats().is_err());
+    assert!(engine.drop_namespace(DEFAULT_NAMESPACE).is_err());
+    Ok(())
+}
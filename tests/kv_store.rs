@@ -209,3 +209,38 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Keys in distinct namespaces should not collide and dropping a namespace should leave others
+// untouched.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let ns1 = store.namespace("ns1")?;
+    let ns2 = store.namespace("ns2")?;
+
+    store.set("key1".to_owned(), "value0".to_owned())?;
+    ns1.set("key1".to_owned(), "value1".to_owned())?;
+    ns2.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(ns1.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(ns2.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    store.drop_namespace("ns1")?;
+    assert_eq!(ns1.get("key1".to_owned())?, None);
+    assert!(ns1.remove("key1".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(ns1);
+    drop(ns2);
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value0".to_owned()));
+    assert_eq!(store.namespace("ns1")?.get("key1".to_owned())?, None);
+    assert_eq!(
+        store.namespace("ns2")?.get("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+
+    Ok(())
+}
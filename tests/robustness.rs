@@ -0,0 +1,486 @@
+#[cfg(feature = "async")]
+use kvs::AsyncKvsServer;
+use kvs::{
+    KvStore, KvsClient, KvsEngine, KvsServer, KvsServerOptions, SharedQueueThreadPool, ThreadPool,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::io::{prelude::*, BufReader};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Starts a server on `port`, event-loop based if `async_runtime` is true.
+fn start(async_runtime: bool, port: u16) -> (TempDir, SocketAddr, JoinHandle<kvs::Result<()>>) {
+    start_with(async_runtime, port, KvsServerOptions::default())
+}
+
+fn start_with(
+    async_runtime: bool,
+    port: u16,
+    options: KvsServerOptions,
+) -> (TempDir, SocketAddr, JoinHandle<kvs::Result<()>>) {
+    let tmpdir = TempDir::new().unwrap();
+    let engine = KvStore::open(&tmpdir).unwrap();
+    let pool = SharedQueueThreadPool::new(2).unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server_thread = if async_runtime {
+        #[cfg(feature = "async")]
+        {
+            let mut server = AsyncKvsServer::new_with(engine, pool, addr, options).unwrap();
+            std::thread::spawn(move || server.run())
+        }
+        #[cfg(not(feature = "async"))]
+        panic!("built without the async feature")
+    } else {
+        let mut server = KvsServer::new_with(engine, pool, addr, options).unwrap();
+        std::thread::spawn(move || server.run())
+    };
+    (tmpdir, addr, server_thread)
+}
+
+/// Returns random garbage, some of it looking like the start of a valid request.
+fn garbage(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(1, 200);
+    let mut bytes: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+    match rng.gen_range(0, 4) {
+        // JSON-like line.
+        0 => {
+            bytes.insert(0, b'{');
+            bytes.push(b'\n');
+        }
+        // Binary frame with a valid length and random opcode and payload.
+        1 => {
+            // Random payloads may well be valid shutdown requests.
+            const OP_SHUTDOWN: u8 = 8;
+            if bytes[0] == OP_SHUTDOWN {
+                bytes[0] = 0;
+            }
+            let header = (bytes.len() as u32).to_be_bytes();
+            bytes.splice(0..0, header.iter().copied());
+        }
+        // Binary frame claiming to be longer than what follows.
+        2 => {
+            let header = (bytes.len() as u32 + 1000).to_be_bytes();
+            bytes.splice(0..0, header.iter().copied());
+        }
+        _ => {}
+    }
+    bytes
+}
+
+/// Sends random bytes to the server at `addr` and checks it keeps serving.
+fn random_bytes(addr: SocketAddr) {
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    for _ in 0..300 {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // The server may close the connection before reading all of it.
+        let _ = stream.write_all(&garbage(&mut rng));
+    }
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    client.shutdown().unwrap();
+}
+
+#[test]
+fn random_bytes_sync() {
+    let (_tmpdir, addr, server_thread) = start(false, 5300);
+    random_bytes(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn random_bytes_async() {
+    let (_tmpdir, addr, server_thread) = start(true, 5301);
+    random_bytes(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+/// Checks that a malformed request gets an error reply and the connection stays usable.
+fn malformed_request(addr: SocketAddr) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut rd = BufReader::new(&stream);
+    let mut reply = String::new();
+
+    writeln!(&stream, r#"{{"id":1,"op":"Foo"}}"#).unwrap();
+    rd.read_line(&mut reply).unwrap();
+    assert!(reply.starts_with(r#"{"id":1,"result":{"Err":"#));
+
+    reply.clear();
+    writeln!(&stream, r#"{{"id":2,"op":{{"Get":["","key"]}}}}"#).unwrap();
+    rd.read_line(&mut reply).unwrap();
+    assert_eq!(reply, "{\"id\":2,\"result\":{\"Ok\":null}}\n");
+
+    KvsClient::new(addr).unwrap().shutdown().unwrap();
+}
+
+#[test]
+fn malformed_request_sync() {
+    let (_tmpdir, addr, server_thread) = start(false, 5302);
+    malformed_request(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn malformed_request_async() {
+    let (_tmpdir, addr, server_thread) = start(true, 5303);
+    malformed_request(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+/// Sends `request` as clients predating request identifiers did and returns the reply, which the
+/// server must follow by closing the connection.
+fn legacy_request(addr: SocketAddr, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    writeln!(stream, "{}", request).unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    reply
+}
+
+/// Checks that requests in the JSON schema predating request identifiers are still served.
+fn legacy_requests(addr: SocketAddr) {
+    assert_eq!(
+        legacy_request(addr, r#"{"Set":["key","value"]}"#),
+        "{\"Ok\":null}\n"
+    );
+    assert_eq!(
+        legacy_request(addr, r#"{"Get":"key"}"#),
+        "{\"Ok\":\"value\"}\n"
+    );
+    assert_eq!(
+        KvsClient::new(addr).unwrap().get("key").unwrap(),
+        Some("value".to_string())
+    );
+    assert_eq!(
+        legacy_request(addr, r#"{"Rm":"other"}"#),
+        "{\"Err\":\"Key not found: other\"}\n"
+    );
+    assert_eq!(legacy_request(addr, r#"{"Rm":"key"}"#), "{\"Ok\":null}\n");
+    assert_eq!(legacy_request(addr, r#"{"Get":"key"}"#), "{\"Ok\":null}\n");
+    assert_eq!(legacy_request(addr, r#""Shutdown""#), "{\"Ok\":null}\n");
+}
+
+#[test]
+fn legacy_requests_sync() {
+    let (_tmpdir, addr, server_thread) = start(false, 5312);
+    legacy_requests(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn legacy_requests_async() {
+    let (_tmpdir, addr, server_thread) = start(true, 5313);
+    legacy_requests(addr);
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+/// Returns whether the server closed `stream` without replying.
+fn closed_by_server(mut stream: &TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buf = [0; 1];
+    match stream.read(&mut buf) {
+        Ok(len) => len == 0,
+        Err(err) => err.kind() == std::io::ErrorKind::ConnectionReset,
+    }
+}
+
+/// Checks that clients too slow to send requests or sending oversized ones are cut off without
+/// delaying others.
+fn slow_clients(async_runtime: bool, port: u16) {
+    let options = KvsServerOptions {
+        request_timeout: Some(Duration::from_millis(200)),
+        idle_timeout: Some(Duration::from_millis(500)),
+        max_request_size: 1024,
+        ..KvsServerOptions::default()
+    };
+    let (_tmpdir, addr, server_thread) = start_with(async_runtime, port, options);
+    let start = Instant::now();
+
+    // Never completes its request.
+    let slowloris = TcpStream::connect(addr).unwrap();
+    (&slowloris).write_all(br#"{"id":1,"op""#).unwrap();
+    // Trickles its request.
+    let trickler = TcpStream::connect(addr).unwrap();
+    (&trickler).write_all(&[0, 0, 0, 9]).unwrap();
+    // Never sends anything.
+    let idle = TcpStream::connect(addr).unwrap();
+    // Sends a request larger than allowed, which is refused.
+    let large = TcpStream::connect(addr).unwrap();
+    let line = format!(
+        "{{\"id\":1,\"op\":{{\"Get\":[\"\",\"{}\"]}}}}\n",
+        "k".repeat(2000)
+    );
+    let _ = (&large).write_all(line.as_bytes());
+
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    for _ in 0..3 {
+        std::thread::sleep(Duration::from_millis(100));
+        let _ = (&trickler).write_all(&[0]);
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    let mut reply = String::new();
+    BufReader::new(&large).read_line(&mut reply).unwrap();
+    assert_eq!(
+        reply,
+        "{\"id\":1,\"result\":{\"Err\":{\"RequestTooLarge\":1024}}}\n"
+    );
+    assert!(closed_by_server(&large));
+    assert!(closed_by_server(&slowloris));
+    assert!(closed_by_server(&trickler));
+    assert!(closed_by_server(&idle));
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn slow_clients_sync() {
+    slow_clients(false, 5304);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn slow_clients_async() {
+    slow_clients(true, 5305);
+}
+
+/// Checks that connections beyond the limit are closed while others are still served.
+fn connection_limit(async_runtime: bool, port: u16) {
+    let options = KvsServerOptions {
+        max_connections: 2,
+        ..KvsServerOptions::default()
+    };
+    let (_tmpdir, addr, server_thread) = start_with(async_runtime, port, options);
+
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    let idle = TcpStream::connect(addr).unwrap();
+    let refused = TcpStream::connect(addr).unwrap();
+    assert!(closed_by_server(&refused));
+
+    drop(idle);
+    // The server notices the closed connection asynchronously.
+    let start = Instant::now();
+    loop {
+        let mut other = KvsClient::new(addr).unwrap();
+        match other.get("key") {
+            Ok(val) => {
+                assert_eq!(val, Some("value".to_string()));
+                break;
+            }
+            Err(_) => assert!(start.elapsed() < Duration::from_secs(5)),
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn connection_limit_sync() {
+    connection_limit(false, 5306);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn connection_limit_async() {
+    connection_limit(true, 5307);
+}
+
+/// Checks that the default limit serves 1024 connections and refuses the next one.
+fn default_connection_limit(async_runtime: bool, port: u16) {
+    let (_tmpdir, addr, server_thread) = start(async_runtime, port);
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    let idle: Vec<TcpStream> = (1..1024)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    // Idle connections are served too.
+    let mut last = idle.last().unwrap();
+    let get = "{\"id\":1,\"op\":{\"Get\":[\"\",\"key\"]}}\n";
+    last.write_all(get.as_bytes()).unwrap();
+    let mut reply = String::new();
+    BufReader::new(last).read_line(&mut reply).unwrap();
+    assert!(reply.contains("value"));
+
+    let refused = TcpStream::connect(addr).unwrap();
+    assert!(closed_by_server(&refused));
+    assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn default_connection_limit_sync() {
+    default_connection_limit(false, 5310);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn default_connection_limit_async() {
+    default_connection_limit(true, 5311);
+}
+
+/// Checks that requests pipelined before shutdown are all replied to.
+fn pipelined_before_shutdown(async_runtime: bool, port: u16) {
+    const COUNT: usize = 1000;
+    let (_tmpdir, addr, server_thread) = start(async_runtime, port);
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut requests = String::new();
+    for id in 0..COUNT {
+        requests += &format!(
+            "{{\"id\":{},\"op\":{{\"Set\":[\"\",\"k{}\",\"v\"]}}}}\n",
+            id, id
+        );
+    }
+    (&stream).write_all(requests.as_bytes()).unwrap();
+    KvsClient::new(addr).unwrap().shutdown().unwrap();
+
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut replies = String::new();
+    BufReader::new(&stream)
+        .read_to_string(&mut replies)
+        .unwrap();
+    assert_eq!(replies.lines().count(), COUNT);
+    assert!(replies
+        .lines()
+        .all(|reply| reply.ends_with(r#""result":{"Ok":null}}"#)));
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn pipelined_before_shutdown_sync() {
+    pipelined_before_shutdown(false, 5314);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn pipelined_before_shutdown_async() {
+    pipelined_before_shutdown(true, 5315);
+}
+
+/// Checks that clients streaming values too slowly or sending endless chunk sizes are cut off.
+fn slow_streams(async_runtime: bool, port: u16) {
+    let options = KvsServerOptions {
+        request_timeout: Some(Duration::from_millis(300)),
+        ..KvsServerOptions::default()
+    };
+    let (_tmpdir, addr, server_thread) = start_with(async_runtime, port, options);
+    let set_stream = "{\"id\":1,\"op\":{\"SetStream\":[\"\",\"key\"]}}\n";
+
+    let endless = TcpStream::connect(addr).unwrap();
+    (&endless).write_all(set_stream.as_bytes()).unwrap();
+    let _ = (&endless).write_all("1".repeat(1000).as_bytes());
+    assert!(closed_by_server(&endless));
+
+    // Sends a byte more often than the request timeout but never completes the value.
+    let trickler = TcpStream::connect(addr).unwrap();
+    (&trickler).write_all(set_stream.as_bytes()).unwrap();
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(5) {
+        if (&trickler).write_all(b"1\nx").is_err() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    KvsClient::new(addr).unwrap().shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+#[test]
+fn slow_streams_sync() {
+    slow_streams(false, 5308);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn slow_streams_async() {
+    slow_streams(true, 5309);
+}
+
+/// Checks that idle connections do not keep a single reader thread from serving others.
+#[test]
+fn idle_connections_share_readers() {
+    let options = KvsServerOptions {
+        reader_threads: 1,
+        ..KvsServerOptions::default()
+    };
+    let (_tmpdir, addr, server_thread) = start_with(false, 5316, options);
+    let idle: Vec<TcpStream> = (0..10).map(|_| TcpStream::connect(addr).unwrap()).collect();
+
+    let start = Instant::now();
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    // Idle connections with pending requests are served too.
+    for mut stream in &idle {
+        let get = "{\"id\":1,\"op\":{\"Get\":[\"\",\"key\"]}}\n";
+        stream.write_all(get.as_bytes()).unwrap();
+        let mut reply = String::new();
+        BufReader::new(stream).read_line(&mut reply).unwrap();
+        assert!(reply.contains("value"));
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}
+
+/// Returns the average time taken by a get on `client`.
+fn get_latency(client: &mut KvsClient) -> Duration {
+    const COUNT: u32 = 50;
+    let start = Instant::now();
+    for _ in 0..COUNT {
+        assert_eq!(client.get("key").unwrap(), Some("value".to_string()));
+    }
+    start.elapsed() / COUNT
+}
+
+/// Checks that idle connections do not slow down requests on other connections.
+#[test]
+fn latency_with_idle_connections() {
+    let options = KvsServerOptions {
+        reader_threads: 2,
+        ..KvsServerOptions::default()
+    };
+    let (_tmpdir, addr, server_thread) = start_with(false, 5317, options);
+    let mut client = KvsClient::new(addr).unwrap();
+    client.set("key", "value").unwrap();
+    let alone = get_latency(&mut client);
+
+    let idle: Vec<TcpStream> = (0..500)
+        .map(|_| TcpStream::connect(addr).unwrap())
+        .collect();
+    // Lets the server accept them all.
+    let mut other = KvsClient::new(addr).unwrap();
+    assert_eq!(other.get("key").unwrap(), Some("value".to_string()));
+    let crowded = get_latency(&mut client);
+    assert!(
+        crowded < alone * 3 + Duration::from_millis(2),
+        "{:?} with idle connections, {:?} without",
+        crowded,
+        alone
+    );
+
+    drop(idle);
+    client.shutdown().unwrap();
+    assert!(server_thread.join().unwrap().is_ok());
+}